@@ -0,0 +1,334 @@
+// One record per tunneled tcp connection, written once its stream closes, for an audit trail
+// apart from the debug log. Records are JSON lines by default:
+//
+//   {"start":"2026-10-19T08:12:03.418Z","duration_ms":1520,"id":"146bff4877fe46dd",
+//    "peer":"203.0.113.5:4433","client":"127.0.0.1:50276","listener":"127.0.0.1:2222",
+//    "target":null,"bytes_sent":518,"bytes_received":20931,"close":"fin","error_code":null,
+//    "error":null}
+//
+// or text, laid out by a template of the same fields in braces, where unknown values are `-`.
+// `close` is fin when both directions ended normally, reset when either tcp connection was
+// reset and error otherwise, which includes streams that failed before their target was reached.
+// The end that accepted the client doesn't know the target, and the end that dialed the target
+// only knows the client from peers that sent it
+use std::{
+    fmt,
+    fs::OpenOptions,
+    io::{self, Write},
+    net::SocketAddr,
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    errors::Error,
+    metadata,
+    monitor::{Hooks, Monitor, StreamEnd},
+};
+
+const FIELDS: [&str; 12] = [
+    "start",
+    "duration_ms",
+    "id",
+    "peer",
+    "client",
+    "listener",
+    "target",
+    "bytes_sent",
+    "bytes_received",
+    "close",
+    "error_code",
+    "error",
+];
+
+const TEXT_TEMPLATE: &str =
+    "{start} {id} {client} {target} {peer} {bytes_sent} {bytes_received} {duration_ms} {close}";
+
+#[derive(Deserialize, Clone, PartialEq, Debug, Default)]
+#[serde(try_from = "String")]
+pub enum AccessLogFormat {
+    #[default]
+    Json,
+    // a template such as `{client} {bytes_sent}`
+    Text(String),
+}
+
+impl FromStr for AccessLogFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let template = match value {
+            "json" => return Ok(AccessLogFormat::Json),
+            "text" => TEXT_TEMPLATE,
+            template if template.contains('{') => template,
+            _ => {
+                return Err(format!(
+                    "unknown access log format `{value}`, expected json, text or a template"
+                ))
+            }
+        };
+
+        // every field in braces must be known, so a typo doesn't go unnoticed until a stream closes
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| format!("unclosed `{{` in access log format `{value}`"))?;
+            let field = &rest[start + 1..start + end];
+            if !FIELDS.contains(&field) {
+                return Err(format!("unknown access log field `{field}`"));
+            }
+            rest = &rest[start + end + 1..];
+        }
+
+        Ok(AccessLogFormat::Text(template.to_string()))
+    }
+}
+
+impl TryFrom<String> for AccessLogFormat {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+// Where the records of a tunnel go, `-` for stdout
+#[derive(Clone, PartialEq, Debug)]
+pub struct AccessLog {
+    pub path: PathBuf,
+    pub format: AccessLogFormat,
+}
+
+impl AccessLog {
+    // Writes a record for every stream of the monitor's tunnel. `accepts_clients` tells the end
+    // of the tunnel with the listener apart from the one that dials the target
+    pub fn register(self, monitor: &Monitor, accepts_clients: bool) -> Result<(), Error> {
+        let output: Box<dyn Write + Send> = if self.path.as_os_str() == "-" {
+            Box::new(io::stdout())
+        } else {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)
+                .map_err(|e| {
+                    Error::Config(format!(
+                        "unable to open access log {}: {e}",
+                        self.path.display()
+                    ))
+                })?;
+            Box::new(file)
+        };
+
+        monitor.add_hooks(Arc::new(AccessLogHooks {
+            format: self.format,
+            accepts_clients,
+            output: Mutex::new(output),
+        }));
+        Ok(())
+    }
+}
+
+struct AccessLogHooks {
+    format: AccessLogFormat,
+    accepts_clients: bool,
+    output: Mutex<Box<dyn Write + Send>>,
+}
+
+impl Hooks for AccessLogHooks {
+    fn on_stream_close(&self, stream: &StreamEnd<'_>) {
+        let record = Record::new(stream, self.accepts_clients);
+        let mut line = match &self.format {
+            AccessLogFormat::Json => serde_json::to_string(&record).unwrap_or_default(),
+            AccessLogFormat::Text(template) => record.render(template),
+        };
+        line.push('\n');
+
+        // a whole line in one write, so records of tunnels sharing a file don't interleave
+        let mut output = self.output.lock().unwrap();
+        if let Err(e) = output
+            .write_all(line.as_bytes())
+            .and_then(|_| output.flush())
+        {
+            tracing::warn!("Unable to write access log record: {e}");
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Record {
+    start: Timestamp,
+    duration_ms: u128,
+    id: String,
+    peer: SocketAddr,
+    client: Option<SocketAddr>,
+    listener: Option<SocketAddr>,
+    target: Option<SocketAddr>,
+    bytes_sent: u64,
+    bytes_received: u64,
+    close: &'static str,
+    error_code: Option<u32>,
+    error: Option<String>,
+}
+
+impl Record {
+    fn new(stream: &StreamEnd<'_>, accepts_clients: bool) -> Self {
+        let close = match stream.error {
+            None => "fin",
+            Some(Error::Reset(_)) => "reset",
+            Some(_) => "error",
+        };
+
+        Self {
+            start: Timestamp(SystemTime::now() - stream.duration),
+            duration_ms: stream.duration.as_millis(),
+            id: stream.metadata.id.to_string(),
+            peer: stream.peer,
+            client: stream.metadata.client,
+            listener: stream.metadata.listener,
+            target: Some(stream.tcp_addr)
+                .filter(|addr| !accepts_clients && !addr.ip().is_unspecified()),
+            bytes_sent: stream.bytes_sent,
+            bytes_received: stream.bytes_received,
+            close,
+            error_code: stream.error.map(Error::code),
+            error: stream.error.map(Error::to_string),
+        }
+    }
+
+    fn render(&self, template: &str) -> String {
+        fn or_dash<T: fmt::Display>(value: &Option<T>) -> String {
+            value.as_ref().map_or_else(|| "-".to_string(), T::to_string)
+        }
+
+        let mut line = String::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            let end = start + rest[start..].find('}').unwrap_or(rest.len() - start);
+            line.push_str(&rest[..start]);
+            let value = match &rest[start + 1..end] {
+                "start" => self.start.to_string(),
+                "duration_ms" => self.duration_ms.to_string(),
+                "id" => self.id.clone(),
+                "peer" => self.peer.to_string(),
+                "client" => or_dash(&self.client),
+                "listener" => or_dash(&self.listener),
+                "target" => or_dash(&self.target),
+                "bytes_sent" => self.bytes_sent.to_string(),
+                "bytes_received" => self.bytes_received.to_string(),
+                "close" => self.close.to_string(),
+                "error_code" => or_dash(&self.error_code),
+                "error" => or_dash(&self.error),
+                _ => String::new(),
+            };
+            line.push_str(&value);
+            rest = rest.get(end + 1..).unwrap_or_default();
+        }
+        line.push_str(rest);
+
+        line
+    }
+}
+
+// RFC 3339 in UTC, to the millisecond
+pub(crate) struct Timestamp(pub(crate) SystemTime);
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let millis = metadata::unix_millis(self.0);
+        let (days, millis) = (millis / 86_400_000, millis % 86_400_000);
+
+        // days since the epoch to a civil date, after Howard Hinnant's `civil_from_days`
+        let z = days as i64 + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z.rem_euclid(146_097);
+        let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + i64::from(month <= 2);
+
+        let time = Duration::from_millis(millis);
+        write!(
+            f,
+            "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+            time.as_secs() / 3600,
+            time.as_secs() / 60 % 60,
+            time.as_secs() % 60,
+            time.subsec_millis()
+        )
+    }
+}
+
+impl Serialize for Timestamp {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{AccessLogFormat, Record, Timestamp};
+
+    fn record() -> Record {
+        Record {
+            start: Timestamp(UNIX_EPOCH + Duration::from_millis(1_792_375_013_242)),
+            duration_ms: 1520,
+            id: "146bff4877fe46dd".to_string(),
+            peer: "203.0.113.5:4433".parse().unwrap(),
+            client: Some("127.0.0.1:50276".parse().unwrap()),
+            listener: None,
+            target: None,
+            bytes_sent: 518,
+            bytes_received: 20931,
+            close: "reset",
+            error_code: Some(12),
+            error: Some("connection reset".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_timestamp() {
+        assert_eq!(
+            Timestamp(UNIX_EPOCH).to_string(),
+            "1970-01-01T00:00:00.000Z"
+        );
+        assert_eq!(record().start.to_string(), "2026-10-19T01:56:53.242Z");
+        assert_eq!(
+            Timestamp(UNIX_EPOCH + Duration::from_secs(951_782_400)).to_string(),
+            "2000-02-29T00:00:00.000Z"
+        );
+    }
+
+    #[test]
+    fn test_formats() {
+        let json = serde_json::to_string(&record()).unwrap();
+        assert!(json.starts_with(r#"{"start":"2026-10-19T01:56:53.242Z","duration_ms":1520,"#));
+        assert!(json.contains(r#""listener":null,"#), "{json}");
+        assert!(json.ends_with(r#""error_code":12,"error":"connection reset"}"#));
+
+        let AccessLogFormat::Text(template) = "text".parse().unwrap() else {
+            panic!("text should be a template");
+        };
+        assert_eq!(
+            record().render(&template),
+            "2026-10-19T01:56:53.242Z 146bff4877fe46dd 127.0.0.1:50276 - 203.0.113.5:4433 518 20931 1520 reset"
+        );
+        assert_eq!(
+            record().render("client={client} error_code={error_code} {error}"),
+            "client=127.0.0.1:50276 error_code=12 connection reset"
+        );
+
+        assert_eq!("json".parse(), Ok(AccessLogFormat::Json));
+        assert!("xml".parse::<AccessLogFormat>().is_err());
+        assert!("{client} {bytes}".parse::<AccessLogFormat>().is_err());
+        assert!("{client".parse::<AccessLogFormat>().is_err());
+    }
+}
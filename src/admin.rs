@@ -0,0 +1,95 @@
+// Admin commands for a process running a config file, sent over a unix socket as one line per
+// connection and answered with one line, e.g. `echo reload | nc -U /run/sirang.sock`
+use std::{path::Path, time::Duration};
+
+use tokio::sync::{mpsc, oneshot};
+
+use crate::errors::Error;
+
+const MAX_COMMAND_LEN: u64 = 1024;
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct Request {
+    pub command: String,
+    reply: oneshot::Sender<String>,
+}
+
+impl Request {
+    pub fn reply(self, response: String) {
+        let _ = self.reply.send(response);
+    }
+}
+
+// Listens for commands on `path` in the background, replacing a stale socket file
+#[cfg(unix)]
+pub fn listen(path: &Path) -> Result<mpsc::Receiver<Request>, Error> {
+    let bind_error = |e: std::io::Error| {
+        Error::Bind(format!(
+            "unable to listen for admin commands on {}: {e}",
+            path.display()
+        ))
+    };
+
+    if let Err(e) = std::fs::remove_file(path) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            return Err(bind_error(e));
+        }
+    }
+    let listener = tokio::net::UnixListener::bind(path).map_err(bind_error)?;
+    tracing::info!("Listening for admin commands on {}", path.display());
+
+    let (request_tx, request_rx) = mpsc::channel(16);
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(handle_client(stream, request_tx.clone()));
+                }
+                Err(e) => tracing::warn!("Error accepting admin connection: {e}"),
+            }
+        }
+    });
+
+    Ok(request_rx)
+}
+
+#[cfg(not(unix))]
+pub fn listen(_path: &Path) -> Result<mpsc::Receiver<Request>, Error> {
+    Err(Error::Config(
+        "the admin socket is only supported on unix".to_string(),
+    ))
+}
+
+#[cfg(unix)]
+async fn handle_client(stream: tokio::net::UnixStream, request_tx: mpsc::Sender<Request>) {
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+    let (reader, mut writer) = stream.into_split();
+    let mut line = String::new();
+    let mut reader = BufReader::new(reader.take(MAX_COMMAND_LEN));
+    let read = reader.read_line(&mut line);
+
+    let response = match tokio::time::timeout(READ_TIMEOUT, read).await {
+        Ok(Ok(_)) => {
+            let (reply, response) = oneshot::channel();
+            let request = Request {
+                command: line.trim().to_string(),
+                reply,
+            };
+
+            if request_tx.send(request).await.is_err() {
+                return;
+            }
+            match response.await {
+                Ok(response) => response,
+                Err(_) => return,
+            }
+        }
+        Ok(Err(e)) => format!("error: unable to read command: {e}"),
+        Err(_) => "error: timed out reading command".to_string(),
+    };
+
+    if let Err(e) = writer.write_all(format!("{response}\n").as_bytes()).await {
+        tracing::debug!("Unable to answer admin command: {e}");
+    }
+}
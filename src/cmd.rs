@@ -1,10 +1,32 @@
-use crate::{common::TunnelType, errors, local, remote};
-use std::{net::SocketAddr, path::PathBuf, process::exit};
-
-use clap::{arg, command, value_parser, ArgAction, ArgMatches, Command};
+use crate::{
+    access::{AccessLog, AccessLogFormat},
+    common::TunnelType,
+    compress::Compression,
+    config,
+    errors::Error,
+    exec::HookCommands,
+    local,
+    logging::{self, LogFormat, LogOptions},
+    metrics::Metrics,
+    monitor::Monitor,
+    proxy::{Cidr, ProxyProtocol},
+    remote,
+    supervisor::Supervisor,
+    tls::PemSource,
+};
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    process::exit,
+    time::Duration,
+};
+
+use clap::{
+    arg, builder::FalseyValueParser, command, value_parser, Arg, ArgAction, ArgMatches, Command,
+};
 
 pub async fn execute() {
-    let matches = command!()
+    let matches = with_env(command!()
         .subcommand(
             Command::new("forward")
             .arg_required_else_help(true)
@@ -15,20 +37,20 @@ pub async fn execute() {
                         .arg(
                             arg!(
 
-                                -k --key <PATH> "Path to the tls key file"
+                                -k --key <PATH> "Tls key file, - to read it from stdin, or the PEM itself"
 
                             )
                             .required(true)
-                            .value_parser(value_parser!(PathBuf)),
+                            .value_parser(value_parser!(PemSource)),
                         )
                         .arg(
                             arg!(
 
-                                -c --cert <PATH> "Path to the tls certificate file"
+                                -c --cert <PATH> "Tls certificate file, - to read it from stdin, or the PEM itself"
 
                             )
                             .required(true)
-                            .value_parser(value_parser!(PathBuf)),
+                            .value_parser(value_parser!(PemSource)),
                         )
 
                         .arg(
@@ -40,6 +62,16 @@ pub async fn execute() {
                             .required(true)
                             .value_parser(value_parser!(SocketAddr)),
                         )
+                        .arg(
+                            arg!(
+
+                                -a --allowtarget <ADDRESS> "Additional host:port a client may ask to be forwarded to with connect --target, optionally followed by ,compress=<zstd|lz4|none> for the compression streams to it use"
+
+                            )
+                            .required(false)
+                            .value_delimiter(',')
+                            .action(ArgAction::Append),
+                        )
                         .arg(
                             arg!(
 
@@ -49,6 +81,53 @@ pub async fn execute() {
                             .required(false)
                             .value_parser(value_parser!(SocketAddr)),
                         )
+                        .arg(
+                            arg!(
+
+                                --goaway <SECONDS> "On shutdown, ask clients to reconnect after SECONDS instead of closing"
+
+                            )
+                            .required(false)
+                            .value_parser(value_parser!(u64)),
+                        )
+                        .arg(
+                            arg!(
+
+                                --redirectaddr <ADDRESS> "On shutdown, ask clients to reconnect to this quic address instead"
+
+                            )
+                            .required(false)
+                            .value_parser(value_parser!(SocketAddr)),
+                        )
+                        .arg(
+                            arg!(
+
+                                --handover <PATH> "Unix socket to hand the listening sockets over on, start a newer process with the same path to upgrade without downtime"
+
+                            )
+                            .required(false)
+                            .value_parser(value_parser!(PathBuf)),
+                        )
+                        .arg(
+                            arg!(
+
+                                --proxyprotocol <VERSION> "Send a PROXY protocol v1 or v2 header with the client's address on each connection to a target, the address of the local instance unless it is trusted with --acceptproxy"
+
+                            )
+                            .required(false)
+                            .value_parser(value_parser!(ProxyProtocol)),
+                        )
+                        .arg(
+                            arg!(
+
+                                --acceptproxy <CIDR> "Trust the client address local instances at these addresses send with each stream, others are reported as the client themselves"
+
+                            )
+                            .required(false)
+                            .value_parser(value_parser!(Cidr))
+                            .value_delimiter(',')
+                            .action(ArgAction::Append),
+                        )
                )
                 .subcommand(
                     Command::new("local")
@@ -56,11 +135,11 @@ pub async fn execute() {
                         .arg(
                             arg!(
 
-                                -c --cert <PATH> "Path to the tls certificate file"
+                                -c --cert <PATH> "Tls certificate file, - to read it from stdin, or the PEM itself"
 
                             )
                             .required(true)
-                            .value_parser(value_parser!(PathBuf)),
+                            .value_parser(value_parser!(PemSource)),
                         )
                         .arg(
                             arg!(
@@ -80,26 +159,36 @@ pub async fn execute() {
                             .required(true)
                             .value_parser(value_parser!(SocketAddr)),
                         )
+                        .arg(
+                            arg!(
 
-                )
-                 .arg(
-                    arg!(
+                                --compress <ALGORITHM> "Compress streams with zstd or lz4 when the remote instance supports it"
 
-                        -d --debug "Turns on debug logging"
+                            )
+                            .required(false)
+                            .value_parser(value_parser!(Compression)),
+                        )
+                        .arg(
+                            arg!(
 
-                    )
-                    .required(false)
-                    .action(ArgAction::SetTrue)
-                )
-                .arg(
-                    arg!(
+                                --acceptproxy <CIDR> "Read a PROXY protocol header with the real client's address from connections coming from these addresses"
 
-                        -b --buffersize [SIZE] "Sets the buffer size"
+                            )
+                            .required(false)
+                            .value_parser(value_parser!(Cidr))
+                            .value_delimiter(',')
+                            .action(ArgAction::Append),
+                        )
 
-                    )
-                    .required(false)
-                    .value_parser(value_parser!(usize))
                 )
+                .args(log_args())
+                .arg(buffer_size_arg())
+                .arg(drain_timeout_arg())
+                .args(hook_args())
+                .args(access_log_args())
+                .arg(metrics_arg(
+                    "Serves Prometheus metrics of the tunnel on http://ADDR/metrics",
+                ))
         )
          .subcommand(
             Command::new("reverse")
@@ -111,20 +200,20 @@ pub async fn execute() {
                         .arg(
                             arg!(
 
-                                -k --key <PATH> "Path to the tls key file"
+                                -k --key <PATH> "Tls key file, - to read it from stdin, or the PEM itself"
 
                             )
                             .required(true)
-                            .value_parser(value_parser!(PathBuf)),
+                            .value_parser(value_parser!(PemSource)),
                         )
                         .arg(
                             arg!(
 
-                                -c --cert <PATH> "Path to the tls certificate file"
+                                -c --cert <PATH> "Tls certificate file, - to read it from stdin, or the PEM itself"
 
                             )
                             .required(true)
-                            .value_parser(value_parser!(PathBuf)),
+                            .value_parser(value_parser!(PemSource)),
                         )
 
                         .arg(
@@ -136,6 +225,33 @@ pub async fn execute() {
                             .required(false)
                             .value_parser(value_parser!(SocketAddr)),
                         )
+                        .arg(
+                            arg!(
+
+                                --goaway <SECONDS> "On shutdown, ask clients to reconnect after SECONDS instead of closing"
+
+                            )
+                            .required(false)
+                            .value_parser(value_parser!(u64)),
+                        )
+                        .arg(
+                            arg!(
+
+                                --redirectaddr <ADDRESS> "On shutdown, ask clients to reconnect to this quic address instead"
+
+                            )
+                            .required(false)
+                            .value_parser(value_parser!(SocketAddr)),
+                        )
+                        .arg(
+                            arg!(
+
+                                --handover <PATH> "Unix socket to hand the listening sockets over on, start a newer process with the same path to upgrade without downtime"
+
+                            )
+                            .required(false)
+                            .value_parser(value_parser!(PathBuf)),
+                        )
                         .arg(
                             arg!(
 
@@ -145,6 +261,26 @@ pub async fn execute() {
                             .required(false)
                             .value_parser(value_parser!(SocketAddr)),
                         )
+                        .arg(
+                            arg!(
+
+                                --compress <ALGORITHM> "Compress streams with zstd or lz4 when the local instance supports it"
+
+                            )
+                            .required(false)
+                            .value_parser(value_parser!(Compression)),
+                        )
+                        .arg(
+                            arg!(
+
+                                --acceptproxy <CIDR> "Read a PROXY protocol header with the real client's address from connections coming from these addresses"
+
+                            )
+                            .required(false)
+                            .value_parser(value_parser!(Cidr))
+                            .value_delimiter(',')
+                            .action(ArgAction::Append),
+                        )
                )
                 .subcommand(
                     Command::new("local")
@@ -152,11 +288,11 @@ pub async fn execute() {
                         .arg(
                             arg!(
 
-                                -c --cert <PATH> "Path to the tls certificate file"
+                                -c --cert <PATH> "Tls certificate file, - to read it from stdin, or the PEM itself"
 
                             )
                             .required(true)
-                            .value_parser(value_parser!(PathBuf)),
+                            .value_parser(value_parser!(PemSource)),
                         )
                         .arg(
                             arg!(
@@ -176,39 +312,154 @@ pub async fn execute() {
                             .required(true)
                             .value_parser(value_parser!(SocketAddr)),
                         )
+                        .arg(
+                            arg!(
+
+                                --proxyprotocol <VERSION> "Send a PROXY protocol v1 or v2 header with the client's address on each connection to the local address"
+
+                            )
+                            .required(false)
+                            .value_parser(value_parser!(ProxyProtocol)),
+                        )
 
                 )
-                 .arg(
+                .args(log_args())
+                .arg(buffer_size_arg())
+                .arg(drain_timeout_arg())
+                .args(hook_args())
+                .args(access_log_args())
+                .arg(metrics_arg(
+                    "Serves Prometheus metrics of the tunnel on http://ADDR/metrics",
+                ))
+        )
+        .subcommand(
+            Command::new("connect")
+                .about("Opens a single stream through a forward tunnel on stdin/stdout, for use as an ssh ProxyCommand")
+                .arg(
                     arg!(
 
-                        -d --debug "Turns on debug logging"
+                        -c --cert <PATH> "Tls certificate file, - to read it from stdin, or the PEM itself"
 
                     )
-                    .required(false)
-                    .action(ArgAction::SetTrue)
+                    .required(true)
+                    .value_parser(value_parser!(PemSource)),
                 )
                 .arg(
                     arg!(
 
-                        -b --buffersize [SIZE] "Sets the buffer size"
+                        -r --remoteaddr <ADDRESS> "Address of the remote quic instance to connect to"
+
+                    )
+                    .required(true)
+                    .value_parser(value_parser!(SocketAddr)),
+                )
+                .arg(
+                    arg!(
+
+                        -t --target <ADDRESS> "host:port for the remote forward instance to connect to, defaults to its forward address"
+
+                    )
+                    .required(false),
+                )
+                .arg(
+                    arg!(
+
+                        --compress <ALGORITHM> "Compress the stream with zstd or lz4 when the remote instance supports it"
 
                     )
                     .required(false)
-                    .value_parser(value_parser!(usize))
-                )        
+                    .value_parser(value_parser!(Compression)),
+                )
+                .args(log_args())
+                .arg(buffer_size_arg())
         )
-        .arg_required_else_help(true)
+        .arg(
+            arg!(
+
+                --config <PATH> "Runs every tunnel defined in a toml config file"
+
+            )
+            .required(false)
+            .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            arg!(
+
+                --set <VALUE> "Overrides a value from the config file, as tunnel.key=value"
+
+            )
+            .required(false)
+            .requires("config")
+            .action(ArgAction::Append),
+        )
+        .arg(
+            arg!(
+
+                --admin <PATH> "Listens for admin commands, such as reload, on a unix socket"
+
+            )
+            .required(false)
+            .requires("config")
+            .value_parser(value_parser!(PathBuf)),
+        )
+        // the same options as for a single tunnel, applied to every tunnel in the config file
+        .args(
+            log_args()
+                .into_iter()
+                .chain([
+                    metrics_arg("Serves Prometheus metrics of every tunnel on http://ADDR/metrics"),
+                    buffer_size_arg(),
+                    drain_timeout_arg(),
+                ])
+                .map(|arg| arg.requires("config")),
+        )
+        .mut_arg("debug", |arg| {
+            arg.help("Turns on debug logging for every tunnel in the config file")
+        })
+        .mut_arg("buffersize", |arg| {
+            arg.help("Sets the buffer size for every tunnel in the config file")
+        })
+        .mut_arg("draintimeout", |arg| {
+            arg.help("Sets the drain timeout for every tunnel in the config file")
+        })
+        .args_conflicts_with_subcommands(true)
+        .arg_required_else_help(true))
         .get_matches();
 
-    if let Err(e) = handle_matches(matches).await {
-        log::error!("Error occured: {e}");
+    // stdin is read on a blocking thread that never returns on its own,
+    // so connect exits directly instead of waiting on the runtime to shut down
+    let is_connect = matches.subcommand_matches("connect").is_some();
+
+    // the exit code tells apart what kind of error stopped the process
+    let res = handle_matches(matches).await;
+    if let Err(e) = &res {
+        tracing::error!("Error occured: {e}");
+    }
+    // spans still waiting to be exported would be lost on exit
+    logging::flush();
+
+    if let Err(e) = res {
+        exit(e.exit_code());
+    }
+
+    if is_connect {
+        exit(0);
     }
 }
 
 async fn handle_matches(
     arg_matches: ArgMatches,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+) -> Result<(), Error> {
         
+    if let Some(config_path) = arg_matches.get_one::<PathBuf>("config") {
+        return handle_config(config_path, &arg_matches).await;
+    }
+
+    if let Some(connect_matches) = arg_matches.subcommand_matches("connect") {
+        logging::init(&log_options(connect_matches)?)?;
+        return handle_connect(connect_matches).await;
+    }
+
     let mut tunnel_type = TunnelType::Forward;
 
     let cmd_matches = match arg_matches.subcommand_matches("forward") {
@@ -228,19 +479,29 @@ async fn handle_matches(
 
     };
 
-    let mut log_builder = colog::default_builder();
-
-    if !cmd_matches.get_flag("debug") {
-        log_builder.filter_level(log::LevelFilter::Info);
-    } else {
-        
-        log_builder.filter_level(log::LevelFilter::Trace);
-
-    }
-
-    log_builder.init();
+    logging::init(&log_options(cmd_matches)?)?;
 
     let buffersize = cmd_matches.get_one::<usize>("buffersize");
+    let drain_timeout = cmd_matches
+        .get_one::<u64>("draintimeout")
+        .map(|secs| Duration::from_secs(*secs));
+    let hook_commands = HookCommands {
+        on_up: cmd_matches.get_one::<String>("onup").cloned(),
+        on_down: cmd_matches.get_one::<String>("ondown").cloned(),
+        on_stream_open: cmd_matches.get_one::<String>("onstreamopen").cloned(),
+        on_stream_close: cmd_matches.get_one::<String>("onstreamclose").cloned(),
+    };
+    let access_log = cmd_matches
+        .get_one::<PathBuf>("accesslog")
+        .map(|path| AccessLog {
+            path: path.clone(),
+            format: cmd_matches
+                .get_one::<AccessLogFormat>("accesslogformat")
+                .cloned()
+                .unwrap_or_default(),
+        });
+    let metrics_address = cmd_matches.get_one::<SocketAddr>("metricsaddr").copied();
+    let tunnel_name = arg_matches.subcommand_name().unwrap_or_default();
 
     if let Some(remote_matches) = cmd_matches.subcommand_matches("remote") {
         let mut remote_config = remote::config::RemoteConfig::new(&tunnel_type);
@@ -249,37 +510,58 @@ async fn handle_matches(
             if let Some(tcp_addr) = remote_matches.get_one::<SocketAddr>("tcpaddr") {
                 remote_config.tcp_reverse_address = Some(*tcp_addr);
             }
+            remote_config.compression = remote_matches.get_one::<Compression>("compress").copied();
+            if let Some(trusted) = remote_matches.get_many::<Cidr>("acceptproxy") {
+                remote_config.trusted_proxies = trusted.copied().collect();
+            }
         } else if let Some(forward_addr) = remote_matches.get_one::<SocketAddr>("forwardaddr") {
             remote_config.tcp_forward_address = Some(*forward_addr);
+
+            if let Some(allowed_targets) = remote_matches.get_many::<String>("allowtarget") {
+                remote_config.allow_targets(allowed_targets)?;
+            }
+            remote_config.proxy_protocol = remote_matches
+                .get_one::<ProxyProtocol>("proxyprotocol")
+                .copied();
+            if let Some(trusted) = remote_matches.get_many::<Cidr>("acceptproxy") {
+                remote_config.trusted_proxies = trusted.copied().collect();
+            }
         }
 
         if let Some(addr) = remote_matches.get_one::<SocketAddr>("quicaddr") {
             remote_config.quic_address = *addr;
         }
 
-        if let Some(tls_cert_file) = remote_matches.get_one::<PathBuf>("cert") {
-            if !tls_cert_file.exists() {
-                return Err(Box::new(errors::GenericError(
-                    "Tls certificate file doesn't exist".to_string(),
-                )));
-            }
-
-            remote_config.tls_cert = std::fs::read_to_string(tls_cert_file.to_str().unwrap())?;
+        if let Some(goaway_delay) = remote_matches.get_one::<u64>("goaway") {
+            remote_config.goaway_delay = Some(Duration::from_secs(*goaway_delay));
+        }
+        if let Some(redirect_addr) = remote_matches.get_one::<SocketAddr>("redirectaddr") {
+            remote_config.redirect_address = Some(*redirect_addr);
+        }
+        if let Some(handover_path) = remote_matches.get_one::<PathBuf>("handover") {
+            remote_config.handover_path = Some(handover_path.clone());
         }
-        if let Some(tls_key_file) = remote_matches.get_one::<PathBuf>("key") {
-            if !tls_key_file.exists() {
-                return Err(Box::new(errors::GenericError(
-                    "Tls key file doesn't exist".to_string(),
-                )));
-            }
 
-            remote_config.tls_key =
-                std::fs::read_to_string(tls_key_file.to_str().unwrap())?;
+        if let Some(tls_cert) = remote_matches.get_one::<PemSource>("cert") {
+            remote_config.tls_cert = tls_cert.read_cert()?;
+        }
+        if let Some(tls_key) = remote_matches.get_one::<PemSource>("key") {
+            remote_config.tls_key = tls_key.read_key()?;
         }
 
         if let Some(buffer_size) = buffersize {
             remote_config.buffer_size = *buffer_size;
         }
+        if let Some(drain_timeout) = drain_timeout {
+            remote_config.drain_timeout = drain_timeout;
+        }
+        hook_commands.register(&remote_config.monitor);
+        if let Some(access_log) = access_log {
+            access_log.register(&remote_config.monitor, tunnel_type == TunnelType::Reverse)?;
+        }
+        if let Some(address) = metrics_address {
+            serve_metrics(tunnel_name, &remote_config.monitor, address).await?;
+        }
 
         remote::start_remote(remote_config).await?;
     }
@@ -293,20 +575,34 @@ async fn handle_matches(
         if let Some(remote_addr) = local_matches.get_one::<SocketAddr>("remoteaddr") {
             local_config.remote_quic_server_addr = *remote_addr;
         }
-        if let Some(tls_cert_file) = local_matches.get_one::<PathBuf>("cert") {
-            if !tls_cert_file.exists() {
-                return Err(Box::new(errors::GenericError(
-                    "Tls certificate file doesn't exist".to_string(),
-                )));
+        if let Some(tls_cert) = local_matches.get_one::<PemSource>("cert") {
+            local_config.tls_cert = tls_cert.read_cert()?;
+        }
+        // the remote of a reverse tunnel picks the compression, the local one dials the target
+        if tunnel_type == TunnelType::Forward {
+            local_config.compression = local_matches.get_one::<Compression>("compress").copied();
+            if let Some(trusted) = local_matches.get_many::<Cidr>("acceptproxy") {
+                local_config.trusted_proxies = trusted.copied().collect();
             }
-
-            local_config.tls_cert =
-                std::fs::read_to_string(tls_cert_file.to_str().unwrap())?;
+        } else {
+            local_config.proxy_protocol = local_matches
+                .get_one::<ProxyProtocol>("proxyprotocol")
+                .copied();
         }
 
         if let Some(buffer_size) = buffersize {
             local_config.buffer_size = *buffer_size;
         }
+        if let Some(drain_timeout) = drain_timeout {
+            local_config.drain_timeout = drain_timeout;
+        }
+        hook_commands.register(&local_config.monitor);
+        if let Some(access_log) = access_log {
+            access_log.register(&local_config.monitor, tunnel_type == TunnelType::Forward)?;
+        }
+        if let Some(address) = metrics_address {
+            serve_metrics(tunnel_name, &local_config.monitor, address).await?;
+        }
 
         local_config.tunnel_type = tunnel_type;
 
@@ -315,3 +611,176 @@ async fn handle_matches(
 
     Ok(())
 }
+
+// Every option can also be set through a SIRANG_<OPTION> variable, such as SIRANG_CERT. Values
+// are hidden from the help output since they may hold inline keys
+fn with_env(command: Command) -> Command {
+    let subcommands: Vec<String> = command
+        .get_subcommands()
+        .map(|subcommand| subcommand.get_name().to_string())
+        .collect();
+
+    let command = command.mut_args(|arg| {
+        let name = format!("SIRANG_{}", arg.get_id().as_str().to_uppercase());
+        let arg = arg.env(name).hide_env_values(true);
+
+        // flags are on for any value but an empty one, 0, false, no or off
+        match arg.get_action() {
+            ArgAction::SetTrue => arg.value_parser(FalseyValueParser::new()),
+            _ => arg,
+        }
+    });
+
+    subcommands.iter().fold(command, |command, subcommand| {
+        command.mut_subcommand(subcommand, with_env)
+    })
+}
+
+// Logging options, given before the forward or reverse command, to connect, or along with a
+// config file
+fn log_args() -> Vec<Arg> {
+    vec![
+        arg!(-d --debug "Turns on debug logging").action(ArgAction::SetTrue),
+        arg!(--logformat <FORMAT> "Writes log lines as text or json")
+            .visible_alias("log-format")
+            .value_parser(value_parser!(LogFormat)),
+        arg!(--logfilter <FILTER> "Per-module log levels in RUST_LOG syntax, such as info,s2n_quic=warn"),
+        arg!(--logfile <PATH> "Writes logs to this file instead of stderr")
+            .value_parser(value_parser!(PathBuf)),
+        arg!(--logfilesize <MB> "Rotates the log file once it reaches this many megabytes")
+            .value_parser(value_parser!(u64).range(..=config::MAX_LOG_FILE_SIZE)),
+        arg!(--logfiles <COUNT> "Number of rotated log files to keep, 5 by default")
+            .value_parser(value_parser!(usize)),
+        arg!(--otlpendpoint <URL> "Exports tracing spans to this OpenTelemetry collector over OTLP/HTTP"),
+    ]
+}
+
+fn hook_args() -> Vec<Arg> {
+    vec![
+        arg!(--onup <COMMAND> "Shell command to run when a quic connection is established, see the README for its variables")
+            .visible_alias("on-up"),
+        arg!(--ondown <COMMAND> "Shell command to run when a quic connection is lost or closed")
+            .visible_alias("on-down"),
+        arg!(--onstreamopen <COMMAND> "Shell command to run when a stream opens")
+            .visible_alias("on-stream-open"),
+        arg!(--onstreamclose <COMMAND> "Shell command to run when a stream closes")
+            .visible_alias("on-stream-close"),
+    ]
+}
+
+fn access_log_args() -> Vec<Arg> {
+    vec![
+        arg!(--accesslog <PATH> "Writes a record of every tunneled connection to this file, - for stdout")
+            .value_parser(value_parser!(PathBuf)),
+        arg!(--accesslogformat <FORMAT> "Access log records as json, text or a template of their fields, see the README")
+            .requires("accesslog")
+            .value_parser(value_parser!(AccessLogFormat)),
+    ]
+}
+
+fn metrics_arg(help: &'static str) -> Arg {
+    arg!(--metricsaddr <ADDR>)
+        .help(help)
+        .visible_alias("metrics-addr")
+        .value_parser(value_parser!(SocketAddr))
+}
+
+fn buffer_size_arg() -> Arg {
+    arg!(-b --buffersize [SIZE] "Sets the buffer size").value_parser(value_parser!(usize))
+}
+
+fn drain_timeout_arg() -> Arg {
+    arg!(--draintimeout [SECONDS] "Seconds to wait for active streams to finish on shutdown")
+        .value_parser(value_parser!(u64))
+}
+
+// A tunnel run without a config file is named after its subcommand in its metrics
+async fn serve_metrics(name: &str, monitor: &Monitor, address: SocketAddr) -> Result<(), Error> {
+    let metrics = Metrics::default();
+    metrics.add(name, monitor);
+    metrics.serve(address).await.map(|_| ())
+}
+
+fn log_options(matches: &ArgMatches) -> Result<LogOptions, Error> {
+    Ok(LogOptions {
+        debug: matches.get_flag("debug"),
+        format: matches
+            .get_one::<LogFormat>("logformat")
+            .copied()
+            .unwrap_or_default(),
+        filter: matches.get_one::<String>("logfilter").cloned(),
+        file: matches.get_one::<PathBuf>("logfile").cloned(),
+        max_size: matches
+            .get_one::<u64>("logfilesize")
+            .copied()
+            .map(config::log_file_bytes)
+            .transpose()?,
+        max_files: matches.get_one::<usize>("logfiles").copied(),
+        otlp_endpoint: matches.get_one::<String>("otlpendpoint").cloned(),
+    })
+}
+
+async fn handle_connect(connect_matches: &ArgMatches) -> Result<(), Error> {
+    let mut local_config = local::config::LocalConfig::default();
+
+    if let Some(remote_addr) = connect_matches.get_one::<SocketAddr>("remoteaddr") {
+        local_config.remote_quic_server_addr = *remote_addr;
+    }
+    match connect_matches.get_one::<PemSource>("cert") {
+        // stdin carries the tunneled stream
+        Some(PemSource::Stdin) => {
+            return Err(Error::Config(
+                "connect can't read the tls certificate from stdin".to_string(),
+            ));
+        }
+        Some(tls_cert) => local_config.tls_cert = tls_cert.read_cert()?,
+        None => {}
+    }
+    if let Some(buffer_size) = connect_matches.get_one::<usize>("buffersize") {
+        local_config.buffer_size = *buffer_size;
+    }
+
+    local_config.target = connect_matches.get_one::<String>("target").cloned();
+    local_config.compression = connect_matches.get_one::<Compression>("compress").copied();
+
+    local::start_connect(local_config).await
+}
+
+async fn handle_config(config_path: &Path, arg_matches: &ArgMatches) -> Result<(), Error> {
+    let overrides = config::Overrides {
+        debug: arg_matches.get_flag("debug"),
+        buffer_size: arg_matches.get_one::<usize>("buffersize").copied(),
+        drain_timeout: arg_matches.get_one::<u64>("draintimeout").copied(),
+        log_format: arg_matches.get_one::<LogFormat>("logformat").copied(),
+        log_filter: arg_matches.get_one::<String>("logfilter").cloned(),
+        log_file: arg_matches.get_one::<PathBuf>("logfile").cloned(),
+        log_file_size: arg_matches.get_one::<u64>("logfilesize").copied(),
+        log_files: arg_matches.get_one::<usize>("logfiles").copied(),
+        otlp_endpoint: arg_matches.get_one::<String>("otlpendpoint").cloned(),
+        metrics_address: arg_matches.get_one::<SocketAddr>("metricsaddr").copied(),
+        values: arg_matches
+            .get_many::<String>("set")
+            .map(|values| values.cloned().collect())
+            .unwrap_or_default(),
+    };
+
+    let config = match config::load(config_path, &overrides) {
+        Ok(config) => config,
+        Err(e) => {
+            // logging isn't set up before the file says how
+            logging::init(&overrides.log_options()?)?;
+            return Err(e);
+        }
+    };
+
+    logging::init(&config.log)?;
+    Supervisor::new(config_path, overrides)
+        .run(
+            config.tunnels,
+            arg_matches
+                .get_one::<PathBuf>("admin")
+                .map(PathBuf::as_path),
+            config.metrics_address,
+        )
+        .await
+}
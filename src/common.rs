@@ -1,4 +1,10 @@
+use std::time::Duration;
+
 pub const DEFAULT_BUFSIZE: usize = 1024 * 32;
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Application error code a quic connection is closed with once its tunnel has shut down
+pub const SHUTDOWN_ERROR_CODE: u32 = 1;
 
 #[derive(PartialEq, Clone)]
 pub enum TunnelType {
@@ -8,33 +14,71 @@ pub enum TunnelType {
 
 pub mod proto {
     use core::str;
-    use std::{net::SocketAddr, str::FromStr};
+    use std::{io, net::SocketAddr, str::FromStr, time::Duration};
 
     use bytes::Bytes;
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+    use crate::{
+        compress::{self, Compression, Offer},
+        metadata::Metadata,
+    };
+
+    // How long the other end has to send the header of a stream it opened
+    const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
 
-    #[derive(Debug, PartialEq)]
+    // variants are named as they are sent
+    #[allow(clippy::upper_case_acronyms)]
+    #[derive(Debug, PartialEq, Clone)]
     pub enum ProtoCommand {
-        CONNECTED(SocketAddr),
+        // along with whether the streams of the reverse tunnel open with a StreamHeader, which
+        // they don't for locals of 0.1.5 and earlier. `CONNECTED <addr>` without it,
+        // `CONNECTED <addr> header` with it
+        CONNECTED(SocketAddr, bool),
         CLOSED,
         ACK,
+        // stop opening streams and reconnect after the given seconds, optionally to another address
+        GOAWAY(u64, Option<SocketAddr>),
+        // the compression algorithms the sender can decode, sent first by the local instance and
+        // answered by the remote of a forward tunnel, which adds what it wants for some targets.
+        // `COMPRESS zstd,lz4 db:5432=zstd 10.0.0.9:443=none`
+        COMPRESS(Offer),
     }
 
     impl ProtoCommand {
         pub fn deserialize(&self) -> Bytes {
             match *self {
-                ProtoCommand::CONNECTED(socket_addr) => {
+                ProtoCommand::CONNECTED(socket_addr, false) => {
                     Bytes::copy_from_slice(
                         [b"CONNECTED ", socket_addr.to_string().as_bytes()]
                             .concat()
                             .as_slice(),
                     )
                 }
+                ProtoCommand::CONNECTED(socket_addr, true) => {
+                    Bytes::from(format!("CONNECTED {socket_addr} header"))
+                }
                 ProtoCommand::CLOSED => {
                     Bytes::from_static(b"CLOSED")
                 }
                 ProtoCommand::ACK => {
                     Bytes::from_static(b"ACK")
                 }
+                ProtoCommand::GOAWAY(after, None) => {
+                    Bytes::from(format!("GOAWAY {after}"))
+                }
+                ProtoCommand::GOAWAY(after, Some(socket_addr)) => {
+                    Bytes::from(format!("GOAWAY {after} {socket_addr}"))
+                }
+                ProtoCommand::COMPRESS(ref offer) => {
+                    let names: Vec<&str> = offer.supported.iter().map(Compression::name).collect();
+                    let mut data = format!("COMPRESS {}", names.join(","));
+                    for (target, compression) in &offer.targets {
+                        let name = compression.as_ref().map_or("none", Compression::name);
+                        data.push_str(&format!(" {target}={name}"));
+                    }
+                    Bytes::from(data)
+                }
             }
         }
 
@@ -46,7 +90,8 @@ pub mod proto {
                         if let Some(addr_bytes) = iter.next() {
                             if let Ok(addr_bytes_str) = &str::from_utf8(addr_bytes) {
                                 if let Ok(address) = SocketAddr::from_str(addr_bytes_str) {
-                                    return Some(ProtoCommand::CONNECTED(address));
+                                    let header = iter.next() == Some(b"header");
+                                    return Some(ProtoCommand::CONNECTED(address, header));
                                 }
                             }
                         }
@@ -59,6 +104,35 @@ pub mod proto {
                     b"ACK" => {
                         return Some(ProtoCommand::ACK);
                     }
+                    b"GOAWAY" => {
+                        let after = str::from_utf8(iter.next()?).ok()?.parse().ok()?;
+                        let redirect_address = match iter.next() {
+                            Some(addr_bytes) => {
+                                Some(SocketAddr::from_str(str::from_utf8(addr_bytes).ok()?).ok()?)
+                            }
+                            None => None,
+                        };
+
+                        return Some(ProtoCommand::GOAWAY(after, redirect_address));
+                    }
+                    b"COMPRESS" => {
+                        // algorithms this build doesn't know are left out
+                        let names = str::from_utf8(iter.next().unwrap_or_default()).ok()?;
+                        let supported = names
+                            .split(',')
+                            .filter_map(|name| name.parse().ok())
+                            .collect();
+                        // and so are choices for targets made with them
+                        let mut targets = Vec::new();
+                        for field in iter {
+                            let (target, name) = str::from_utf8(field).ok()?.split_once('=')?;
+                            if let Ok(compression) = compress::parse_choice(name) {
+                                targets.push((target.to_string(), compression));
+                            }
+                        }
+
+                        return Some(ProtoCommand::COMPRESS(Offer { supported, targets }));
+                    }
                     _ => {}
                 }
             }
@@ -67,6 +141,95 @@ pub mod proto {
         }
     }
 
+    // Sent at the start of every forward tunnel data stream, ahead of the tunneled bytes, and of
+    // reverse tunnel streams once the remote said so in CONNECTED. Unlike commands it is length
+    // prefixed, since the payload follows it on the same stream. Instances of 0.1.5 and earlier,
+    // told apart by the ALPN of the connection, get raw streams without one
+    #[derive(Debug, PartialEq, Default)]
+    pub struct StreamHeader {
+        // host:port the remote instance should dial, None means its configured forward address
+        pub target: Option<String>,
+
+        // how the rest of the stream is compressed
+        pub compression: Option<Compression>,
+
+        // the tcp client the stream is for and its id, passed on to the target in a PROXY
+        // protocol header
+        pub metadata: Option<Metadata>,
+    }
+
+    impl StreamHeader {
+        pub fn deserialize(&self) -> Bytes {
+            let mut data = b"OPEN".to_vec();
+            if let Some(target) = &self.target {
+                data.extend_from_slice(format!(" {target}").as_bytes());
+            }
+            if let Some(compression) = self.compression {
+                data.extend_from_slice(format!(" compress={compression}").as_bytes());
+            }
+            if let Some(metadata) = &self.metadata {
+                data.extend_from_slice(format!(" {}", metadata.options()).as_bytes());
+            }
+
+            Bytes::from(data)
+        }
+
+        pub fn serialize(data: Bytes) -> Option<Self> {
+            let mut iter = data.split(|byte| *byte == b" "[0]);
+            if iter.next()? != b"OPEN" {
+                return None;
+            }
+
+            // targets never contain `=`, which tells them apart from options. Options of newer
+            // versions are left out rather than rejected, so they can be added without another
+            // round of negotiation, while a known option with an invalid value still is
+            let mut header = StreamHeader::default();
+            for field in iter {
+                let field = str::from_utf8(field).ok()?;
+                match field.split_once('=') {
+                    Some(("compress", name)) => header.compression = Some(name.parse().ok()?),
+                    Some((key, value)) => {
+                        let mut metadata =
+                            header.metadata.unwrap_or_else(|| Metadata::new(None, None));
+                        if metadata.set_option(key, value)? {
+                            header.metadata = Some(metadata);
+                        }
+                    }
+                    None => header.target = Some(field.to_string()),
+                }
+            }
+
+            Some(header)
+        }
+
+        pub async fn write_to<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> io::Result<()> {
+            let data = self.deserialize();
+            let len = u16::try_from(data.len()).map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidInput, "stream header too long")
+            })?;
+            writer.write_u16(len).await?;
+            writer.write_all(&data).await
+        }
+
+        // Fails with TimedOut when the header doesn't arrive within HEADER_TIMEOUT
+        pub async fn read_from<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Self> {
+            let read = async {
+                let len = reader.read_u16().await?;
+                let mut data = vec![0; len as usize];
+                reader.read_exact(&mut data).await?;
+                Ok::<_, io::Error>(data)
+            };
+            let data = tokio::time::timeout(HEADER_TIMEOUT, read)
+                .await
+                .map_err(|_| {
+                    io::Error::new(io::ErrorKind::TimedOut, "no stream header in time")
+                })??;
+
+            Self::serialize(Bytes::from(data))
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid stream header"))
+        }
+    }
+
     #[cfg(test)]
     mod tests {
 
@@ -74,7 +237,11 @@ pub mod proto {
 
         use bytes::Bytes;
 
-        use super::ProtoCommand;
+        use super::{ProtoCommand, StreamHeader};
+        use crate::{
+            compress::{Compression, Offer},
+            metadata::Metadata,
+        };
 
         #[test]
         fn test_serialize() {
@@ -90,8 +257,49 @@ pub mod proto {
 
             assert_eq!(
                 ProtoCommand::serialize(Bytes::from_static(b"CONNECTED 127.0.0.1:5050")).unwrap(),
-                ProtoCommand::CONNECTED(SocketAddr::from_str("127.0.0.1:5050").unwrap())
+                ProtoCommand::CONNECTED(SocketAddr::from_str("127.0.0.1:5050").unwrap(), false)
+            );
+
+            assert_eq!(
+                ProtoCommand::serialize(Bytes::from_static(b"CONNECTED 127.0.0.1:5050 header"))
+                    .unwrap(),
+                ProtoCommand::CONNECTED(SocketAddr::from_str("127.0.0.1:5050").unwrap(), true)
             );
+
+            assert_eq!(
+                ProtoCommand::serialize(Bytes::from_static(b"COMPRESS zstd,brotli,lz4")).unwrap(),
+                ProtoCommand::COMPRESS(Offer {
+                    supported: vec![Compression::Zstd, Compression::Lz4],
+                    targets: Vec::new(),
+                })
+            );
+
+            assert_eq!(
+                ProtoCommand::serialize(Bytes::from_static(
+                    b"COMPRESS zstd db:5432=lz4 10.0.0.9:443=none cache:6379=brotli"
+                ))
+                .unwrap(),
+                ProtoCommand::COMPRESS(Offer {
+                    supported: vec![Compression::Zstd],
+                    targets: vec![
+                        ("db:5432".to_string(), Some(Compression::Lz4)),
+                        ("10.0.0.9:443".to_string(), None),
+                    ],
+                })
+            );
+
+            assert_eq!(
+                ProtoCommand::serialize(Bytes::from_static(b"GOAWAY 5")).unwrap(),
+                ProtoCommand::GOAWAY(5, None)
+            );
+
+            assert_eq!(
+                ProtoCommand::serialize(Bytes::from_static(b"GOAWAY 0 127.0.0.1:4433")).unwrap(),
+                ProtoCommand::GOAWAY(0, Some(SocketAddr::from_str("127.0.0.1:4433").unwrap()))
+            );
+
+            assert!(ProtoCommand::serialize(Bytes::from_static(b"GOAWAY")).is_none());
+            assert!(ProtoCommand::serialize(Bytes::from_static(b"GOAWAY 5 nowhere")).is_none());
         }
 
         #[test]
@@ -99,7 +307,7 @@ pub mod proto {
             let closed_cmd = ProtoCommand::CLOSED;
             let ack_cmd = ProtoCommand::ACK;
             let connected_cmd =
-                ProtoCommand::CONNECTED(SocketAddr::from_str("127.0.0.1:5050").unwrap());
+                ProtoCommand::CONNECTED(SocketAddr::from_str("127.0.0.1:5050").unwrap(), false);
 
             assert_eq!(closed_cmd.deserialize(), Bytes::from_static(b"CLOSED"));
             assert_eq!(ack_cmd.deserialize(), Bytes::from_static(b"ACK"));
@@ -107,6 +315,129 @@ pub mod proto {
                 connected_cmd.deserialize(),
                 Bytes::from_static(b"CONNECTED 127.0.0.1:5050")
             );
+
+            let goaway_cmd = ProtoCommand::GOAWAY(5, None);
+            let redirect_cmd =
+                ProtoCommand::GOAWAY(0, Some(SocketAddr::from_str("127.0.0.1:4433").unwrap()));
+
+            assert_eq!(goaway_cmd.deserialize(), Bytes::from_static(b"GOAWAY 5"));
+            assert_eq!(
+                redirect_cmd.deserialize(),
+                Bytes::from_static(b"GOAWAY 0 127.0.0.1:4433")
+            );
+
+            let compress_cmd = ProtoCommand::COMPRESS(Offer {
+                supported: vec![Compression::Zstd, Compression::Lz4],
+                targets: vec![
+                    ("db:5432".to_string(), Some(Compression::Zstd)),
+                    ("10.0.0.9:443".to_string(), None),
+                ],
+            });
+            assert_eq!(
+                compress_cmd.deserialize(),
+                Bytes::from_static(b"COMPRESS zstd,lz4 db:5432=zstd 10.0.0.9:443=none")
+            );
+        }
+
+        #[test]
+        fn test_stream_header() {
+            let default_header = StreamHeader::default();
+            let target_header = StreamHeader {
+                target: Some("example.com:22".to_string()),
+                ..StreamHeader::default()
+            };
+            let compressed_header = StreamHeader {
+                compression: Some(Compression::Zstd),
+                ..StreamHeader::default()
+            };
+            let metadata = Metadata::new(
+                Some(SocketAddr::from_str("[2001:db8::1]:51234").unwrap()),
+                None,
+            );
+            let client_header = StreamHeader {
+                metadata: Some(metadata),
+                ..StreamHeader::default()
+            };
+
+            assert_eq!(default_header.deserialize(), Bytes::from_static(b"OPEN"));
+            assert_eq!(
+                target_header.deserialize(),
+                Bytes::from_static(b"OPEN example.com:22")
+            );
+
+            assert_eq!(
+                StreamHeader::serialize(default_header.deserialize()).unwrap(),
+                default_header
+            );
+            assert_eq!(
+                StreamHeader::serialize(target_header.deserialize()).unwrap(),
+                target_header
+            );
+            assert_eq!(
+                compressed_header.deserialize(),
+                Bytes::from_static(b"OPEN compress=zstd")
+            );
+            assert_eq!(
+                StreamHeader::serialize(compressed_header.deserialize()).unwrap(),
+                compressed_header
+            );
+            assert_eq!(
+                client_header.deserialize(),
+                Bytes::from(format!(
+                    "OPEN id={} time={} client=[2001:db8::1]:51234",
+                    metadata.id,
+                    crate::metadata::unix_millis(metadata.accepted)
+                ))
+            );
+            assert_eq!(
+                StreamHeader::serialize(client_header.deserialize()).unwrap(),
+                client_header
+            );
+
+            // a client without an id, as sent by earlier versions, gets one of its own
+            let header =
+                StreamHeader::serialize(Bytes::from_static(b"OPEN client=127.0.0.1:51234"))
+                    .unwrap();
+            assert_eq!(
+                header.metadata.unwrap().client,
+                Some(SocketAddr::from_str("127.0.0.1:51234").unwrap())
+            );
+            assert!(StreamHeader::serialize(Bytes::from_static(b"OPEN client=nowhere")).is_none());
+            assert!(StreamHeader::serialize(Bytes::from_static(b"OPEN compress=gzip")).is_none());
+            assert_eq!(
+                StreamHeader::serialize(Bytes::from_static(b"OPEN example.com:22 newer=1"))
+                    .unwrap(),
+                target_header
+            );
+            assert!(StreamHeader::serialize(Bytes::from_static(b"ACK")).is_none());
+        }
+
+        #[tokio::test]
+        async fn test_stream_header_framing() {
+            let header = StreamHeader {
+                target: Some("127.0.0.1:22".to_string()),
+                compression: Some(Compression::Lz4),
+                metadata: Some(Metadata::new(
+                    Some(SocketAddr::from_str("127.0.0.1:51234").unwrap()),
+                    Some(SocketAddr::from_str("127.0.0.1:8080").unwrap()),
+                )),
+            };
+
+            let mut buf = Vec::new();
+            header.write_to(&mut buf).await.unwrap();
+            buf.extend_from_slice(b"payload");
+
+            let mut reader = buf.as_slice();
+            assert_eq!(StreamHeader::read_from(&mut reader).await.unwrap(), header);
+            assert_eq!(reader, b"payload");
+
+            // the length prefix has no room for a longer header
+            let header = StreamHeader {
+                target: Some("a".repeat(usize::from(u16::MAX))),
+                ..StreamHeader::default()
+            };
+            let err = header.write_to(&mut Vec::new()).await.unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
         }
     }
 }
@@ -0,0 +1,526 @@
+// Compression of the tunnel half of a stream. Every write becomes a frame of its own, so a
+// flush always delivers what was written and interactive protocols never wait on a half filled
+// block. A frame is a kind byte, a big endian u32 length and the payload:
+//
+//   0  the payload is the data as is
+//   1  the payload is the data compressed with the stream's algorithm
+//
+// Data that doesn't get smaller is sent as is, and after a few such frames in a row the
+// stream stops trying for a while, so streams that are already compressed or encrypted cost
+// little more than the framing
+use std::{
+    fmt, io,
+    pin::Pin,
+    str::FromStr,
+    task::{ready, Context, Poll},
+};
+
+use s2n_quic::stream::BidirectionalStream;
+use serde::Deserialize;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::{common::proto::ProtoCommand, layer::BoxedIo};
+
+// Largest amount of data in a single frame, before compression
+const MAX_CHUNK: usize = 64 * 1024;
+const HEADER_LEN: usize = 5;
+
+// Smaller writes aren't worth compressing
+const MIN_COMPRESS_LEN: usize = 64;
+// Frames in a row that didn't get smaller before compression is paused, and for how many
+const MAX_MISSES: u32 = 4;
+const PAUSE_FRAMES: u32 = 64;
+
+#[cfg(feature = "compression")]
+const ZSTD_LEVEL: i32 = 3;
+
+const RAW_FRAME: u8 = 0;
+const COMPRESSED_FRAME: u8 = 1;
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Zstd,
+    Lz4,
+}
+
+impl Compression {
+    // Every algorithm this build can decode, in order of preference. Builds without the
+    // `compression` feature still know the names, but never compress
+    #[cfg(feature = "compression")]
+    pub const SUPPORTED: &'static [Compression] = &[Compression::Zstd, Compression::Lz4];
+    #[cfg(not(feature = "compression"))]
+    pub const SUPPORTED: &'static [Compression] = &[];
+
+    const ALL: [Compression; 2] = [Compression::Zstd, Compression::Lz4];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Compression::Zstd => "zstd",
+            Compression::Lz4 => "lz4",
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Compression::ALL
+            .into_iter()
+            .find(|compression| compression.name() == value)
+            .ok_or_else(|| format!("unknown compression `{value}`, expected zstd or lz4"))
+    }
+}
+
+// What an instance lists in its COMPRESS command
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Offer {
+    // the algorithms it can decode
+    pub supported: Vec<Compression>,
+
+    // the compression the remote of a forward tunnel wants for streams to some of its allowed
+    // targets, None for none. Locals list none
+    pub targets: Vec<(String, Option<Compression>)>,
+}
+
+impl Offer {
+    pub(crate) fn new(targets: Vec<(String, Option<Compression>)>) -> Self {
+        Self {
+            supported: Compression::SUPPORTED.to_vec(),
+            targets,
+        }
+    }
+
+    // The compression wanted for streams to the target, the remote's choice for it if it made
+    // one. None is its forward address
+    pub fn wanted(&self, target: Option<&str>, wanted: Option<Compression>) -> Option<Compression> {
+        let choice = self
+            .targets
+            .iter()
+            .find(|(name, _)| Some(name.as_str()) == target);
+        match choice {
+            Some((target, compression)) => {
+                tracing::debug!(
+                    "The remote instance wants {} for streams to {target}",
+                    compression.map_or("no compression", |compression| compression.name())
+                );
+                *compression
+            }
+            None => wanted,
+        }
+    }
+}
+
+// Reads `zstd`, `lz4` or `none`
+pub fn parse_choice(value: &str) -> Result<Option<Compression>, String> {
+    match value {
+        "none" => Ok(None),
+        value => value.parse().map(Some),
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+// Lists what this end supports as the first command on the command stream, the remote of a
+// forward tunnel sends the same in reply along with its choices for targets. Never sent to
+// instances of 0.1.5 and earlier, which don't expect it
+pub(crate) async fn send_offer(command_stream: &mut BidirectionalStream) -> Result<(), io::Error> {
+    let offer = ProtoCommand::COMPRESS(Offer::new(Vec::new()));
+    command_stream.send(offer.deserialize()).await?;
+    Ok(())
+}
+
+// What the other end supports, if the first command it sends lists it. Only asked of instances
+// that negotiated the current protocol, which always send it first
+pub(crate) async fn receive_offer(command_stream: &mut BidirectionalStream) -> Option<Offer> {
+    match command_stream.receive().await {
+        Ok(Some(data)) => match ProtoCommand::serialize(data) {
+            Some(ProtoCommand::COMPRESS(offer)) => Some(offer),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// The algorithm to use for the streams, the one this end wants if the other end supports it
+pub(crate) fn negotiate(wanted: Option<Compression>, offer: &Offer) -> Option<Compression> {
+    let compression = wanted.filter(|compression| {
+        Compression::SUPPORTED.contains(compression) && offer.supported.contains(compression)
+    });
+    match (wanted, compression) {
+        (Some(wanted), None) if !Compression::SUPPORTED.contains(&wanted) => {
+            tracing::warn!("This build has no {wanted} compression, streams are sent as is");
+        }
+        (Some(wanted), None) => {
+            tracing::info!("The other end of the tunnel doesn't support {wanted} compression, streams are sent as is");
+        }
+        _ => {}
+    }
+    compression
+}
+
+// Compresses what is written to the stream and decompresses what is read from it, none leaves
+// it as is
+pub(crate) fn wrap(stream: BoxedIo<'_>, compression: Option<Compression>) -> BoxedIo<'_> {
+    match compression {
+        Some(compression) => Box::new(Compressed::new(stream, compression)),
+        None => stream,
+    }
+}
+
+enum Codec {
+    #[cfg(feature = "compression")]
+    Zstd(
+        zstd::bulk::Compressor<'static>,
+        zstd::bulk::Decompressor<'static>,
+    ),
+    #[cfg(feature = "compression")]
+    Lz4,
+}
+
+impl Codec {
+    fn new(compression: Compression) -> io::Result<Self> {
+        match compression {
+            #[cfg(feature = "compression")]
+            Compression::Zstd => Ok(Codec::Zstd(
+                zstd::bulk::Compressor::new(ZSTD_LEVEL)?,
+                zstd::bulk::Decompressor::new()?,
+            )),
+            #[cfg(feature = "compression")]
+            Compression::Lz4 => Ok(Codec::Lz4),
+            // never negotiated, as this build doesn't offer it
+            #[cfg(not(feature = "compression"))]
+            _ => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("this build has no {compression} compression"),
+            )),
+        }
+    }
+
+    #[cfg_attr(not(feature = "compression"), allow(unused_variables))]
+    fn compress(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            #[cfg(feature = "compression")]
+            Codec::Zstd(compressor, _) => compressor.compress(data),
+            #[cfg(feature = "compression")]
+            Codec::Lz4 => Ok(lz4_flex::compress_prepend_size(data)),
+            #[cfg(not(feature = "compression"))]
+            _ => unreachable!("no codec is built without compression"),
+        }
+    }
+
+    #[cfg_attr(not(feature = "compression"), allow(unused_variables))]
+    fn decompress(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            #[cfg(feature = "compression")]
+            Codec::Zstd(_, decompressor) => decompressor
+                .decompress(data, MAX_CHUNK)
+                .map_err(|e| invalid(&e.to_string())),
+            #[cfg(feature = "compression")]
+            Codec::Lz4 => {
+                // the size is checked before decompressing allocates for it
+                let size = data
+                    .get(..4)
+                    .map(|size| u32::from_le_bytes(size.try_into().unwrap()) as usize)
+                    .ok_or_else(|| invalid("truncated lz4 frame"))?;
+                if size > MAX_CHUNK {
+                    return Err(invalid("lz4 frame too large"));
+                }
+                lz4_flex::decompress_size_prepended(data).map_err(|e| invalid(&e.to_string()))
+            }
+            #[cfg(not(feature = "compression"))]
+            _ => unreachable!("no codec is built without compression"),
+        }
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid compressed stream: {message}"),
+    )
+}
+
+struct Compressed<'a> {
+    inner: BoxedIo<'a>,
+    // created on first use, so a failure surfaces as an error of the stream
+    codec: Option<Codec>,
+    compression: Compression,
+
+    // a frame being written and how much of it was
+    pending: Vec<u8>,
+    written: usize,
+    misses: u32,
+    paused: u32,
+
+    // a frame being read, and data decoded from the last one that wasn't read yet
+    input: Vec<u8>,
+    output: Vec<u8>,
+    read: usize,
+}
+
+impl<'a> Compressed<'a> {
+    fn new(inner: BoxedIo<'a>, compression: Compression) -> Self {
+        Self {
+            inner,
+            codec: None,
+            compression,
+            pending: Vec::new(),
+            written: 0,
+            misses: 0,
+            paused: 0,
+            input: Vec::new(),
+            output: Vec::new(),
+            read: 0,
+        }
+    }
+
+    fn codec(&mut self) -> io::Result<&mut Codec> {
+        if self.codec.is_none() {
+            self.codec = Some(Codec::new(self.compression)?);
+        }
+        Ok(self.codec.as_mut().unwrap())
+    }
+
+    fn encode(&mut self, data: &[u8]) -> io::Result<()> {
+        let compressed = if data.len() < MIN_COMPRESS_LEN {
+            None
+        } else if self.paused > 0 {
+            self.paused -= 1;
+            None
+        } else {
+            let compressed = self.codec()?.compress(data)?;
+            // anything saving less than a sixteenth isn't worth decompressing
+            if compressed.len() < data.len() - data.len() / 16 {
+                self.misses = 0;
+                Some(compressed)
+            } else {
+                self.misses += 1;
+                if self.misses >= MAX_MISSES {
+                    self.misses = 0;
+                    self.paused = PAUSE_FRAMES;
+                }
+                None
+            }
+        };
+
+        let (kind, payload) = match &compressed {
+            Some(compressed) => (COMPRESSED_FRAME, compressed.as_slice()),
+            None => (RAW_FRAME, data),
+        };
+        self.pending.clear();
+        self.pending.push(kind);
+        self.pending
+            .extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        self.pending.extend_from_slice(payload);
+        self.written = 0;
+
+        Ok(())
+    }
+
+    // Writes out the pending frame
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.pending.len() {
+            let written =
+                ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending[self.written..]))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.written += written;
+        }
+        self.pending.clear();
+        self.written = 0;
+
+        Poll::Ready(Ok(()))
+    }
+
+    // How much of the current frame has to be read before it can be decoded
+    fn frame_len(&self) -> io::Result<usize> {
+        if self.input.len() < HEADER_LEN {
+            return Ok(HEADER_LEN);
+        }
+
+        let len = u32::from_be_bytes(self.input[1..HEADER_LEN].try_into().unwrap()) as usize;
+        // compressing can add a little to data that doesn't compress
+        if len > MAX_CHUNK + MAX_CHUNK / 16 + 64 {
+            return Err(invalid("frame too large"));
+        }
+        Ok(HEADER_LEN + len)
+    }
+
+    fn decode(&mut self) -> io::Result<()> {
+        let payload = &self.input[HEADER_LEN..];
+        self.output = match self.input[0] {
+            RAW_FRAME => payload.to_vec(),
+            COMPRESSED_FRAME => {
+                let payload = payload.to_vec();
+                self.codec()?.decompress(&payload)?
+            }
+            kind => return Err(invalid(&format!("unknown frame kind {kind}"))),
+        };
+        self.read = 0;
+        self.input.clear();
+
+        Ok(())
+    }
+}
+
+impl AsyncRead for Compressed<'_> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        loop {
+            if this.read < this.output.len() {
+                let len = buf.remaining().min(this.output.len() - this.read);
+                buf.put_slice(&this.output[this.read..this.read + len]);
+                this.read += len;
+                return Poll::Ready(Ok(()));
+            }
+
+            let frame_len = this.frame_len()?;
+            if this.input.len() >= HEADER_LEN && this.input.len() == frame_len {
+                this.decode()?;
+                continue;
+            }
+
+            // only the rest of the frame is read, the next one stays in the stream
+            let start = this.input.len();
+            this.input.resize(frame_len, 0);
+            let mut read_buf = ReadBuf::new(&mut this.input[start..]);
+            let res = Pin::new(&mut this.inner).poll_read(cx, &mut read_buf);
+            let read = read_buf.filled().len();
+            this.input.truncate(start + read);
+
+            match res {
+                Poll::Ready(Ok(())) if read == 0 => {
+                    return match start {
+                        0 => Poll::Ready(Ok(())),
+                        _ => Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into())),
+                    };
+                }
+                Poll::Ready(Ok(())) => {}
+                res => return res,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for Compressed<'_> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let len = buf.len().min(MAX_CHUNK);
+        this.encode(&buf[..len])?;
+
+        // the data is taken either way, the frame is finished by the next write or flush
+        if let Poll::Ready(Err(e)) = this.poll_pending(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(all(test, feature = "compression"))]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::{wrap, Compression, Offer};
+
+    #[test]
+    fn test_target_choice() {
+        let offer = Offer {
+            supported: vec![Compression::Zstd, Compression::Lz4],
+            targets: vec![
+                ("db:5432".to_string(), Some(Compression::Lz4)),
+                ("10.0.0.9:443".to_string(), None),
+            ],
+        };
+
+        // the remote's choice for a target wins over the one of the local
+        assert_eq!(offer.wanted(Some("db:5432"), None), Some(Compression::Lz4));
+        assert_eq!(
+            offer.wanted(Some("10.0.0.9:443"), Some(Compression::Zstd)),
+            None
+        );
+        assert_eq!(
+            offer.wanted(Some("cache:6379"), Some(Compression::Zstd)),
+            Some(Compression::Zstd)
+        );
+        assert_eq!(offer.wanted(None, None), None);
+    }
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        // text that compresses well followed by noise that doesn't
+        let mut data = b"GET /index.html HTTP/1.1\r\nHost: example.com\r\n\r\n".repeat(4096);
+        let mut state = 0x2545f491u32;
+        data.extend((0..200_000).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        }));
+
+        for &compression in Compression::SUPPORTED {
+            let (client, server) = tokio::io::duplex(16 * 1024);
+            let mut writer = wrap(Box::new(client), Some(compression));
+            let mut reader = wrap(Box::new(server), Some(compression));
+
+            let sent = data.clone();
+            let write = tokio::spawn(async move {
+                // small writes go out raw, larger ones are split into frames
+                writer.write_all(b"hi").await.unwrap();
+                writer.write_all(&sent).await.unwrap();
+                writer.shutdown().await.unwrap();
+            });
+
+            let mut received = Vec::new();
+            reader.read_to_end(&mut received).await.unwrap();
+            write.await.unwrap();
+
+            assert_eq!(&received[..2], b"hi", "{compression}");
+            assert!(received[2..] == data[..], "{compression}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_invalid_frame() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut reader = wrap(Box::new(server), Some(Compression::Lz4));
+
+        client.write_all(&[1, 0, 0, 0, 3, 1, 2, 3]).await.unwrap();
+        drop(client);
+
+        let err = reader.read_to_end(&mut Vec::new()).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,952 @@
+// Tunnels declared in a toml file, all run by one process. Keys are named after the command
+// line flags, and paths are relative to the file. Like the flags, cert and key also take `-`
+// for stdin or the PEM itself:
+//
+//   debug = false
+//   buffersize = 32768
+//   logformat = "json"
+//   logfile = "/var/log/sirang.log"
+//
+//   [tunnels.ssh]
+//   type = "forward"
+//   side = "local"
+//   cert = "cert.pem"
+//   remoteaddr = "203.0.113.5:4433"
+//   localaddr = "127.0.0.1:2222"
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    ops::Range,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::Deserialize;
+use toml::Spanned;
+
+use crate::{
+    access::{AccessLog, AccessLogFormat},
+    common::TunnelType,
+    compress::Compression,
+    errors::Error,
+    exec::HookCommands,
+    layer::Layers,
+    local::{self, config::LocalConfig},
+    logging::{LogFormat, LogOptions},
+    monitor::Monitor,
+    proxy::{Cidr, ProxyProtocol},
+    remote::{self, config::RemoteConfig},
+    shutdown::StopSignal,
+    tls::PemSource,
+};
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    debug: Option<bool>,
+    logformat: Option<LogFormat>,
+    logfilter: Option<String>,
+    logfile: Option<PathBuf>,
+    // in megabytes
+    logfilesize: Option<u64>,
+    logfiles: Option<usize>,
+    otlpendpoint: Option<String>,
+    metricsaddr: Option<SocketAddr>,
+
+    // defaults for tunnels that don't set their own
+    buffersize: Option<usize>,
+    draintimeout: Option<u64>,
+
+    #[serde(default)]
+    tunnels: BTreeMap<String, Spanned<TunnelDef>>,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Kind {
+    Forward,
+    Reverse,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Side {
+    Local,
+    Remote,
+}
+
+#[derive(Deserialize, Default, Clone, PartialEq, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct TunnelDef {
+    #[serde(rename = "type")]
+    pub(crate) kind: Option<Kind>,
+    pub(crate) side: Option<Side>,
+    pub(crate) cert: Option<PemSource>,
+    pub(crate) key: Option<PemSource>,
+    pub(crate) localaddr: Option<SocketAddr>,
+    pub(crate) remoteaddr: Option<SocketAddr>,
+    pub(crate) quicaddr: Option<SocketAddr>,
+    pub(crate) forwardaddr: Option<SocketAddr>,
+    pub(crate) tcpaddr: Option<SocketAddr>,
+    pub(crate) allowtarget: Option<Vec<String>>,
+    pub(crate) buffersize: Option<usize>,
+    pub(crate) draintimeout: Option<u64>,
+    pub(crate) goaway: Option<u64>,
+    pub(crate) redirectaddr: Option<SocketAddr>,
+    pub(crate) handover: Option<PathBuf>,
+    pub(crate) compress: Option<Compression>,
+    pub(crate) proxyprotocol: Option<ProxyProtocol>,
+    pub(crate) acceptproxy: Option<Vec<Cidr>>,
+    pub(crate) onup: Option<String>,
+    pub(crate) ondown: Option<String>,
+    pub(crate) onstreamopen: Option<String>,
+    pub(crate) onstreamclose: Option<String>,
+    pub(crate) accesslog: Option<PathBuf>,
+    pub(crate) accesslogformat: Option<AccessLogFormat>,
+}
+
+impl TunnelDef {
+    // Takes every value the other definition sets
+    fn merge(&mut self, other: TunnelDef) {
+        self.kind = other.kind.or(self.kind);
+        self.side = other.side.or(self.side);
+        self.cert = other.cert.or(self.cert.take());
+        self.key = other.key.or(self.key.take());
+        self.localaddr = other.localaddr.or(self.localaddr);
+        self.remoteaddr = other.remoteaddr.or(self.remoteaddr);
+        self.quicaddr = other.quicaddr.or(self.quicaddr);
+        self.forwardaddr = other.forwardaddr.or(self.forwardaddr);
+        self.tcpaddr = other.tcpaddr.or(self.tcpaddr);
+        self.allowtarget = other.allowtarget.or(self.allowtarget.take());
+        self.buffersize = other.buffersize.or(self.buffersize);
+        self.draintimeout = other.draintimeout.or(self.draintimeout);
+        self.goaway = other.goaway.or(self.goaway);
+        self.redirectaddr = other.redirectaddr.or(self.redirectaddr);
+        self.handover = other.handover.or(self.handover.take());
+        self.compress = other.compress.or(self.compress);
+        self.proxyprotocol = other.proxyprotocol.or(self.proxyprotocol);
+        self.acceptproxy = other.acceptproxy.or(self.acceptproxy.take());
+        self.onup = other.onup.or(self.onup.take());
+        self.ondown = other.ondown.or(self.ondown.take());
+        self.onstreamopen = other.onstreamopen.or(self.onstreamopen.take());
+        self.onstreamclose = other.onstreamclose.or(self.onstreamclose.take());
+        self.accesslog = other.accesslog.or(self.accesslog.take());
+        self.accesslogformat = other.accesslogformat.or(self.accesslogformat.take());
+    }
+
+    // Mode specific keys along with whether they are set, the shared ones are always allowed
+    fn mode_fields(&self) -> [(&'static str, bool); 14] {
+        [
+            ("cert", self.cert.is_some()),
+            ("key", self.key.is_some()),
+            ("localaddr", self.localaddr.is_some()),
+            ("remoteaddr", self.remoteaddr.is_some()),
+            ("quicaddr", self.quicaddr.is_some()),
+            ("forwardaddr", self.forwardaddr.is_some()),
+            ("tcpaddr", self.tcpaddr.is_some()),
+            ("allowtarget", self.allowtarget.is_some()),
+            ("goaway", self.goaway.is_some()),
+            ("redirectaddr", self.redirectaddr.is_some()),
+            ("handover", self.handover.is_some()),
+            ("compress", self.compress.is_some()),
+            ("proxyprotocol", self.proxyprotocol.is_some()),
+            ("acceptproxy", self.acceptproxy.is_some()),
+        ]
+    }
+}
+
+// Keys each mode accepts and which of them it requires
+fn mode_keys(kind: Kind, side: Side) -> (&'static [&'static str], &'static [&'static str]) {
+    match (kind, side) {
+        (Kind::Forward, Side::Local) => (
+            &["cert", "localaddr", "remoteaddr", "compress", "acceptproxy"],
+            &["cert", "remoteaddr"],
+        ),
+        (Kind::Forward, Side::Remote) => (
+            &[
+                "cert",
+                "key",
+                "quicaddr",
+                "forwardaddr",
+                "allowtarget",
+                "goaway",
+                "redirectaddr",
+                "handover",
+                "proxyprotocol",
+                "acceptproxy",
+            ],
+            &["cert", "key", "forwardaddr"],
+        ),
+        (Kind::Reverse, Side::Local) => (
+            &["cert", "localaddr", "remoteaddr", "proxyprotocol"],
+            &["cert", "localaddr", "remoteaddr"],
+        ),
+        (Kind::Reverse, Side::Remote) => (
+            &[
+                "cert",
+                "key",
+                "quicaddr",
+                "tcpaddr",
+                "goaway",
+                "redirectaddr",
+                "handover",
+                "compress",
+                "acceptproxy",
+            ],
+            &["cert", "key"],
+        ),
+    }
+}
+
+// Values given on the command line, applied over the file
+#[derive(Default)]
+pub struct Overrides {
+    pub debug: bool,
+    pub buffer_size: Option<usize>,
+    pub drain_timeout: Option<u64>,
+    pub log_format: Option<LogFormat>,
+    pub log_filter: Option<String>,
+    pub log_file: Option<PathBuf>,
+    pub log_file_size: Option<u64>,
+    pub log_files: Option<usize>,
+    pub otlp_endpoint: Option<String>,
+    pub metrics_address: Option<SocketAddr>,
+
+    // tunnel.key=value pairs
+    pub values: Vec<String>,
+}
+
+impl Overrides {
+    // Logging as the command line alone sets it up, for errors found before the file is read
+    pub fn log_options(&self) -> Result<LogOptions, Error> {
+        Ok(LogOptions {
+            debug: self.debug,
+            format: self.log_format.unwrap_or_default(),
+            filter: self.log_filter.clone(),
+            file: self.log_file.clone(),
+            max_size: self.log_file_size.map(log_file_bytes).transpose()?,
+            max_files: self.log_files,
+            otlp_endpoint: self.otlp_endpoint.clone(),
+        })
+    }
+}
+
+// The largest log file size in megabytes whose size in bytes still fits
+pub(crate) const MAX_LOG_FILE_SIZE: u64 = u64::MAX / (1024 * 1024);
+
+// The size in bytes of a log file given in megabytes
+pub(crate) fn log_file_bytes(megabytes: u64) -> Result<u64, Error> {
+    megabytes.checked_mul(1024 * 1024).ok_or_else(|| {
+        Error::Config(format!(
+            "log file size of {megabytes} megabytes is too large"
+        ))
+    })
+}
+
+#[derive(Clone)]
+pub enum TunnelConfig {
+    Local(LocalConfig),
+    Remote(RemoteConfig),
+}
+
+impl TunnelConfig {
+    pub(crate) fn set_stop(&mut self, stop: StopSignal) {
+        match self {
+            TunnelConfig::Local(config) => config.stop = stop,
+            TunnelConfig::Remote(config) => config.stop = stop,
+        }
+    }
+
+    pub(crate) fn set_layers(&mut self, layers: Layers) {
+        match self {
+            TunnelConfig::Local(config) => config.layers = layers,
+            TunnelConfig::Remote(config) => config.layers = layers,
+        }
+    }
+
+    pub fn monitor(&self) -> &Monitor {
+        match self {
+            TunnelConfig::Local(config) => &config.monitor,
+            TunnelConfig::Remote(config) => &config.monitor,
+        }
+    }
+
+    // Runs the tunnel until it is stopped or fails
+    pub async fn run(self) -> Result<(), Error> {
+        match self {
+            TunnelConfig::Local(config) => local::start_local(config).await,
+            TunnelConfig::Remote(config) => remote::start_remote(config).await,
+        }
+    }
+
+    fn pem_files(&self) -> (&str, &str) {
+        match self {
+            TunnelConfig::Local(config) => (&config.tls_cert, ""),
+            TunnelConfig::Remote(config) => (&config.tls_cert, &config.tls_key),
+        }
+    }
+}
+
+// The hook commands and access log of a tunnel, which start threads and open files
+#[derive(Clone)]
+pub(crate) struct Outputs {
+    hook_commands: HookCommands,
+    access_log: Option<AccessLog>,
+    // the end of the tunnel with the listener, rather than the one that dials the target
+    accepts_clients: bool,
+}
+
+impl Outputs {
+    pub(crate) fn register(&self, monitor: &Monitor) -> Result<(), Error> {
+        self.hook_commands.clone().register(monitor);
+        if let Some(access_log) = &self.access_log {
+            access_log.clone().register(monitor, self.accepts_clients)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct Tunnel {
+    pub name: String,
+    pub config: TunnelConfig,
+
+    // the definition it was built from, with defaults and overrides applied
+    def: TunnelDef,
+    outputs: Outputs,
+}
+
+impl Tunnel {
+    // Runs the hook commands and opens the access log on the tunnel's events, once it is
+    // actually started. Reloads build every tunnel of the file, most of which are left as is
+    pub(crate) fn register_outputs(&self) -> Result<(), Error> {
+        self.outputs.register(self.config.monitor())
+    }
+
+    // Whether both would run the same tunnel. Certificates and keys are compared by content,
+    // so replacing the files counts as a change
+    pub fn same_as(&self, other: &Tunnel) -> bool {
+        self.name == other.name
+            && self.def == other.def
+            && self.config.pem_files() == other.config.pem_files()
+    }
+}
+
+pub struct Config {
+    pub log: LogOptions,
+    // where metrics of every tunnel are served, they aren't if none
+    pub metrics_address: Option<SocketAddr>,
+    pub tunnels: Vec<Tunnel>,
+}
+
+pub fn load(path: &Path, overrides: &Overrides) -> Result<Config, Error> {
+    let source = std::fs::read_to_string(path).map_err(|e| {
+        Error::Config(format!(
+            "unable to read config file {}: {e}",
+            path.display()
+        ))
+    })?;
+
+    parse(&source, path, overrides)
+}
+
+fn parse(source: &str, path: &Path, overrides: &Overrides) -> Result<Config, Error> {
+    let mut file: ConfigFile = toml::from_str(source).map_err(|e| {
+        let location = match e.span() {
+            Some(span) => match value_key(source, span.start) {
+                Some(key) => format!("{}: `{key}`", locate(path, source, span)),
+                None => locate(path, source, span),
+            },
+            None => path.display().to_string(),
+        };
+        Error::Config(format!("{location}: {}", e.message().trim_end()))
+    })?;
+
+    for value in &overrides.values {
+        apply_override(&mut file, value)?;
+    }
+
+    if file.tunnels.is_empty() {
+        return Err(Error::Config(format!(
+            "{}: no tunnels defined, add a [tunnels.<name>] table",
+            path.display()
+        )));
+    }
+
+    let base_dir = path.parent().unwrap_or(Path::new(""));
+    let mut tunnels = Vec::new();
+
+    for (name, def) in &file.tunnels {
+        let location = locate(path, source, def.span());
+
+        let mut def = def.get_ref().clone();
+        def.buffersize = overrides.buffer_size.or(def.buffersize).or(file.buffersize);
+        def.draintimeout = overrides
+            .drain_timeout
+            .or(def.draintimeout)
+            .or(file.draintimeout);
+
+        let (config, outputs) = build_tunnel(def.clone(), base_dir)
+            .map_err(|e| e.context(&format!("{location}: tunnel `{name}`")))?;
+        tunnels.push(Tunnel {
+            name: name.clone(),
+            config,
+            def,
+            outputs,
+        });
+    }
+
+    let log = LogOptions {
+        debug: overrides.debug || file.debug.unwrap_or(false),
+        format: overrides.log_format.or(file.logformat).unwrap_or_default(),
+        filter: overrides.log_filter.clone().or(file.logfilter),
+        file: overrides
+            .log_file
+            .clone()
+            .or_else(|| file.logfile.map(|path| base_dir.join(path))),
+        max_size: overrides
+            .log_file_size
+            .or(file.logfilesize)
+            .map(log_file_bytes)
+            .transpose()?,
+        max_files: overrides.log_files.or(file.logfiles),
+        otlp_endpoint: overrides.otlp_endpoint.clone().or(file.otlpendpoint),
+    };
+
+    Ok(Config {
+        log,
+        metrics_address: overrides.metrics_address.or(file.metricsaddr),
+        tunnels,
+    })
+}
+
+// Applies a tunnel.key=value override. Values that aren't valid toml are taken as strings,
+// so addresses and paths don't need quoting
+fn apply_override(file: &mut ConfigFile, value: &str) -> Result<(), Error> {
+    let invalid = |message: String| Error::Config(format!("--set {value}: {message}"));
+
+    let (key, raw) = value
+        .split_once('=')
+        .ok_or_else(|| invalid("expected tunnel.key=value".to_string()))?;
+    let (name, field) = key
+        .split_once('.')
+        .ok_or_else(|| invalid("expected tunnel.key=value".to_string()))?;
+
+    let tunnel = file
+        .tunnels
+        .get_mut(name)
+        .ok_or_else(|| invalid(format!("no tunnel named `{name}`")))?;
+
+    let raw = if format!("v = {raw}").parse::<toml::Table>().is_ok() {
+        raw.to_string()
+    } else {
+        toml::Value::String(raw.to_string()).to_string()
+    };
+
+    let def: TunnelDef = toml::from_str(&format!("{field} = {raw}"))
+        .map_err(|e| invalid(e.message().trim_end().to_string()))?;
+    tunnel.get_mut().merge(def);
+
+    Ok(())
+}
+
+// Checks a tunnel definition and reads its certificate and key. The hook commands and access log
+// come back apart from the tunnel, they are only set up when it starts
+pub(crate) fn build_tunnel(
+    def: TunnelDef,
+    base_dir: &Path,
+) -> Result<(TunnelConfig, Outputs), Error> {
+    let invalid = |message: &str| Error::Config(message.to_string());
+
+    let kind = def
+        .kind
+        .ok_or_else(|| invalid("missing `type`, expected \"forward\" or \"reverse\""))?;
+    let side = def
+        .side
+        .ok_or_else(|| invalid("missing `side`, expected \"local\" or \"remote\""))?;
+    let mode = format!("{kind:?} {side:?}").to_lowercase();
+
+    let (allowed, required) = mode_keys(kind, side);
+    let fields = def.mode_fields();
+    if let Some((field, _)) = fields
+        .iter()
+        .find(|(field, is_set)| *is_set && !allowed.contains(field))
+    {
+        return Err(invalid(&format!(
+            "`{field}` is not used by a {mode} tunnel"
+        )));
+    }
+    if let Some((field, _)) = fields
+        .iter()
+        .find(|(field, is_set)| !*is_set && required.contains(field))
+    {
+        return Err(invalid(&format!(
+            "missing `{field}`, required by a {mode} tunnel"
+        )));
+    }
+
+    let tunnel_type = match kind {
+        Kind::Forward => TunnelType::Forward,
+        Kind::Reverse => TunnelType::Reverse,
+    };
+    let hook_commands = HookCommands {
+        on_up: def.onup,
+        on_down: def.ondown,
+        on_stream_open: def.onstreamopen,
+        on_stream_close: def.onstreamclose,
+    };
+    if def.accesslogformat.is_some() && def.accesslog.is_none() {
+        return Err(invalid("`accesslogformat` needs `accesslog`"));
+    }
+    // `-` is stdout rather than a file next to the config
+    let access_log = def.accesslog.map(|path| AccessLog {
+        path: if path.as_os_str() == "-" {
+            path
+        } else {
+            base_dir.join(path)
+        },
+        format: def.accesslogformat.unwrap_or_default(),
+    });
+    let outputs = Outputs {
+        hook_commands,
+        access_log,
+        accepts_clients: matches!(
+            (kind, side),
+            (Kind::Forward, Side::Local) | (Kind::Reverse, Side::Remote)
+        ),
+    };
+    let tls_cert = def
+        .cert
+        .unwrap()
+        .relative_to(base_dir)
+        .read_cert()
+        .map_err(|e| e.context("`cert`"))?;
+
+    match side {
+        Side::Local => {
+            let mut config = LocalConfig {
+                tunnel_type,
+                tls_cert,
+                ..LocalConfig::default()
+            };
+
+            config.remote_quic_server_addr = def.remoteaddr.unwrap();
+            if let Some(localaddr) = def.localaddr {
+                config.local_tcp_server_addr = localaddr;
+            }
+            if let Some(buffer_size) = def.buffersize {
+                config.buffer_size = buffer_size;
+            }
+            if let Some(drain_timeout) = def.draintimeout {
+                config.drain_timeout = Duration::from_secs(drain_timeout);
+            }
+            config.compression = def.compress;
+            config.proxy_protocol = def.proxyprotocol;
+            config.trusted_proxies = def.acceptproxy.unwrap_or_default();
+
+            Ok((TunnelConfig::Local(config), outputs))
+        }
+        Side::Remote => {
+            let mut config = RemoteConfig::new(&tunnel_type);
+            config.tls_cert = tls_cert;
+            config.tls_key = def
+                .key
+                .unwrap()
+                .relative_to(base_dir)
+                .read_key()
+                .map_err(|e| e.context("`key`"))?;
+
+            config.tcp_forward_address = def.forwardaddr;
+            config
+                .allow_targets(def.allowtarget.iter().flatten())
+                .map_err(|e| e.context("`allowtarget`"))?;
+            if let Some(tcpaddr) = def.tcpaddr {
+                config.tcp_reverse_address = Some(tcpaddr);
+            }
+            if let Some(quicaddr) = def.quicaddr {
+                config.quic_address = quicaddr;
+            }
+            if let Some(buffer_size) = def.buffersize {
+                config.buffer_size = buffer_size;
+            }
+            if let Some(drain_timeout) = def.draintimeout {
+                config.drain_timeout = Duration::from_secs(drain_timeout);
+            }
+            config.goaway_delay = def.goaway.map(Duration::from_secs);
+            config.redirect_address = def.redirectaddr;
+            config.handover_path = def.handover.map(|handover| base_dir.join(handover));
+            config.compression = def.compress;
+            config.proxy_protocol = def.proxyprotocol;
+            config.trusted_proxies = def.acceptproxy.unwrap_or_default();
+
+            Ok((TunnelConfig::Remote(config), outputs))
+        }
+    }
+}
+
+// file:line:column of the start of a span
+fn locate(path: &Path, source: &str, span: Range<usize>) -> String {
+    let before = &source[..span.start.min(source.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.len() - before.rfind('\n').map(|i| i + 1).unwrap_or(0) + 1;
+
+    format!("{}:{line}:{column}", path.display())
+}
+
+// Key of the value starting at an offset, for errors about the value rather than the key
+fn value_key(source: &str, offset: usize) -> Option<&str> {
+    let before = &source[..offset.min(source.len())];
+    let line = &before[before.rfind('\n').map(|i| i + 1).unwrap_or(0)..];
+    let (key, _) = line.rsplit(['{', ',']).next()?.split_once('=')?;
+
+    Some(key.trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{parse, Cidr, Compression, LogFormat, LogOptions, Overrides, TunnelConfig};
+
+    const CERT: &str = "../test_cert.pem";
+
+    fn parse_str(source: &str, overrides: &Overrides) -> Result<super::Config, String> {
+        parse(source, Path::new("src/sirang.toml"), overrides).map_err(|e| e.to_string())
+    }
+
+    fn parse_err(source: &str, overrides: &Overrides) -> String {
+        match parse_str(source, overrides) {
+            Ok(_) => panic!("expected {source:?} to be rejected"),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn test_parse_tunnels() {
+        let source = format!(
+            r#"
+buffersize = 1024
+
+[tunnels.ssh]
+type = "forward"
+side = "local"
+cert = "{CERT}"
+remoteaddr = "127.0.0.1:4433"
+localaddr = "127.0.0.1:2222"
+compress = "zstd"
+
+[tunnels.web]
+type = "reverse"
+side = "remote"
+cert = "{CERT}"
+key = "../test_key.pem"
+tcpaddr = "0.0.0.0:8080"
+buffersize = 2048
+acceptproxy = ["10.0.0.0/8", "192.0.2.1"]
+"#
+        );
+
+        let config = parse_str(&source, &Overrides::default()).unwrap();
+        assert_eq!(config.log, LogOptions::default());
+        assert_eq!(config.tunnels.len(), 2);
+
+        match &config.tunnels[0].config {
+            TunnelConfig::Local(local) => {
+                assert_eq!(config.tunnels[0].name, "ssh");
+                assert_eq!(local.local_tcp_server_addr.port(), 2222);
+                assert_eq!(local.buffer_size, 1024);
+                assert_eq!(local.compression, Some(Compression::Zstd));
+            }
+            TunnelConfig::Remote(_) => panic!("ssh should be a local tunnel"),
+        }
+
+        match &config.tunnels[1].config {
+            TunnelConfig::Remote(remote) => {
+                assert_eq!(remote.tcp_reverse_address.unwrap().port(), 8080);
+                assert_eq!(remote.buffer_size, 2048);
+                assert!(!remote.tls_key.is_empty());
+                assert_eq!(remote.compression, None);
+                assert_eq!(
+                    remote.trusted_proxies,
+                    vec![
+                        "10.0.0.0/8".parse::<Cidr>().unwrap(),
+                        "192.0.2.1/32".parse::<Cidr>().unwrap()
+                    ]
+                );
+            }
+            TunnelConfig::Local(_) => panic!("web should be a remote tunnel"),
+        }
+    }
+
+    #[test]
+    fn test_parse_target_compression() {
+        let source = format!(
+            r#"
+[tunnels.db]
+type = "forward"
+side = "remote"
+cert = "{CERT}"
+key = "../test_key.pem"
+forwardaddr = "127.0.0.1:5432"
+allowtarget = ["10.0.0.5:5432,compress=zstd", "10.0.0.9:443,compress=none", "10.0.0.7:22"]
+"#
+        );
+
+        let config = parse_str(&source, &Overrides::default()).unwrap();
+        match &config.tunnels[0].config {
+            TunnelConfig::Remote(remote) => {
+                assert_eq!(
+                    remote.allowed_targets,
+                    vec!["10.0.0.5:5432", "10.0.0.9:443", "10.0.0.7:22"]
+                );
+                assert_eq!(
+                    remote.target_compression,
+                    vec![
+                        ("10.0.0.5:5432".to_string(), Some(Compression::Zstd)),
+                        ("10.0.0.9:443".to_string(), None)
+                    ]
+                );
+            }
+            TunnelConfig::Local(_) => panic!("db should be a remote tunnel"),
+        }
+
+        let err = parse_err(
+            &source.replace("compress=none", "compress=brotli"),
+            &Overrides::default(),
+        );
+        assert!(
+            err.contains("`allowtarget`: unknown compression `brotli`"),
+            "{err}"
+        );
+        let err = parse_err(
+            &source.replace("compress=none", "level=3"),
+            &Overrides::default(),
+        );
+        assert!(
+            err.contains("unknown option `level=3` for target 10.0.0.9:443"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let err = parse_err(
+            "[tunnels.ssh]\ntype = \"forward\"\nside = \"local\"\nremoteaddr = \"nowhere\"\n",
+            &Overrides::default(),
+        );
+        assert!(
+            err.contains("src/sirang.toml:4:14: `remoteaddr`: invalid socket address"),
+            "{err}"
+        );
+
+        let err = parse_err(
+            "\n[tunnels.ssh]\ntype = \"forward\"\nside = \"local\"\ncert = \"x\"\n",
+            &Overrides::default(),
+        );
+        assert!(err.contains("src/sirang.toml:2:1: tunnel `ssh`"), "{err}");
+        assert!(err.contains("missing `remoteaddr`"), "{err}");
+
+        let err = parse_err(
+            "[tunnels.ssh]\ntype = \"forward\"\nside = \"local\"\ntcpaddr = \"0.0.0.0:80\"\n",
+            &Overrides::default(),
+        );
+        assert!(
+            err.contains("`tcpaddr` is not used by a forward local tunnel"),
+            "{err}"
+        );
+
+        let err = parse_err(
+            "[tunnels.ssh]\ntype = \"reverse\"\nside = \"local\"\ncompress = \"lz4\"\n",
+            &Overrides::default(),
+        );
+        assert!(
+            err.contains("`compress` is not used by a reverse local tunnel"),
+            "{err}"
+        );
+
+        let err = parse_err(
+            "[tunnels.ssh]\ntype = \"forward\"\nside = \"local\"\nproxyprotocol = \"v2\"\n",
+            &Overrides::default(),
+        );
+        assert!(
+            err.contains("`proxyprotocol` is not used by a forward local tunnel"),
+            "{err}"
+        );
+
+        let err = parse_err(
+            "[tunnels.web]\ntype = \"reverse\"\nside = \"remote\"\nacceptproxy = [\"10.0.0.0/33\"]\n",
+            &Overrides::default(),
+        );
+        assert!(err.contains("`acceptproxy`"), "{err}");
+
+        let err = parse_err(
+            &format!("[tunnels.ssh]\ntype = \"forward\"\nside = \"local\"\ncert = \"{CERT}\"\nremoteaddr = \"127.0.0.1:4433\"\naccesslogformat = \"text\"\n"),
+            &Overrides::default(),
+        );
+        assert!(err.contains("`accesslogformat` needs `accesslog`"), "{err}");
+
+        let err = parse_err(
+            "[tunnels.ssh]\naccesslog = \"-\"\naccesslogformat = \"{client} {bytes}\"\n",
+            &Overrides::default(),
+        );
+        assert!(err.contains("unknown access log field `bytes`"), "{err}");
+
+        let err = parse_err("[tunnels.ssh]\nport = 22\n", &Overrides::default());
+        assert!(
+            err.contains("src/sirang.toml:2:1: unknown field `port`"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_log_options() {
+        let source = format!(
+            "logformat = \"json\"\nlogfile = \"sirang.log\"\nlogfilesize = 10\nlogfilter = \"s2n_quic=warn\"\n\n[tunnels.ssh]\ntype = \"forward\"\nside = \"local\"\ncert = \"{CERT}\"\nremoteaddr = \"127.0.0.1:4433\"\n"
+        );
+
+        let config = parse_str(&source, &Overrides::default()).unwrap();
+        assert_eq!(
+            config.log,
+            LogOptions {
+                debug: false,
+                format: LogFormat::Json,
+                filter: Some("s2n_quic=warn".to_string()),
+                file: Some(Path::new("src/sirang.log").to_path_buf()),
+                max_size: Some(10 * 1024 * 1024),
+                max_files: None,
+                otlp_endpoint: None,
+            }
+        );
+
+        // a log file given on the command line is relative to the working directory
+        let overrides = Overrides {
+            log_format: Some(LogFormat::Text),
+            log_file: Some("sirang.log".into()),
+            log_files: Some(2),
+            ..Overrides::default()
+        };
+        let config = parse_str(&source, &overrides).unwrap();
+        assert_eq!(config.log.format, LogFormat::Text);
+        assert_eq!(config.log.file, Some("sirang.log".into()));
+        assert_eq!(config.log.max_files, Some(2));
+
+        let err = parse_err(&source.replace("json", "xml"), &Overrides::default());
+        assert!(err.contains("unknown variant `xml`"), "{err}");
+
+        let err = parse_err(
+            &source.replace("logfilesize = 10", "logfilesize = 9223372036854775807"),
+            &Overrides::default(),
+        );
+        assert!(err.contains("log file size of"), "{err}");
+        let overrides = Overrides {
+            log_file_size: Some(u64::MAX),
+            ..Overrides::default()
+        };
+        assert!(matches!(
+            overrides.log_options(),
+            Err(crate::Error::Config(_))
+        ));
+    }
+
+    #[test]
+    fn test_metrics_address() {
+        let source = format!(
+            "metricsaddr = \"127.0.0.1:9100\"\n\n[tunnels.ssh]\ntype = \"forward\"\nside = \"local\"\ncert = \"{CERT}\"\nremoteaddr = \"127.0.0.1:4433\"\n"
+        );
+
+        let config = parse_str(&source, &Overrides::default()).unwrap();
+        assert_eq!(
+            config.metrics_address,
+            Some("127.0.0.1:9100".parse().unwrap())
+        );
+
+        let overrides = Overrides {
+            metrics_address: Some("0.0.0.0:9200".parse().unwrap()),
+            ..Overrides::default()
+        };
+        let config = parse_str(&source, &overrides).unwrap();
+        assert_eq!(
+            config.metrics_address,
+            Some("0.0.0.0:9200".parse().unwrap())
+        );
+
+        let err = parse_err(
+            &source.replace("127.0.0.1:9100", "nowhere"),
+            &Overrides::default(),
+        );
+        assert!(err.contains("`metricsaddr`"), "{err}");
+    }
+
+    #[test]
+    fn test_overrides() {
+        let source = format!(
+            "[tunnels.ssh]\ntype = \"forward\"\nside = \"local\"\ncert = \"{CERT}\"\nremoteaddr = \"127.0.0.1:4433\"\n"
+        );
+        let overrides = Overrides {
+            debug: true,
+            drain_timeout: Some(5),
+            values: vec![
+                "ssh.localaddr=127.0.0.1:2200".to_string(),
+                "ssh.buffersize=4096".to_string(),
+            ],
+            ..Overrides::default()
+        };
+
+        let config = parse_str(&source, &overrides).unwrap();
+        assert!(config.log.debug);
+        match &config.tunnels[0].config {
+            TunnelConfig::Local(local) => {
+                assert_eq!(local.local_tcp_server_addr.port(), 2200);
+                assert_eq!(local.buffer_size, 4096);
+                assert_eq!(local.drain_timeout.as_secs(), 5);
+            }
+            TunnelConfig::Remote(_) => panic!("ssh should be a local tunnel"),
+        }
+
+        let overrides = Overrides {
+            values: vec!["web.buffersize=1".to_string()],
+            ..Overrides::default()
+        };
+        let err = parse_err(&source, &overrides);
+        assert!(err.contains("no tunnel named `web`"), "{err}");
+    }
+
+    #[test]
+    fn test_same_tunnel() {
+        let source = |localaddr: &str| {
+            format!(
+                "[tunnels.ssh]\ntype = \"forward\"\nside = \"local\"\ncert = \"{CERT}\"\nremoteaddr = \"127.0.0.1:4433\"\nlocaladdr = \"{localaddr}\"\n"
+            )
+        };
+
+        let running = parse_str(&source("127.0.0.1:2222"), &Overrides::default()).unwrap();
+        let reloaded = parse_str(&source("127.0.0.1:2222"), &Overrides::default()).unwrap();
+        assert!(running.tunnels[0].same_as(&reloaded.tunnels[0]));
+
+        let changed = parse_str(&source("127.0.0.1:2200"), &Overrides::default()).unwrap();
+        assert!(!running.tunnels[0].same_as(&changed.tunnels[0]));
+
+        let overrides = Overrides {
+            buffer_size: Some(1024),
+            ..Overrides::default()
+        };
+        let changed = parse_str(&source("127.0.0.1:2222"), &overrides).unwrap();
+        assert!(!running.tunnels[0].same_as(&changed.tunnels[0]));
+    }
+
+    #[test]
+    fn test_outputs_on_start() {
+        let path = std::env::temp_dir().join(format!("sirang-outputs-{}", std::process::id()));
+        let source = format!(
+            "[tunnels.ssh]\ntype = \"forward\"\nside = \"local\"\ncert = \"{CERT}\"\nremoteaddr = \"127.0.0.1:4433\"\naccesslog = \"{}\"\n",
+            path.display()
+        );
+
+        // reading the file, as a reload does, leaves the access log alone
+        let config = parse_str(&source, &Overrides::default()).unwrap();
+        assert!(!path.exists());
+
+        config.tunnels[0].register_outputs().unwrap();
+        assert!(path.exists());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
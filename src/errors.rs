@@ -1,10 +1,300 @@
+use std::fmt;
+
+use s2n_quic::{application, connection, stream};
+
+use crate::common::SHUTDOWN_ERROR_CODE;
+
+// Quic application error codes, one per kind of error. A connection failing because of an
+// error is closed with its code, which the peer decodes back into the same kind. These are
+// part of the protocol, so existing codes must never change
+pub mod code {
+    pub const CLOSED: u32 = super::SHUTDOWN_ERROR_CODE;
+    pub const INTERNAL: u32 = 0x02;
+    pub const CONFIG: u32 = 0x03;
+    pub const TLS: u32 = 0x04;
+    pub const HANDSHAKE: u32 = 0x05;
+    pub const AUTH: u32 = 0x06;
+    pub const DIAL: u32 = 0x07;
+    pub const BIND: u32 = 0x08;
+    pub const PROTOCOL: u32 = 0x09;
+    pub const CONNECTION: u32 = 0x0a;
+    pub const REFUSED: u32 = 0x0b;
+    pub const TIMED_OUT: u32 = 0x0c;
+    pub const RESET: u32 = 0x0d;
+}
+
 #[derive(Debug)]
-pub struct GenericError(pub String);
+#[non_exhaustive]
+pub enum Error {
+    // invalid command line options or config file
+    Config(String),
+    // a certificate or key couldn't be loaded or used
+    Tls(String),
+    // the quic connection or the tunnel handshake with the peer failed
+    Handshake(String),
+    // the peer asked for something it isn't allowed to
+    Auth(String),
+    // connecting to a tcp target failed
+    Dial(String),
+    // a listening socket couldn't be bound
+    Bind(String),
+    // the peer sent something unexpected
+    Protocol(String),
+    // the connection to the peer was lost
+    Connection(String),
+    // the connection was closed because a tunnel shut down
+    Closed(String),
+    // the tcp target of a stream refused the connection
+    Refused(String),
+    // the tcp target of a stream didn't answer in time
+    TimedOut(String),
+    // the tcp connection behind a stream was reset
+    Reset(String),
+    Io(std::io::Error),
+}
+
+impl Error {
+    pub fn code(&self) -> u32 {
+        match self {
+            Error::Config(_) => code::CONFIG,
+            Error::Tls(_) => code::TLS,
+            Error::Handshake(_) => code::HANDSHAKE,
+            Error::Auth(_) => code::AUTH,
+            Error::Dial(_) => code::DIAL,
+            Error::Bind(_) => code::BIND,
+            Error::Protocol(_) => code::PROTOCOL,
+            Error::Connection(_) => code::CONNECTION,
+            Error::Closed(_) => code::CLOSED,
+            Error::Refused(_) => code::REFUSED,
+            Error::TimedOut(_) => code::TIMED_OUT,
+            Error::Reset(_) => code::RESET,
+            Error::Io(_) => code::INTERNAL,
+        }
+    }
+
+    // Short name of the kind of error, such as `timed_out`, used as a metrics label
+    pub fn label(&self) -> &'static str {
+        match self {
+            Error::Config(_) => "config",
+            Error::Tls(_) => "tls",
+            Error::Handshake(_) => "handshake",
+            Error::Auth(_) => "auth",
+            Error::Dial(_) => "dial",
+            Error::Bind(_) => "bind",
+            Error::Protocol(_) => "protocol",
+            Error::Connection(_) => "connection",
+            Error::Closed(_) => "closed",
+            Error::Refused(_) => "refused",
+            Error::TimedOut(_) => "timed_out",
+            Error::Reset(_) => "reset",
+            Error::Io(_) => "io",
+        }
+    }
+
+    // The error a peer reported by closing with `code`
+    pub fn from_code(code: u64, message: String) -> Self {
+        match u32::try_from(code).unwrap_or(u32::MAX) {
+            code::CLOSED => Error::Closed(message),
+            code::INTERNAL => Error::Io(std::io::Error::other(message)),
+            code::CONFIG => Error::Config(message),
+            code::TLS => Error::Tls(message),
+            code::HANDSHAKE => Error::Handshake(message),
+            code::AUTH => Error::Auth(message),
+            code::DIAL => Error::Dial(message),
+            code::BIND => Error::Bind(message),
+            code::PROTOCOL => Error::Protocol(message),
+            code::CONNECTION => Error::Connection(message),
+            code::REFUSED => Error::Refused(message),
+            code::TIMED_OUT => Error::TimedOut(message),
+            code::RESET => Error::Reset(message),
+            _ => Error::Protocol(format!("{message}, with unknown error code {code}")),
+        }
+    }
+
+    // Exit code of a process that stopped because of this error
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Io(_) => 1,
+            Error::Config(_) => 2,
+            Error::Tls(_) => 3,
+            Error::Bind(_) => 4,
+            Error::Handshake(_) => 5,
+            Error::Auth(_) => 6,
+            Error::Dial(_) | Error::Refused(_) | Error::TimedOut(_) => 7,
+            Error::Protocol(_) => 8,
+            Error::Connection(_) | Error::Closed(_) | Error::Reset(_) => 9,
+        }
+    }
+
+    // Prefixes the message with where the error happened, keeping its kind
+    pub fn context(self, context: &str) -> Self {
+        match self {
+            Error::Config(message) => Error::Config(format!("{context}: {message}")),
+            Error::Tls(message) => Error::Tls(format!("{context}: {message}")),
+            Error::Handshake(message) => Error::Handshake(format!("{context}: {message}")),
+            Error::Auth(message) => Error::Auth(format!("{context}: {message}")),
+            Error::Dial(message) => Error::Dial(format!("{context}: {message}")),
+            Error::Bind(message) => Error::Bind(format!("{context}: {message}")),
+            Error::Protocol(message) => Error::Protocol(format!("{context}: {message}")),
+            Error::Connection(message) => Error::Connection(format!("{context}: {message}")),
+            Error::Closed(message) => Error::Closed(format!("{context}: {message}")),
+            Error::Refused(message) => Error::Refused(format!("{context}: {message}")),
+            Error::TimedOut(message) => Error::TimedOut(format!("{context}: {message}")),
+            Error::Reset(message) => Error::Reset(format!("{context}: {message}")),
+            Error::Io(e) => Error::Io(std::io::Error::new(e.kind(), format!("{context}: {e}"))),
+        }
+    }
+
+    // A failed tcp connection by how it failed, errors of no particular kind are kept as io
+    pub fn tcp(e: std::io::Error, context: &str) -> Self {
+        use std::io::ErrorKind;
+
+        match e.kind() {
+            ErrorKind::ConnectionRefused => Error::Refused(format!("{context}: {e}")),
+            ErrorKind::TimedOut => Error::TimedOut(format!("{context}: {e}")),
+            ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe => {
+                Error::Reset(format!("{context}: {e}"))
+            }
+            _ => Error::Io(e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Config(message)
+            | Error::Tls(message)
+            | Error::Handshake(message)
+            | Error::Auth(message)
+            | Error::Dial(message)
+            | Error::Bind(message)
+            | Error::Protocol(message)
+            | Error::Connection(message)
+            | Error::Closed(message) => write!(f, "{} error: {message}", self.label()),
+            // these read as the error on their own
+            Error::Refused(message) => write!(f, "backend refused: {message}"),
+            Error::TimedOut(message) => write!(f, "backend timed out: {message}"),
+            Error::Reset(message) => write!(f, "connection reset: {message}"),
+            Error::Io(e) => write!(f, "{} error: {e}", self.label()),
+        }
+    }
+}
+
+// Quic errors surfacing through the io traits, such as a reset stream in a copy, are decoded too
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        let inner = e.get_ref();
+
+        if let Some(error) = inner.and_then(|inner| inner.downcast_ref::<stream::Error>()) {
+            return Error::from(*error);
+        }
+        if let Some(error) = inner.and_then(|inner| inner.downcast_ref::<connection::Error>()) {
+            return Error::from(*error);
+        }
+
+        Error::Io(e)
+    }
+}
+
+// Closes with an application code are decoded into the error the peer reported
+impl From<connection::Error> for Error {
+    fn from(e: connection::Error) -> Self {
+        match e {
+            connection::Error::Application { error, .. } => {
+                Error::from_code(u64::from(error), format!("connection closed: {e}"))
+            }
+            _ => Error::Connection(e.to_string()),
+        }
+    }
+}
+
+impl From<stream::Error> for Error {
+    fn from(e: stream::Error) -> Self {
+        match e {
+            stream::Error::ConnectionError { error, .. } => Error::from(error),
+            stream::Error::StreamReset { error, .. } => {
+                Error::from_code(u64::from(error), "stream reset by peer".to_string())
+            }
+            _ => Error::Connection(e.to_string()),
+        }
+    }
+}
+
+impl From<&Error> for application::Error {
+    fn from(e: &Error) -> Self {
+        e.code().into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{code, Error};
+
+    #[test]
+    fn test_error_codes() {
+        let errors = [
+            Error::Config(String::new()),
+            Error::Tls(String::new()),
+            Error::Handshake(String::new()),
+            Error::Auth(String::new()),
+            Error::Dial(String::new()),
+            Error::Bind(String::new()),
+            Error::Protocol(String::new()),
+            Error::Connection(String::new()),
+            Error::Closed(String::new()),
+            Error::Refused(String::new()),
+            Error::TimedOut(String::new()),
+            Error::Reset(String::new()),
+            Error::Io(std::io::Error::other("")),
+        ];
+
+        for error in errors {
+            let decoded = Error::from_code(error.code().into(), "decoded".to_string());
+            assert_eq!(decoded.code(), error.code(), "{error:?}");
+            assert_eq!(decoded.exit_code(), error.exit_code(), "{error:?}");
+        }
+
+        assert_eq!(code::CLOSED, 1);
+        assert!(matches!(
+            Error::from_code(0xbeef, "closed".to_string()),
+            Error::Protocol(_)
+        ));
+        assert_eq!(
+            Error::Bind("address in use".to_string())
+                .context("tunnel web")
+                .to_string(),
+            "bind error: tunnel web: address in use"
+        );
+    }
+
+    #[test]
+    fn test_tcp_errors() {
+        use std::io::{Error as IoError, ErrorKind};
+
+        let refused = Error::tcp(IoError::from(ErrorKind::ConnectionRefused), "dialing");
+        assert_eq!(refused.code(), code::REFUSED);
+        assert!(
+            refused.to_string().starts_with("backend refused: dialing"),
+            "{refused}"
+        );
+
+        let timed_out = Error::tcp(IoError::from(ErrorKind::TimedOut), "dialing");
+        assert_eq!(timed_out.code(), code::TIMED_OUT);
 
-impl std::error::Error for GenericError {}
+        let reset = Error::tcp(IoError::from(ErrorKind::ConnectionReset), "copying");
+        assert_eq!(reset.code(), code::RESET);
 
-impl std::fmt::Display for GenericError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "error: {}", self.0)
+        let other = Error::tcp(IoError::from(ErrorKind::PermissionDenied), "dialing");
+        assert!(matches!(other, Error::Io(_)));
     }
 }
@@ -0,0 +1,272 @@
+// Shell commands run on tunnel events, for operators who script around the command line rather
+// than embed the library. Each command runs through `sh -c` with the event described in
+// SIRANG_* variables:
+//
+//   SIRANG_EVENT                  up, down, stream-open or stream-close
+//   SIRANG_PEER                   the other end of the tunnel, which identifies the client on
+//                                 a remote
+//   SIRANG_LISTEN_ADDR, _PORT     where the tunnel accepts connections, for a local reverse
+//                                 tunnel the tcp address its remote assigned
+//   SIRANG_TCP_ADDR               stream events, the client or target of the stream
+//   SIRANG_STREAM_ID              stream events, the id both ends of the tunnel know it by
+//   SIRANG_CLIENT_ADDR            stream events, the tcp client of the stream and the address
+//   SIRANG_CLIENT_LISTENER        it connected to, when the end that accepted it sent them
+//   SIRANG_ACCEPTED_MS            stream events, when the client was accepted, in unix millis
+//   SIRANG_BYTES_SENT, _RECEIVED  down and stream-close, for the connection or the stream
+//   SIRANG_DURATION_MS            down and stream-close
+//   SIRANG_ERROR, _ERROR_CODE     stream-close, only when the stream failed
+//
+// Commands run one at a time in the order of the events, so a down command never overtakes the
+// up command before it, and they don't hold up the tunnel itself
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    process::Command,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    layer::StreamInfo,
+    metadata::{self, Metadata},
+    monitor::{Hooks, Monitor, StreamEnd},
+};
+
+// a command along with the variables to run it with
+type Job = (String, Vec<(&'static str, String)>);
+
+// How long the commands still queued when the hooks are dropped are waited for
+const QUEUE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Default, Clone, PartialEq, Debug)]
+pub struct HookCommands {
+    pub on_up: Option<String>,
+    pub on_down: Option<String>,
+    pub on_stream_open: Option<String>,
+    pub on_stream_close: Option<String>,
+}
+
+impl HookCommands {
+    fn is_empty(&self) -> bool {
+        self == &HookCommands::default()
+    }
+
+    // Runs the commands on the events of the monitor's tunnel, if any are set
+    pub fn register(self, monitor: &Monitor) {
+        if !self.is_empty() {
+            monitor.add_hooks(Arc::new(ExecHooks::new(self)));
+        }
+    }
+}
+
+struct ExecHooks {
+    commands: HookCommands,
+    runner: Option<mpsc::Sender<Job>>,
+    // disconnects once the worker has run every queued command
+    worker_done: Mutex<Option<mpsc::Receiver<()>>>,
+    listen_addr: Mutex<Option<SocketAddr>>,
+
+    // bytes carried and when it started, for each connection
+    connections: Mutex<HashMap<SocketAddr, (u64, u64, Instant)>>,
+}
+
+impl ExecHooks {
+    fn new(commands: HookCommands) -> Self {
+        let (runner, queue) = mpsc::channel::<Job>();
+        let (done, worker_done) = mpsc::channel();
+
+        thread::spawn(move || {
+            let _done = done;
+            for (command, env) in queue {
+                match Command::new("sh")
+                    .arg("-c")
+                    .arg(&command)
+                    .envs(env)
+                    .status()
+                {
+                    Ok(status) if status.success() => {}
+                    Ok(status) => tracing::warn!("Hook command `{command}` failed: {status}"),
+                    Err(e) => tracing::warn!("Unable to run hook command `{command}`: {e}"),
+                }
+            }
+        });
+
+        Self {
+            commands,
+            runner: Some(runner),
+            worker_done: Mutex::new(Some(worker_done)),
+            listen_addr: Mutex::new(None),
+            connections: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn run(
+        &self,
+        command: &Option<String>,
+        event: &str,
+        peer: SocketAddr,
+        mut env: Vec<(&'static str, String)>,
+    ) {
+        let Some(command) = command else {
+            return;
+        };
+
+        env.push(("SIRANG_EVENT", event.to_string()));
+        env.push(("SIRANG_PEER", peer.to_string()));
+        if let Some(listen_addr) = *self.listen_addr.lock().unwrap() {
+            env.push(("SIRANG_LISTEN_ADDR", listen_addr.to_string()));
+            env.push(("SIRANG_LISTEN_PORT", listen_addr.port().to_string()));
+        }
+
+        if let Some(runner) = &self.runner {
+            let _ = runner.send((command.clone(), env));
+        }
+    }
+}
+
+// The hooks are dropped along with the tunnel, the commands still queued then are given up to
+// QUEUE_TIMEOUT to run before the process can exit, such as the down command of its last
+// connection. Within a runtime the wait happens on a blocking thread, which the runtime waits
+// for when it shuts down, so a slow command never holds up the thread dropping the tunnel
+impl Drop for ExecHooks {
+    fn drop(&mut self) {
+        drop(self.runner.take());
+        let Some(worker_done) = self.worker_done.get_mut().unwrap().take() else {
+            return;
+        };
+
+        let wait = move || {
+            let _ = worker_done.recv_timeout(QUEUE_TIMEOUT);
+        };
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => drop(runtime.spawn_blocking(wait)),
+            Err(_) => wait(),
+        }
+    }
+}
+
+impl Hooks for ExecHooks {
+    fn on_listening(&self, addr: SocketAddr) {
+        *self.listen_addr.lock().unwrap() = Some(addr);
+    }
+
+    fn on_connected(&self, peer: SocketAddr) {
+        self.connections
+            .lock()
+            .unwrap()
+            .insert(peer, (0, 0, Instant::now()));
+        self.run(&self.commands.on_up, "up", peer, Vec::new());
+    }
+
+    fn on_disconnected(&self, peer: SocketAddr) {
+        let mut env = Vec::new();
+        if let Some((sent, received, connected)) = self.connections.lock().unwrap().remove(&peer) {
+            env.push(("SIRANG_BYTES_SENT", sent.to_string()));
+            env.push(("SIRANG_BYTES_RECEIVED", received.to_string()));
+            env.push((
+                "SIRANG_DURATION_MS",
+                connected.elapsed().as_millis().to_string(),
+            ));
+        }
+        self.run(&self.commands.on_down, "down", peer, env);
+    }
+
+    fn on_stream_open(&self, stream: &StreamInfo) {
+        let env = stream_env(stream.tcp_addr, &stream.metadata);
+        self.run(
+            &self.commands.on_stream_open,
+            "stream-open",
+            stream.peer,
+            env,
+        );
+    }
+
+    fn on_stream_close(&self, stream: &StreamEnd<'_>) {
+        if let Some((sent, received, _)) = self.connections.lock().unwrap().get_mut(&stream.peer) {
+            *sent += stream.bytes_sent;
+            *received += stream.bytes_received;
+        }
+
+        let mut env = stream_env(stream.tcp_addr, &stream.metadata);
+        env.extend([
+            ("SIRANG_BYTES_SENT", stream.bytes_sent.to_string()),
+            ("SIRANG_BYTES_RECEIVED", stream.bytes_received.to_string()),
+            (
+                "SIRANG_DURATION_MS",
+                stream.duration.as_millis().to_string(),
+            ),
+        ]);
+        if let Some(error) = stream.error {
+            env.push(("SIRANG_ERROR", error.to_string()));
+            env.push(("SIRANG_ERROR_CODE", error.code().to_string()));
+        }
+        self.run(
+            &self.commands.on_stream_close,
+            "stream-close",
+            stream.peer,
+            env,
+        );
+    }
+}
+
+// The variables every stream event has
+fn stream_env(tcp_addr: SocketAddr, metadata: &Metadata) -> Vec<(&'static str, String)> {
+    let mut env = vec![
+        ("SIRANG_TCP_ADDR", tcp_addr.to_string()),
+        ("SIRANG_STREAM_ID", metadata.id.to_string()),
+        (
+            "SIRANG_ACCEPTED_MS",
+            metadata::unix_millis(metadata.accepted).to_string(),
+        ),
+    ];
+    if let Some(client) = metadata.client {
+        env.push(("SIRANG_CLIENT_ADDR", client.to_string()));
+    }
+    if let Some(listener) = metadata.listener {
+        env.push(("SIRANG_CLIENT_LISTENER", listener.to_string()));
+    }
+
+    env
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::HookCommands;
+    use crate::monitor::Monitor;
+
+    #[test]
+    fn test_commands_run_in_order() {
+        let path = std::env::temp_dir().join(format!("sirang-hooks-{}", std::process::id()));
+        let record = |name: &str| {
+            Some(format!(
+                "echo {name} $SIRANG_PEER $SIRANG_LISTEN_PORT $SIRANG_BYTES_SENT >> {}",
+                path.display()
+            ))
+        };
+
+        let monitor = Monitor::default();
+        HookCommands {
+            on_up: record("up"),
+            on_down: record("down"),
+            ..HookCommands::default()
+        }
+        .register(&monitor);
+
+        let peer: SocketAddr = "127.0.0.1:4433".parse().unwrap();
+        monitor.listening("127.0.0.1:5000".parse().unwrap());
+        drop(monitor.connected(peer));
+
+        // dropping the tunnel's monitor waits for the queued commands
+        drop(monitor);
+        let output = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            output,
+            "up 127.0.0.1:4433 5000\ndown 127.0.0.1:4433 5000 0\n"
+        );
+    }
+}
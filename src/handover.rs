@@ -0,0 +1,546 @@
+// Zero-downtime upgrades of a remote instance. The running process listens on a unix socket,
+// a newer process started with the same path connects to it and is passed the reverse tcp
+// listener over SCM_RIGHTS, then binds its own quic socket on the same address with
+// SO_REUSEPORT. Every process puts its own tag at the start of the connection ids it hands out,
+// and before replying the old process attaches a reuseport program that steers packets for its
+// tag to its socket and everything else, new handshakes included, to the new one. The old
+// process then drops every new connection attempt and drains the connections it has without
+// losing their packets to the new process
+use std::{
+    net::{SocketAddr, TcpListener, UdpSocket},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use s2n_quic::provider::endpoint_limits::{self, ConnectionAttempt, Limiter, Outcome};
+
+use crate::errors::Error;
+
+// Holds the reverse tcp listener while a client is connected so it can be handed over.
+// A listener inherited from the previous process is reused instead of binding a new one
+#[derive(Clone, Default)]
+pub struct TcpListenerSlot(Arc<Mutex<Option<TcpListener>>>);
+
+impl TcpListenerSlot {
+    fn with_listener(listener: Option<TcpListener>) -> Self {
+        Self(Arc::new(Mutex::new(listener)))
+    }
+
+    pub fn bind(&self, address: SocketAddr) -> std::io::Result<tokio::net::TcpListener> {
+        let mut slot = self.0.lock().unwrap();
+
+        let listener = match slot.take() {
+            Some(listener) if listener.local_addr()? == address => listener,
+            inherited => {
+                if let Some(inherited) = inherited {
+                    tracing::warn!(
+                        "Ignoring inherited tcp listener on {}, expected {address}",
+                        inherited.local_addr()?
+                    );
+                }
+                TcpListener::bind(address)?
+            }
+        };
+
+        listener.set_nonblocking(true)?;
+        *slot = Some(listener.try_clone()?);
+
+        tokio::net::TcpListener::from_std(listener)
+    }
+
+    // Closes the held listener, the port stops accepting once the tokio listener is dropped too
+    pub fn release(&self) {
+        self.0.lock().unwrap().take();
+    }
+
+    fn try_clone(&self) -> std::io::Result<Option<TcpListener>> {
+        match self.0.lock().unwrap().as_ref() {
+            Some(listener) => Ok(Some(listener.try_clone()?)),
+            None => Ok(None),
+        }
+    }
+}
+
+// Drops connection attempts once the sockets have been handed over, so new clients end up
+// on the new process. Otherwise defers to the default s2n-quic limits
+struct HandoverLimiter {
+    accepting: Arc<AtomicBool>,
+    limits: endpoint_limits::Default,
+}
+
+impl Limiter for HandoverLimiter {
+    fn on_connection_attempt(&mut self, info: &ConnectionAttempt) -> Outcome {
+        if !self.accepting.load(Ordering::Relaxed) {
+            return Outcome::drop();
+        }
+
+        self.limits.on_connection_attempt(info)
+    }
+}
+
+// Resolves once a newer process has taken over the sockets, never when there is no handover path
+pub async fn wait_for_takeover(handover: Option<&Handover>) {
+    match handover {
+        Some(handover) => handover.wait_for_takeover().await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub struct Handover {
+    listener: tokio::net::UnixListener,
+    udp_socket: UdpSocket,
+    tag: u16,
+    tcp_listeners: TcpListenerSlot,
+    accepting: Arc<AtomicBool>,
+    // the process this one took over from, which closes the stream when it exits
+    predecessor: Mutex<Option<tokio::net::UnixStream>>,
+    // the process this one handed over to, kept open until this one exits
+    successor: Mutex<Option<tokio::net::UnixStream>>,
+}
+
+#[cfg(target_os = "linux")]
+impl Handover {
+    // Takes over from the process listening on `path`, if there is one, binds the quic socket
+    // next to its socket and then listens on `path` for the next upgrade
+    pub fn start(path: &Path, quic_address: SocketAddr) -> Result<Self, Error> {
+        use std::io::{ErrorKind, Write};
+
+        let bind_error = |e: std::io::Error| Error::Bind(format!("{}: {e}", path.display()));
+
+        let (tag, tcp_listener, predecessor) = match std::os::unix::net::UnixStream::connect(path) {
+            Ok(stream) => {
+                let (tag, tcp_listener) = linux::receive_sockets(&stream).map_err(|e| {
+                    Error::Bind(format!(
+                        "unable to take over from the process at {}: {e}",
+                        path.display()
+                    ))
+                })?;
+                (tag.wrapping_add(1), tcp_listener, Some(stream))
+            }
+            Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::ConnectionRefused) => {
+                (linux::random_tag(), None, None)
+            }
+            Err(e) => return Err(bind_error(e)),
+        };
+
+        let udp_socket = linux::bind_reuseport(quic_address).map_err(|e| {
+            Error::Bind(format!("unable to bind quic socket on {quic_address}: {e}"))
+        })?;
+
+        // the previous process stops accepting once this one tells it the socket is bound
+        let predecessor = match predecessor {
+            Some(mut stream) => {
+                stream.write_all(b"\n").map_err(bind_error)?;
+                tracing::info!("Took over from the process at {}", path.display());
+
+                stream.set_nonblocking(true)?;
+                Some(tokio::net::UnixStream::from_std(stream)?)
+            }
+            None => None,
+        };
+
+        // the path is either stale or belonged to the process that just handed over
+        if let Err(e) = std::fs::remove_file(path) {
+            if e.kind() != ErrorKind::NotFound {
+                return Err(bind_error(e));
+            }
+        }
+        let listener = tokio::net::UnixListener::bind(path).map_err(bind_error)?;
+        tracing::info!("Listening for socket handover on {}", path.display());
+
+        Ok(Self {
+            listener,
+            udp_socket,
+            tag,
+            tcp_listeners: TcpListenerSlot::with_listener(tcp_listener),
+            accepting: Arc::new(AtomicBool::new(true)),
+            predecessor: Mutex::new(predecessor),
+            successor: Mutex::new(None),
+        })
+    }
+
+    pub fn quic_socket(&self) -> std::io::Result<UdpSocket> {
+        let socket = self.udp_socket.try_clone()?;
+        socket.set_nonblocking(true)?;
+        Ok(socket)
+    }
+
+    pub fn connection_ids(&self) -> impl s2n_quic::provider::connection_id::Provider {
+        linux::ConnectionIds::new(self.tag)
+    }
+
+    pub fn limiter(&self) -> impl Limiter {
+        HandoverLimiter {
+            accepting: self.accepting.clone(),
+            limits: endpoint_limits::Default::default(),
+        }
+    }
+
+    pub fn tcp_listeners(&self) -> TcpListenerSlot {
+        self.tcp_listeners.clone()
+    }
+
+    async fn wait_for_takeover(&self) {
+        use tokio::io::AsyncReadExt;
+
+        // steering only knows two processes, so the next one has to wait until the previous
+        // one has drained its connections and exited
+        let predecessor = self.predecessor.lock().unwrap().take();
+        if let Some(mut predecessor) = predecessor {
+            let _ = predecessor.read(&mut [0]).await;
+            tracing::debug!("Previous process exited, ready to hand over");
+        }
+
+        loop {
+            let stream = match self.listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    tracing::warn!("Error accepting socket handover request: {e}");
+                    continue;
+                }
+            };
+
+            match self.hand_over(stream).await {
+                Ok(stream) => {
+                    self.accepting.store(false, Ordering::Relaxed);
+                    *self.successor.lock().unwrap() = Some(stream);
+                    return;
+                }
+                Err(e) => tracing::warn!("Unable to hand over listening sockets: {e}"),
+            }
+        }
+    }
+
+    async fn hand_over(
+        &self,
+        mut stream: tokio::net::UnixStream,
+    ) -> Result<tokio::net::UnixStream, Error> {
+        use tokio::io::AsyncReadExt;
+
+        linux::steer_to_successor(&self.udp_socket, self.tag)?;
+        linux::send_sockets(&stream, self.tag, self.tcp_listeners.try_clone()?.as_ref())?;
+
+        // until the new process has bound its socket, packets steered to it still end up here
+        tokio::time::timeout(linux::RECEIVE_TIMEOUT, stream.read_u8())
+            .await
+            .map_err(|_| {
+                Error::TimedOut("the new process did not bind its socket".to_string())
+            })??;
+
+        Ok(stream)
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::{
+        collections::hash_map::RandomState,
+        hash::{BuildHasher, Hasher},
+        io::{IoSlice, IoSliceMut},
+        net::{SocketAddr, TcpListener, UdpSocket},
+        os::{
+            fd::{AsRawFd, FromRawFd, RawFd},
+            unix::net::UnixStream,
+        },
+        time::Duration,
+    };
+
+    use nix::{
+        libc,
+        sys::socket::{
+            recvmsg, sendmsg, setsockopt, sockopt, ControlMessage, ControlMessageOwned, MsgFlags,
+            UnixAddr,
+        },
+    };
+    use s2n_quic::provider::connection_id::{ConnectionInfo, Generator, LocalId, Validator};
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    use crate::errors::Error;
+
+    pub(super) const RECEIVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+    // Length of the connection ids handed out by a process that can be handed over, the same as
+    // s2n-quic's default
+    const CONNECTION_ID_LEN: usize = 16;
+
+    // Connection ids made of the process tag followed by random bytes, so the reuseport program
+    // can tell which process a packet belongs to
+    pub(super) struct ConnectionIds {
+        tag: u16,
+        random: RandomState,
+        generated: u64,
+    }
+
+    impl ConnectionIds {
+        pub(super) fn new(tag: u16) -> Self {
+            Self {
+                tag,
+                random: RandomState::new(),
+                generated: 0,
+            }
+        }
+
+        fn random_u64(&mut self) -> u64 {
+            let mut hasher = self.random.build_hasher();
+            hasher.write_u64(self.generated);
+            self.generated += 1;
+            hasher.finish()
+        }
+    }
+
+    impl Generator for ConnectionIds {
+        fn generate(&mut self, _connection_info: &ConnectionInfo) -> LocalId {
+            let mut id = [0; CONNECTION_ID_LEN];
+            id[..8].copy_from_slice(&self.random_u64().to_be_bytes());
+            id[8..].copy_from_slice(&self.random_u64().to_be_bytes());
+            id[..2].copy_from_slice(&self.tag.to_be_bytes());
+            LocalId::try_from_bytes(&id).unwrap()
+        }
+    }
+
+    impl Validator for ConnectionIds {
+        fn validate(&self, connection_info: &ConnectionInfo, buffer: &[u8]) -> Option<usize> {
+            CONNECTION_ID_LEN.validate(connection_info, buffer)
+        }
+    }
+
+    pub(super) fn random_tag() -> u16 {
+        RandomState::new().build_hasher().finish() as u16
+    }
+
+    pub(super) fn bind_reuseport(address: SocketAddr) -> std::io::Result<UdpSocket> {
+        let socket = Socket::new(
+            Domain::for_address(address),
+            Type::DGRAM,
+            Some(Protocol::UDP),
+        )?;
+        setsockopt(&socket, sockopt::ReusePort, &true)?;
+        socket.bind(&address.into())?;
+        Ok(socket.into())
+    }
+
+    // Sends the tag of this process, followed by the reverse tcp listener if there is one
+    pub(super) fn send_sockets(
+        stream: &impl AsRawFd,
+        tag: u16,
+        tcp_listener: Option<&TcpListener>,
+    ) -> Result<(), Error> {
+        let payload = format!("{tag:04x}");
+        let fds: Vec<RawFd> = tcp_listener.iter().map(|l| l.as_raw_fd()).collect();
+        let cmsgs: &[ControlMessage] = match fds.is_empty() {
+            true => &[],
+            false => &[ControlMessage::ScmRights(&fds)],
+        };
+
+        sendmsg::<UnixAddr>(
+            stream.as_raw_fd(),
+            &[IoSlice::new(payload.as_bytes())],
+            cmsgs,
+            MsgFlags::empty(),
+            None,
+        )
+        .map_err(std::io::Error::from)?;
+
+        Ok(())
+    }
+
+    pub(super) fn receive_sockets(
+        stream: &UnixStream,
+    ) -> Result<(u16, Option<TcpListener>), Error> {
+        stream.set_read_timeout(Some(RECEIVE_TIMEOUT))?;
+
+        let mut payload = [0; 16];
+        let mut iov = [IoSliceMut::new(&mut payload)];
+        let mut cmsg_buffer = nix::cmsg_space!([RawFd; 1]);
+
+        let msg = recvmsg::<UnixAddr>(
+            stream.as_raw_fd(),
+            &mut iov,
+            Some(&mut cmsg_buffer),
+            MsgFlags::MSG_CMSG_CLOEXEC,
+        )
+        .map_err(std::io::Error::from)?;
+
+        let mut fds = Vec::new();
+        for cmsg in msg.cmsgs().map_err(std::io::Error::from)? {
+            if let ControlMessageOwned::ScmRights(received) = cmsg {
+                fds.extend(received);
+            }
+        }
+        let len = msg.bytes;
+
+        // taking ownership right away closes every received descriptor on an error below
+        let mut fds = fds
+            .into_iter()
+            .map(|fd| unsafe { std::os::fd::OwnedFd::from_raw_fd(fd) });
+
+        let tag = std::str::from_utf8(&payload[..len])
+            .ok()
+            .and_then(|tag| u16::from_str_radix(tag, 16).ok())
+            .ok_or_else(|| Error::Protocol("invalid socket handover message".to_string()))?;
+
+        Ok((tag, fds.next().map(TcpListener::from)))
+    }
+
+    // Makes the reuseport group pick this socket for packets whose connection id starts with
+    // `tag` and the socket bound after it for everything else. Without a second socket the
+    // index is out of range and the kernel falls back to this one
+    pub(super) fn steer_to_successor(udp_socket: &UdpSocket, tag: u16) -> std::io::Result<()> {
+        const SO_ATTACH_REUSEPORT_CBPF: libc::c_int = 51;
+
+        let op = |code: u32, jt: u8, jf: u8, k: u32| libc::sock_filter {
+            code: code as u16,
+            jt,
+            jf,
+            k,
+        };
+
+        // the offsets are into the udp payload, loads past its end select this socket
+        let mut program = [
+            // short headers have the top bit clear and the id right after the first byte
+            op(libc::BPF_LD | libc::BPF_B | libc::BPF_ABS, 0, 0, 0),
+            op(libc::BPF_JMP | libc::BPF_JSET | libc::BPF_K, 2, 0, 0x80),
+            op(libc::BPF_LD | libc::BPF_H | libc::BPF_ABS, 0, 0, 1),
+            op(libc::BPF_JMP | libc::BPF_JA, 0, 0, 3),
+            // long headers carry the id length after the version, ids of any other length
+            // were picked by a client that hasn't heard from a server yet
+            op(libc::BPF_LD | libc::BPF_B | libc::BPF_ABS, 0, 0, 5),
+            op(
+                libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+                0,
+                3,
+                CONNECTION_ID_LEN as u32,
+            ),
+            op(libc::BPF_LD | libc::BPF_H | libc::BPF_ABS, 0, 0, 6),
+            op(
+                libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+                0,
+                1,
+                tag.into(),
+            ),
+            op(libc::BPF_RET | libc::BPF_K, 0, 0, 0),
+            op(libc::BPF_RET | libc::BPF_K, 0, 0, 1),
+        ];
+        let program = libc::sock_fprog {
+            len: program.len() as u16,
+            filter: program.as_mut_ptr(),
+        };
+
+        let result = unsafe {
+            libc::setsockopt(
+                udp_socket.as_raw_fd(),
+                libc::SOL_SOCKET,
+                SO_ATTACH_REUSEPORT_CBPF,
+                &program as *const libc::sock_fprog as *const libc::c_void,
+                std::mem::size_of::<libc::sock_fprog>() as libc::socklen_t,
+            )
+        };
+        if result != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::{net::TcpListener, os::unix::net::UnixStream, time::Duration};
+
+        use super::{bind_reuseport, receive_sockets, send_sockets, steer_to_successor};
+
+        #[test]
+        fn test_pass_sockets() {
+            let (sender, receiver) = UnixStream::pair().unwrap();
+            let tcp_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+            send_sockets(&sender, 0xbeef, Some(&tcp_listener)).unwrap();
+            let (tag, received_tcp) = receive_sockets(&receiver).unwrap();
+
+            assert_eq!(tag, 0xbeef);
+            assert_eq!(
+                received_tcp.unwrap().local_addr().unwrap(),
+                tcp_listener.local_addr().unwrap()
+            );
+
+            send_sockets(&sender, 7, None).unwrap();
+            let (tag, received_tcp) = receive_sockets(&receiver).unwrap();
+
+            assert_eq!(tag, 7);
+            assert!(received_tcp.is_none());
+        }
+
+        #[test]
+        fn test_steer_by_connection_id() {
+            let old = bind_reuseport("127.0.0.1:0".parse().unwrap()).unwrap();
+            let address = old.local_addr().unwrap();
+            steer_to_successor(&old, 0xbeef).unwrap();
+            let new = bind_reuseport(address).unwrap();
+
+            for socket in [&old, &new] {
+                socket
+                    .set_read_timeout(Some(Duration::from_secs(1)))
+                    .unwrap();
+            }
+
+            let mut long_header = vec![0xc0, 0, 0, 0, 1, 16];
+            long_header.extend([0xbe, 0xef]);
+            long_header.extend([0; 14]);
+
+            let client = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+            let mut buf = [0; 64];
+            for (packet, socket) in [
+                (&[0x40, 0xbe, 0xef, 1, 2][..], &old),
+                (&[0x40, 0xbe, 0xf0, 1, 2][..], &new),
+                (&long_header[..], &old),
+                (&[0xc0, 0, 0, 0, 1, 8, 0xbe, 0xef][..], &new),
+            ] {
+                client.send_to(packet, address).unwrap();
+                let len = socket.recv(&mut buf).unwrap();
+                assert_eq!(&buf[..len], packet);
+            }
+        }
+    }
+}
+
+// Steering packets between processes needs SO_ATTACH_REUSEPORT_CBPF, elsewhere asking for a
+// handover fails at startup
+#[cfg(not(target_os = "linux"))]
+pub struct Handover {
+    never: std::convert::Infallible,
+}
+
+#[cfg(not(target_os = "linux"))]
+impl Handover {
+    pub fn start(_path: &Path, _quic_address: SocketAddr) -> Result<Self, Error> {
+        Err(Error::Config(
+            "socket handover is only supported on linux".to_string(),
+        ))
+    }
+
+    pub fn quic_socket(&self) -> std::io::Result<UdpSocket> {
+        match self.never {}
+    }
+
+    pub fn connection_ids(&self) -> s2n_quic::provider::connection_id::Default {
+        match self.never {}
+    }
+
+    pub fn limiter(&self) -> impl Limiter {
+        HandoverLimiter {
+            accepting: Arc::new(AtomicBool::new(true)),
+            limits: endpoint_limits::Default::default(),
+        }
+    }
+
+    pub fn tcp_listeners(&self) -> TcpListenerSlot {
+        match self.never {}
+    }
+
+    async fn wait_for_takeover(&self) {
+        match self.never {}
+    }
+}
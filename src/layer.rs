@@ -0,0 +1,82 @@
+// Middleware around the two halves of every tunneled stream, for features that need to see or
+// change the bytes on their way, such as compression, mirroring or recording. A layer wraps
+// the tunnel half, where writes go into the quic stream and reads come out of it, and the local
+// half, the tcp connection with the client or target. Layers are stacked per tunnel, the first
+// one added sits closest to the stream or connection it wraps:
+//
+//   struct Recorder;
+//
+//   impl StreamLayer for Recorder {
+//       fn wrap_local<'a>(&self, stream: BoxedIo<'a>, info: &StreamInfo) -> BoxedIo<'a> {
+//           Box::new(Recording::new(stream, info.tcp_addr))
+//       }
+//   }
+//
+// Errors a wrapper returns fail the stream like any other, and its shutdown must pass the end
+// of stream on so each direction can still close on its own
+use std::{net::SocketAddr, sync::Arc};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::metadata::Metadata;
+
+pub trait Io: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Io for T {}
+
+pub type BoxedIo<'a> = Box<dyn Io + 'a>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[non_exhaustive]
+pub struct StreamInfo {
+    // the other end of the tunnel
+    pub peer: SocketAddr,
+    // the client or target of the stream, unspecified for the stdin/stdout of connect and for a
+    // target the stream failed before reaching
+    pub tcp_addr: SocketAddr,
+    // the id of the stream and the client it is for, as the end that accepted it saw them
+    pub metadata: Metadata,
+}
+
+impl StreamInfo {
+    pub fn new(peer: SocketAddr, tcp_addr: SocketAddr, metadata: Metadata) -> Self {
+        Self {
+            peer,
+            tcp_addr,
+            metadata,
+        }
+    }
+}
+
+pub trait StreamLayer: Send + Sync + 'static {
+    fn wrap_tunnel<'a>(&self, stream: BoxedIo<'a>, _info: &StreamInfo) -> BoxedIo<'a> {
+        stream
+    }
+
+    fn wrap_local<'a>(&self, stream: BoxedIo<'a>, _info: &StreamInfo) -> BoxedIo<'a> {
+        stream
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Layers {
+    layers: Vec<Arc<dyn StreamLayer>>,
+}
+
+impl Layers {
+    pub fn push(&mut self, layer: Arc<dyn StreamLayer>) {
+        self.layers.push(layer);
+    }
+
+    pub(crate) fn wrap_tunnel<'a>(&self, stream: BoxedIo<'a>, info: &StreamInfo) -> BoxedIo<'a> {
+        self.layers
+            .iter()
+            .fold(stream, |stream, layer| layer.wrap_tunnel(stream, info))
+    }
+
+    pub(crate) fn wrap_local<'a>(&self, stream: BoxedIo<'a>, info: &StreamInfo) -> BoxedIo<'a> {
+        self.layers
+            .iter()
+            .fold(stream, |stream, layer| layer.wrap_local(stream, info))
+    }
+}
@@ -1,6 +1,44 @@
-pub mod cmd;
-pub mod common;
-pub mod errors;
-pub mod local;
+// the config file, supervisor, admin socket and metrics server are only reached from the command
+// line, builds without it leave them unused
+#![cfg_attr(not(feature = "cli"), allow(dead_code))]
+
+pub(crate) mod access;
+pub(crate) mod admin;
+#[cfg(feature = "cli")]
+pub(crate) mod cmd;
+pub(crate) mod common;
+pub(crate) mod compress;
+pub(crate) mod config;
+pub(crate) mod errors;
+pub(crate) mod exec;
+pub(crate) mod handover;
+pub mod layer;
+pub(crate) mod local;
+pub(crate) mod logging;
+pub(crate) mod metadata;
+pub(crate) mod metrics;
+pub mod monitor;
+pub(crate) mod proxy;
 pub mod quic;
-pub mod remote;
+pub(crate) mod remote;
+pub(crate) mod shutdown;
+pub(crate) mod stream;
+pub(crate) mod supervisor;
+pub(crate) mod tls;
+pub(crate) mod transport;
+pub(crate) mod tunnel;
+
+// the sirang command line, run by the binary
+#[cfg(feature = "cli")]
+pub use cmd::execute;
+
+pub use access::AccessLogFormat;
+pub use compress::Compression;
+pub use config::TunnelConfig;
+pub use errors::{code, Error};
+pub use metadata::{Metadata, StreamId};
+pub use monitor::{Event, Hooks, Stats, StreamEnd};
+pub use proxy::{Cidr, ProxyProtocol};
+pub use tls::PemSource;
+pub use transport::QuicStats;
+pub use tunnel::{TunnelBuilder, TunnelHandle};
@@ -1,5 +1,12 @@
-use crate::common::{TunnelType, DEFAULT_BUFSIZE};
-use std::{net::SocketAddr, str::FromStr};
+use crate::{
+    common::{TunnelType, DEFAULT_BUFSIZE, DEFAULT_DRAIN_TIMEOUT},
+    compress::Compression,
+    layer::Layers,
+    monitor::Monitor,
+    proxy::{Cidr, ProxyProtocol},
+    shutdown::StopSignal,
+};
+use std::{net::SocketAddr, str::FromStr, time::Duration};
 
 #[derive(Clone)]
 pub struct LocalConfig {
@@ -8,6 +15,28 @@ pub struct LocalConfig {
     pub remote_quic_server_addr: SocketAddr,
     pub tls_cert: String,
     pub buffer_size: usize,
+    pub drain_timeout: Duration,
+
+    // only used by connect, None means the remote's forward address
+    pub target: Option<String>,
+
+    // resolves when the tunnel should shut down
+    pub stop: StopSignal,
+
+    // stats and events of the running tunnel
+    pub monitor: Monitor,
+
+    // middleware wrapping every stream of the tunnel
+    pub layers: Layers,
+
+    // compresses the streams with this when the other end supports it
+    pub compression: Option<Compression>,
+
+    // sends a PROXY protocol header with the client's address on every connection to the target
+    pub proxy_protocol: Option<ProxyProtocol>,
+
+    // connections from these addresses start with a PROXY protocol header naming the real client
+    pub trusted_proxies: Vec<Cidr>,
 }
 
 impl Default for LocalConfig {
@@ -21,6 +50,14 @@ impl Default for LocalConfig {
             // tls_cert is guaranteed to be properly set later
             tls_cert: String::new(),
             buffer_size: DEFAULT_BUFSIZE,
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            target: None,
+            stop: StopSignal::default(),
+            monitor: Monitor::default(),
+            layers: Layers::default(),
+            compression: None,
+            proxy_protocol: None,
+            trusted_proxies: Vec::new(),
         }
     }
 }
@@ -0,0 +1,201 @@
+use super::{config::LocalConfig, forward};
+use crate::{common::proto::StreamHeader, compress, errors::Error, metadata::Metadata, quic};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::Instrument;
+
+// Opens a single stream through a forward tunnel and pipes it to stdin/stdout,
+// so sirang can be used as an ssh ProxyCommand without binding a local port.
+// Returns once the remote has closed the stream: stdin EOF half-closes it and
+// the remote closing it ends stdout, whether or not stdin is still open
+pub async fn connect_stdio(config: LocalConfig) -> Result<(), Error> {
+    let stdio = tokio::io::join(tokio::io::stdin(), tokio::io::stdout());
+    connect(config, stdio).await
+}
+
+async fn connect<T: AsyncRead + AsyncWrite + Send + Unpin>(
+    config: LocalConfig,
+    local_stream: T,
+) -> Result<(), Error> {
+    let mut quic_conn = forward::setup_quic_connection(&config).await?;
+
+    // remotes of 0.1.5 and earlier have no command stream and read no stream header, so they
+    // can only be asked for their forward address
+    let legacy = quic::legacy_peer(&quic_conn);
+    let metadata = Metadata::new(None, None);
+    let (compression, _command_stream) = if legacy {
+        if let Some(target) = &config.target {
+            return Err(Error::Protocol(format!(
+                "remote instance is too old to connect to {target}, only its forward address can be used"
+            )));
+        }
+        (None, None)
+    } else {
+        let mut command_stream = quic_conn.open_bidirectional_stream().await?;
+        compress::send_offer(&mut command_stream).await?;
+        let offer = compress::receive_offer(&mut command_stream)
+            .await
+            .unwrap_or_default();
+        // the remote may want something else for the target
+        let wanted = offer.wanted(config.target.as_deref(), config.compression);
+        let compression = compress::negotiate(wanted, &offer);
+        (compression, Some(command_stream))
+    };
+    let quic_bidirectional_stream = quic_conn.open_bidirectional_stream().await?;
+
+    tracing::debug!(
+        "Stream {} opened to {}",
+        metadata.id,
+        config
+            .target
+            .as_deref()
+            .unwrap_or("the remote forward address")
+    );
+
+    // the metadata names the stream on both ends
+    let header = Some(StreamHeader {
+        target: config.target.clone(),
+        compression,
+        metadata: Some(metadata),
+    })
+    .filter(|_| !legacy);
+
+    forward::handle_single_connection(
+        local_stream,
+        quic_bidirectional_stream,
+        header,
+        metadata,
+        &config,
+    )
+    .instrument(tracing::info_span!("stream", stream_id = %metadata.id))
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::{
+        net::{SocketAddr, UdpSocket},
+        time::Duration,
+    };
+
+    use crate::{
+        common::TunnelType,
+        local::config::LocalConfig,
+        remote::{self, config::RemoteConfig},
+        Error,
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt, DuplexStream},
+        net::TcpListener,
+        task::JoinHandle,
+    };
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    async fn spawn_echo_server() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = stream.split();
+                    let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                });
+            }
+        });
+
+        addr
+    }
+
+    // Greets every connection and closes it without waiting for the client
+    async fn spawn_greeting_server() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let _ = stream.write_all(b"hello").await;
+            }
+        });
+
+        addr
+    }
+
+    fn spawn_forward_remote(forward_addr: SocketAddr) -> SocketAddr {
+        let quic_address = UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let mut config = RemoteConfig::new(&TunnelType::Forward);
+        config.quic_address = quic_address;
+        config.tcp_forward_address = Some(forward_addr);
+        config.tls_cert = include_str!("../../test_cert.pem").to_string();
+        config.tls_key = include_str!("../../test_key.pem").to_string();
+
+        tokio::spawn(remote::start_remote(config));
+
+        quic_address
+    }
+
+    // Connects through the remote at `quic_address`, with the returned end of a duplex in place
+    // of stdio
+    fn spawn_connect(
+        quic_address: SocketAddr,
+    ) -> (LocalConfig, DuplexStream, JoinHandle<Result<(), Error>>) {
+        let config = LocalConfig {
+            remote_quic_server_addr: quic_address,
+            tls_cert: include_str!("../../test_cert.pem").to_string(),
+            ..LocalConfig::default()
+        };
+        let (stdio, local_stream) = tokio::io::duplex(1024);
+        let connect = tokio::spawn(super::connect(config.clone(), local_stream));
+
+        (config, stdio, connect)
+    }
+
+    async fn read_to_end(stdio: &mut DuplexStream) -> Vec<u8> {
+        let mut data = Vec::new();
+        tokio::time::timeout(TIMEOUT, stdio.read_to_end(&mut data))
+            .await
+            .unwrap()
+            .unwrap();
+        data
+    }
+
+    #[tokio::test]
+    async fn test_connect_half_close() {
+        let quic_address = spawn_forward_remote(spawn_echo_server().await);
+        let (config, mut stdio, connect) = spawn_connect(quic_address);
+
+        stdio.write_all(b"ping").await.unwrap();
+        stdio.shutdown().await.unwrap();
+        assert_eq!(read_to_end(&mut stdio).await, b"ping");
+
+        tokio::time::timeout(TIMEOUT, connect)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        let stats = config.monitor.stats();
+        assert_eq!((stats.total_streams, stats.active_streams), (1, 0));
+        assert_eq!((stats.bytes_sent, stats.bytes_received), (4, 4));
+    }
+
+    #[tokio::test]
+    async fn test_connect_ends_with_remote() {
+        let quic_address = spawn_forward_remote(spawn_greeting_server().await);
+        let (config, mut stdio, connect) = spawn_connect(quic_address);
+
+        // stdin is never closed, the remote closing the stream is enough
+        assert_eq!(read_to_end(&mut stdio).await, b"hello");
+        tokio::time::timeout(TIMEOUT, connect)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(config.monitor.stats().failed_streams, 0);
+        drop(stdio);
+    }
+}
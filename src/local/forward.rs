@@ -1,31 +1,171 @@
 use super::config;
-use crate::quic;
-use s2n_quic::stream::BidirectionalStream;
+use crate::{
+    common::proto::{ProtoCommand, StreamHeader},
+    compress,
+    errors::Error,
+    layer::StreamInfo,
+    metadata::Metadata,
+    quic,
+    shutdown::{self, Tracker, TrackerGuard},
+    stream,
+};
+use s2n_quic::connection::Handle;
+use s2n_quic::stream::{BidirectionalStream, ReceiveStream};
 use s2n_quic::Connection;
-use std::error::Error;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpListener;
 use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+use tracing::Instrument;
 
 // Main function to start the local forwarding server
-pub async fn forward_local(
-    local_config: config::LocalConfig,
-) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
-    let quic_conn = setup_quic_connection(&local_config).await?;
+pub async fn forward_local(mut local_config: config::LocalConfig) -> Result<(), Error> {
+    let mut quic_conn = setup_quic_connection(&local_config).await?;
     let tcp_listener = setup_tcp_listener(&local_config).await?;
+    let mut reconnecting = false;
+
+    loop {
+        let connection =
+            tracing::info_span!("connection", peer = %local_config.remote_quic_server_addr);
+        let (end_command, drained) =
+            match handle_quic_connection(&tcp_listener, quic_conn, &local_config)
+                .instrument(connection)
+                .await
+            {
+                Ok(res) => res,
+                // after a GOAWAY the old instance may still be draining and turning connections
+                // away, so failures are retried until the new one is up
+                Err(e) if reconnecting => {
+                    tracing::warn!("Connection to remote instance failed, reconnecting: {e}");
+                    match super::reconnect(&local_config, Duration::from_secs(1)).await {
+                        Some(new_quic_conn) => {
+                            quic_conn = new_quic_conn;
+                            continue;
+                        }
+                        None => return Ok(()),
+                    }
+                }
+                Err(e) => return Err(e),
+            };
+
+        // on GOAWAY the listener stays open and new tcp connections wait for the next quic connection
+        if let ProtoCommand::GOAWAY(after, redirect_address) = end_command {
+            reconnecting = true;
+            tracing::info!("Remote tunnel instance is going away, reconnecting in {after}s...");
+            if let Some(redirect_address) = redirect_address {
+                local_config.remote_quic_server_addr = redirect_address;
+            }
+
+            if let Some(new_quic_conn) =
+                super::reconnect(&local_config, Duration::from_secs(after)).await
+            {
+                quic_conn = new_quic_conn;
+                continue;
+            }
+        }
+
+        drop(tcp_listener);
+        let _ = drained.await;
+
+        return Ok(());
+    }
+}
+
+// Serve tcp connections over one quic connection until it is shut down or told to go away.
+// Returns the command that ended it along with the task draining its remaining streams
+async fn handle_quic_connection(
+    tcp_listener: &TcpListener,
+    mut quic_conn: Connection,
+    local_config: &config::LocalConfig,
+) -> Result<(ProtoCommand, JoinHandle<()>), Error> {
+    // remotes of 0.1.5 and earlier take every stream for a client's and its bytes as they are,
+    // so they get neither a command stream nor stream headers
+    let legacy = quic::legacy_peer(&quic_conn);
+    let mut stream_config = local_config.clone();
+    let (mut command_receiver, mut command_sender) = if legacy {
+        tracing::info!("Remote instance speaks the original protocol, streams go without headers");
+        stream_config.compression = None;
+        (None, None)
+    } else {
+        let mut command_stream = quic_conn.open_bidirectional_stream().await?;
+        compress::send_offer(&mut command_stream).await?;
+        // the remote replies with what it can decompress before this end opens any stream
+        let offer = compress::receive_offer(&mut command_stream).await;
+        stream_config.compression =
+            compress::negotiate(local_config.compression, &offer.unwrap_or_default());
+        let (command_receiver, command_sender) = command_stream.split();
+        (Some(command_receiver), Some(command_sender))
+    };
+    let active_streams = Tracker::default();
+    let _connection = local_config
+        .monitor
+        .connected(local_config.remote_quic_server_addr);
+
+    // Whichever finishes first stops opening new streams, active ones are then drained
+    let end_command = tokio::select! {
+        res = handle_incoming_connections(
+            tcp_listener,
+            quic_conn.handle(),
+            &stream_config,
+            !legacy,
+            active_streams.clone(),
+        ) => res.map(|()| ProtoCommand::CLOSED),
+        _ = local_config.stop.wait() => {
+            tracing::info!("Shutting down, closing tunnel...");
+            if let Some(command_sender) = &mut command_sender {
+                if let Err(e) = command_sender.send(ProtoCommand::CLOSED.deserialize()).await {
+                    tracing::warn!("Could not send CLOSED to remote forward tunnel instance: {e}");
+                }
+            }
+            Ok(ProtoCommand::CLOSED)
+        }
+        cmd = handle_command_stream(command_receiver.as_mut(), &mut quic_conn) => {
+            cmd.inspect(|cmd| {
+                if *cmd == ProtoCommand::CLOSED {
+                    tracing::info!("Remote tunnel instance is shutting down, closing tunnel...");
+                }
+            })
+        }
+    };
+
+    let end_command = match end_command {
+        Ok(end_command) => end_command,
+        Err(e) => {
+            quic::close_with_error(&quic_conn, &e).await;
+            return Err(e);
+        }
+    };
+
+    // a remote sending GOAWAY closes the connection itself once its side of the streams is
+    // done, so only wait for that
+    let drained = match end_command {
+        ProtoCommand::GOAWAY(..) => tokio::spawn(shutdown::drain_and_wait_for_close(
+            quic_conn,
+            active_streams,
+            local_config.drain_timeout,
+        )),
+        _ => shutdown::spawn_drain(quic_conn, active_streams, local_config.drain_timeout),
+    };
 
-    handle_incoming_connections(tcp_listener, quic_conn, local_config.buffer_size).await
+    Ok((end_command, drained))
 }
 
 // Set up the QUIC connection with the remote server
-async fn setup_quic_connection(
+pub(super) async fn setup_quic_connection(
     local_config: &config::LocalConfig,
-) -> Result<Connection, Box<dyn Error + Send + Sync + 'static>> {
-    let mut quic_conn =
-        quic::new_quic_connection(local_config.remote_quic_server_addr, &local_config.tls_cert)
-            .await?;
+) -> Result<Connection, Error> {
+    let mut quic_conn = quic::new_quic_connection(
+        local_config.remote_quic_server_addr,
+        &local_config.tls_cert,
+        &local_config.monitor,
+    )
+    .await
+    .inspect_err(|_| local_config.monitor.handshake_failed())?;
 
     quic_conn.keep_alive(true)?;
-    log::info!(
+    tracing::info!(
         "Quic connection established with remote server with buffer Size: {}",
         local_config.buffer_size
     );
@@ -34,58 +174,194 @@ async fn setup_quic_connection(
 }
 
 // Set up the TCP listener for incoming connections
-async fn setup_tcp_listener(
-    local_config: &config::LocalConfig,
-) -> Result<TcpListener, Box<dyn Error + Send + Sync + 'static>> {
+async fn setup_tcp_listener(local_config: &config::LocalConfig) -> Result<TcpListener, Error> {
     let local_tcp_server_addr = local_config.local_tcp_server_addr;
-    let tcp_listener = TcpListener::bind(local_tcp_server_addr).await?;
-    log::info!("Tunneled Tcp Server accessible at: {local_tcp_server_addr}");
+    let tcp_listener = TcpListener::bind(local_tcp_server_addr)
+        .await
+        .map_err(|e| Error::Bind(format!("unable to listen on {local_tcp_server_addr}: {e}")))?;
+    tracing::info!("Tunneled Tcp Server accessible at: {local_tcp_server_addr}");
+    local_config
+        .monitor
+        .listening(tcp_listener.local_addr().unwrap_or(local_tcp_server_addr));
 
     Ok(tcp_listener)
 }
 
 // Handle all incoming TCP connections and forward them to QUIC streams
 async fn handle_incoming_connections(
-    tcp_listener: TcpListener,
-    mut quic_conn: Connection,
-    buffer_size: usize,
-) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
-    while let Ok((tcp_stream, _tcp_addr)) = tcp_listener.accept().await {
-        let quic_bidirectional_stream = quic_conn.open_bidirectional_stream().await?;
-        spawn_connection_handler(tcp_stream, quic_bidirectional_stream, buffer_size);
+    tcp_listener: &TcpListener,
+    mut quic_handle: Handle,
+    local_config: &config::LocalConfig,
+    with_header: bool,
+    active_streams: Tracker,
+) -> Result<(), Error> {
+    while let Ok((tcp_stream, tcp_addr)) = tcp_listener.accept().await {
+        let metadata = Metadata::new(Some(tcp_addr), tcp_stream.local_addr().ok());
+        let span = tracing::info_span!("stream", stream_id = %metadata.id);
+        span.in_scope(|| tracing::info!("Stream {} received from {tcp_addr}", metadata.id));
+
+        let quic_bidirectional_stream = quic_handle.open_bidirectional_stream().await?;
+        spawn_connection_handler(
+            tcp_stream,
+            quic_bidirectional_stream,
+            local_config.clone(),
+            metadata,
+            with_header,
+            active_streams.track(),
+            span,
+        );
     }
 
     Ok(())
 }
 
+// Returns the command the remote instance ends the tunnel with. Older remotes don't send any and
+// just close the connection
+async fn handle_command_stream(
+    command_receiver: Option<&mut ReceiveStream>,
+    quic_conn: &mut Connection,
+) -> Result<ProtoCommand, Error> {
+    let Some(command_receiver) = command_receiver else {
+        quic::wait_for_close(quic_conn).await;
+        return Err(Error::Protocol(
+            "connection closed by remote instance".to_string(),
+        ));
+    };
+
+    while let Some(cmd_data) = command_receiver.receive().await? {
+        match ProtoCommand::serialize(cmd_data) {
+            Some(cmd @ (ProtoCommand::CLOSED | ProtoCommand::GOAWAY(..))) => return Ok(cmd),
+            _ => tracing::debug!("Received unhandled command"),
+        }
+    }
+
+    Err(Error::Protocol(
+        "command stream closed by remote instance".to_string(),
+    ))
+}
+
 // Spawn a new task to handle an individual connection
 fn spawn_connection_handler(
     tcp_stream: TcpStream,
     quic_bidirectional_stream: BidirectionalStream,
-    buffer_size: usize,
+    local_config: config::LocalConfig,
+    metadata: Metadata,
+    with_header: bool,
+    guard: TrackerGuard,
+    span: tracing::Span,
 ) {
-    tokio::spawn(async move {
-        if let Err(e) =
-            handle_single_connection(tcp_stream, quic_bidirectional_stream, buffer_size).await
-        {
-            log::warn!("Error occurred during bidirectional copy: {e}");
+    tokio::spawn(
+        async move {
+            let _guard = guard;
+
+            if let Err(e) = handle_tcp_connection(
+                tcp_stream,
+                quic_bidirectional_stream,
+                &local_config,
+                metadata,
+                with_header,
+            )
+            .await
+            {
+                tracing::warn!("Stream {} failed: {e}", metadata.id);
+            }
         }
-    });
+        .instrument(span),
+    );
 }
 
-// Handle a single connection's bidirectional copying
-async fn handle_single_connection(
+async fn handle_tcp_connection(
     mut tcp_stream: TcpStream,
     mut quic_bidirectional_stream: BidirectionalStream,
-    buffer_size: usize,
-) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
-    tokio::io::copy_bidirectional_with_sizes(
+    local_config: &config::LocalConfig,
+    mut metadata: Metadata,
+    with_header: bool,
+) -> Result<(), Error> {
+    let tcp_addr = stream::peer_addr(&tcp_stream);
+    let mut accepted = local_config.monitor.stream_accepted(StreamInfo::new(
+        local_config.remote_quic_server_addr,
+        tcp_addr,
+        metadata,
+    ));
+
+    let client = match stream::accept_client(
+        &mut tcp_stream,
         &mut quic_bidirectional_stream,
+        &local_config.trusted_proxies,
+    )
+    .await
+    {
+        Ok(client) => client,
+        Err(e) => {
+            accepted.close(Some(&e));
+            return Err(e);
+        }
+    };
+    if metadata.client != Some(client) {
+        tracing::info!("Stream {} relayed for {client}", metadata.id);
+        metadata.client = Some(client);
+    }
+    accepted.update(|info| {
+        info.tcp_addr = client;
+        info.metadata = metadata;
+    });
+
+    if with_header {
+        let header = StreamHeader {
+            compression: local_config.compression,
+            metadata: Some(metadata),
+            ..StreamHeader::default()
+        };
+        if let Err(e) = header.write_to(&mut quic_bidirectional_stream).await {
+            let error = Error::from(e);
+            accepted.close(Some(&error));
+            return Err(error);
+        }
+    }
+
+    stream::copy(
         &mut tcp_stream,
-        buffer_size,
-        buffer_size,
+        &mut quic_bidirectional_stream,
+        local_config.buffer_size,
+        accepted,
+        &local_config.layers,
+        local_config.compression,
     )
-    .await?;
+    .await
+}
 
-    Ok(())
+// Copies between the quic stream and a local stream that isn't a tcp connection, such as stdio,
+// until the remote has closed it. The metadata names the stream here even when the header can't
+// take it to the remote
+pub(super) async fn handle_single_connection<T: AsyncRead + AsyncWrite + Send + Unpin>(
+    mut local_stream: T,
+    mut quic_bidirectional_stream: BidirectionalStream,
+    header: Option<StreamHeader>,
+    metadata: Metadata,
+    local_config: &config::LocalConfig,
+) -> Result<(), Error> {
+    let accepted = local_config.monitor.stream_accepted(StreamInfo::new(
+        local_config.remote_quic_server_addr,
+        SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        metadata,
+    ));
+
+    let compression = header.as_ref().and_then(|header| header.compression);
+    if let Some(header) = header {
+        if let Err(e) = header.write_to(&mut quic_bidirectional_stream).await {
+            let error = Error::from(e);
+            accepted.close(Some(&error));
+            return Err(error);
+        }
+    }
+
+    stream::copy_io(
+        &mut local_stream,
+        &mut quic_bidirectional_stream,
+        local_config.buffer_size,
+        accepted,
+        &local_config.layers,
+        compression,
+    )
+    .await
 }
@@ -1,15 +1,69 @@
-use crate::common::TunnelType;
+use crate::{common::TunnelType, errors::Error, quic};
 use config::LocalConfig;
+use s2n_quic::Connection;
+use std::time::Duration;
 
 pub mod config;
+mod connect;
 mod forward;
 mod reverse;
 
-pub async fn start_local(
-    config: LocalConfig,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(30);
+
+pub async fn start_local(config: LocalConfig) -> Result<(), Error> {
     match config.tunnel_type {
         TunnelType::Forward => forward::forward_local(config).await,
         TunnelType::Reverse => reverse::reverse_local(config).await,
     }
 }
+
+pub async fn start_connect(config: LocalConfig) -> Result<(), Error> {
+    connect::connect_stdio(config).await
+}
+
+// Waits out the delay from a GOAWAY, then connects to the remote again, retrying with backoff
+// until it is reachable. Returns None if a shutdown signal arrives first
+async fn reconnect(config: &LocalConfig, delay: Duration) -> Option<Connection> {
+    let signal = config.stop.wait();
+    tokio::pin!(signal);
+
+    let mut backoff = Duration::from_secs(1);
+    let mut wait = delay;
+
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {}
+            _ = &mut signal => return None,
+        }
+
+        tracing::info!(
+            "Reconnecting to remote instance at {}",
+            config.remote_quic_server_addr
+        );
+
+        let connection = quic::new_quic_connection(
+            config.remote_quic_server_addr,
+            &config.tls_cert,
+            &config.monitor,
+        );
+        match connection.await {
+            Ok(mut quic_conn) => match quic_conn.keep_alive(true) {
+                Ok(()) => {
+                    config.monitor.reconnected();
+                    return Some(quic_conn);
+                }
+                Err(e) => tracing::warn!("Unable to enable keep alive on reconnect: {e}"),
+            },
+            Err(e) => {
+                config.monitor.handshake_failed();
+                tracing::warn!(
+                    "Unable to reconnect, retrying in {}s: {e}",
+                    backoff.as_secs()
+                )
+            }
+        }
+
+        wait = backoff;
+        backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
+    }
+}
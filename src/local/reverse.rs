@@ -1,158 +1,352 @@
 use super::config::LocalConfig;
 use crate::{
-    common::proto::{self, ProtoCommand},
-    errors::GenericError,
+    common::proto::{self, ProtoCommand, StreamHeader},
+    compress,
+    errors::Error,
+    layer::StreamInfo,
+    metadata::{self, Metadata},
+    monitor::StreamGuard,
     quic,
+    shutdown::{self, StopSignal, Tracker, TrackerGuard},
+    stream,
 };
 use bytes::Bytes;
 use s2n_quic::stream::BidirectionalStream;
-use std::error::Error;
-use std::net::SocketAddr;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
 use tokio::{
-    net::TcpStream,
     sync::mpsc::{channel, Sender},
+    task::JoinHandle,
 };
+use tracing::Instrument;
 
-pub async fn reverse_local(
-    config: LocalConfig,
-) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// the command stream of a connection along with whether its streams open with a header
+type CommandStream = (BidirectionalStream, bool);
+
+pub async fn reverse_local(mut config: LocalConfig) -> Result<(), Error> {
     let mut quic_client = setup_quic_connection(&config).await?;
+    let mut command_stream = open_command_stream(&mut quic_client, &config).await?;
+
+    loop {
+        let connection = tracing::info_span!("connection", peer = %config.remote_quic_server_addr);
+        let (end_command, drained) = handle_quic_connection(quic_client, command_stream, &config)
+            .instrument(connection)
+            .await?;
+
+        if let ProtoCommand::GOAWAY(after, redirect_address) = end_command {
+            tracing::info!("Remote tunnel instance is going away, reconnecting in {after}s...");
+            if let Some(redirect_address) = redirect_address {
+                config.remote_quic_server_addr = redirect_address;
+            }
+
+            if let Some((new_quic_client, new_command_stream)) =
+                reconnect(&config, Duration::from_secs(after)).await
+            {
+                quic_client = new_quic_client;
+                command_stream = new_command_stream;
+                continue;
+            }
+        }
+
+        let _ = drained.await;
+
+        return Ok(());
+    }
+}
+
+// Reconnects after a GOAWAY, retrying until a remote instance completes the handshake.
+// The old instance may still be draining on the same address and never answer it
+async fn reconnect(
+    config: &LocalConfig,
+    delay: Duration,
+) -> Option<(s2n_quic::Connection, CommandStream)> {
+    let mut delay = delay;
+
+    loop {
+        let mut quic_client = super::reconnect(config, delay).await?;
+
+        match open_command_stream(&mut quic_client, config).await {
+            Ok(command_stream) => return Some((quic_client, command_stream)),
+            Err(e) => tracing::warn!("Handshake with remote instance failed, retrying: {e}"),
+        }
+
+        delay = Duration::from_secs(1);
+    }
+}
+
+async fn open_command_stream(
+    quic_client: &mut s2n_quic::Connection,
+    config: &LocalConfig,
+) -> Result<CommandStream, Error> {
     let mut command_stream = quic_client.open_bidirectional_stream().await?;
+    // remotes of 0.1.5 and earlier don't expect anything on it
+    if !quic::legacy_peer(quic_client) {
+        compress::send_offer(&mut command_stream).await?;
+    }
 
-    let remote_tcp_address_port = perform_handshake(&mut command_stream).await?;
-    log::info!(
+    let handshake = tokio::time::timeout(HANDSHAKE_TIMEOUT, perform_handshake(&mut command_stream))
+        .await
+        .unwrap_or_else(|_| {
+            Err(Error::Handshake(
+                "timed out waiting for handshake".to_string(),
+            ))
+        });
+    let (remote_tcp_address_port, with_header) = match handshake {
+        Ok(res) => res,
+        Err(e) => {
+            config.monitor.handshake_failed();
+            quic::close_with_error(quic_client, &e).await;
+            return Err(e);
+        }
+    };
+    tracing::info!(
         "Access from {}:{}",
         config.remote_quic_server_addr.ip(),
         remote_tcp_address_port
     );
+    config.monitor.listening(SocketAddr::new(
+        config.remote_quic_server_addr.ip(),
+        remote_tcp_address_port,
+    ));
 
-    let (close_channel_sender, mut close_channel_receiver) = channel::<()>(1);
-    tokio::spawn(handle_command_stream(command_stream, close_channel_sender));
+    Ok((command_stream, with_header))
+}
 
-    loop {
+// Serve streams on one quic connection until it is closed or told to go away.
+// Returns the command that ended it along with the task draining its remaining streams
+async fn handle_quic_connection(
+    mut quic_client: s2n_quic::Connection,
+    (command_stream, with_header): CommandStream,
+    config: &LocalConfig,
+) -> Result<(ProtoCommand, JoinHandle<()>), Error> {
+    let (close_channel_sender, mut close_channel_receiver) = channel::<ProtoCommand>(1);
+    tokio::spawn(
+        handle_command_stream(command_stream, close_channel_sender, config.stop.clone())
+            .in_current_span(),
+    );
+
+    let active_tunnels = Tracker::default();
+    let _connection = config.monitor.connected(config.remote_quic_server_addr);
+
+    let end_command = loop {
         let server_created_quic_bd_stream = tokio::select! {
-            bd_stream = quic_client.accept_bidirectional_stream() => match bd_stream? {
-                Some(s) => s,
-                None => break,
+            bd_stream = quic_client.accept_bidirectional_stream() => match bd_stream {
+                Ok(Some(s)) => s,
+                Ok(None) => break ProtoCommand::CLOSED,
+                Err(e) => match Error::from(e) {
+                    // the remote may close right after its ACK, before the command stream is read
+                    Error::Closed(_) => break ProtoCommand::CLOSED,
+                    e => return Err(e),
+                },
             },
-            _ = close_channel_receiver.recv() => break,
+            cmd = close_channel_receiver.recv() => break cmd.unwrap_or(ProtoCommand::CLOSED),
         };
 
         spawn_tunnel_handler(
             server_created_quic_bd_stream,
-            config.local_tcp_server_addr,
-            config.buffer_size,
+            config.clone(),
+            with_header,
+            active_tunnels.track(),
         );
-    }
-    Ok(())
+    };
+
+    // a remote sending GOAWAY closes the connection itself once its side of the streams is
+    // done, so only wait for that
+    let drained = match end_command {
+        ProtoCommand::GOAWAY(..) => tokio::spawn(shutdown::drain_and_wait_for_close(
+            quic_client,
+            active_tunnels,
+            config.drain_timeout,
+        )),
+        _ => shutdown::spawn_drain(quic_client, active_tunnels, config.drain_timeout),
+    };
+
+    Ok((end_command, drained))
 }
 
 async fn setup_quic_connection(
     config: &LocalConfig,
-) -> Result<s2n_quic::connection::Connection, Box<dyn Error + Send + Sync + 'static>> {
-    let mut quic_client =
-        quic::new_quic_connection(config.remote_quic_server_addr, &config.tls_cert).await?;
+) -> Result<s2n_quic::connection::Connection, Error> {
+    let mut quic_client = quic::new_quic_connection(
+        config.remote_quic_server_addr,
+        &config.tls_cert,
+        &config.monitor,
+    )
+    .await
+    .inspect_err(|_| config.monitor.handshake_failed())?;
     quic_client.keep_alive(true)?;
-    log::debug!("Connected to remote quic server");
+    tracing::debug!("Connected to remote quic server");
     Ok(quic_client)
 }
 
-async fn perform_handshake(
-    command_stream: &mut BidirectionalStream,
-) -> Result<u16, Box<dyn Error + Send + Sync + 'static>> {
+async fn perform_handshake(command_stream: &mut BidirectionalStream) -> Result<(u16, bool), Error> {
     let handshake_data = receive_handshake_data(command_stream).await?;
     let cmd = serialize_handshake_command(handshake_data)?;
 
-    log::debug!("Handshake complete");
+    tracing::debug!("Handshake complete");
 
     match cmd {
-        ProtoCommand::CONNECTED(socket_addr) => Ok(socket_addr.port()),
-        _ => Err(Box::new(GenericError(
-            "Invalid command from remote instance".to_string(),
+        ProtoCommand::CONNECTED(socket_addr, with_header) => Ok((socket_addr.port(), with_header)),
+        cmd => Err(Error::Protocol(format!(
+            "expected CONNECTED from remote instance, got {cmd:?}"
         ))),
     }
 }
 
-async fn receive_handshake_data(
-    command_stream: &mut BidirectionalStream,
-) -> Result<Bytes, Box<dyn Error + Send + Sync + 'static>> {
+async fn receive_handshake_data(command_stream: &mut BidirectionalStream) -> Result<Bytes, Error> {
     match command_stream.receive().await {
         Ok(Some(data)) => Ok(data),
-        Err(_) | Ok(None) => Err(Box::new(GenericError(
-            "Unable to receive handshake data".to_string(),
-        ))),
+        // a remote that can't serve the tunnel closes the connection with the reason
+        Err(e) => Err(Error::from(e)),
+        Ok(None) => Err(Error::Handshake(
+            "command stream closed before the handshake".to_string(),
+        )),
     }
 }
 
-fn serialize_handshake_command(
-    data: Bytes,
-) -> Result<ProtoCommand, Box<dyn Error + Send + Sync + 'static>> {
-    proto::ProtoCommand::serialize(data).ok_or_else(|| {
-        let err: Box<dyn Error + Send + Sync + 'static> = Box::new(GenericError(
-            "Unable to deserialize handshake data".to_string(),
-        ));
-        err
-    })
+fn serialize_handshake_command(data: Bytes) -> Result<ProtoCommand, Error> {
+    proto::ProtoCommand::serialize(data)
+        .ok_or_else(|| Error::Protocol("unable to deserialize handshake data".to_string()))
 }
 
 fn spawn_tunnel_handler(
     quic_stream: BidirectionalStream,
-    tcp_addr: SocketAddr,
-    buffer_size: usize,
+    config: LocalConfig,
+    with_header: bool,
+    guard: TrackerGuard,
 ) {
-    tokio::spawn(async move {
-        if let Err(e) = handle_single_tunnel(quic_stream, tcp_addr, buffer_size).await {
-            log::debug!("Error while bidirectional copy: {e}");
+    // the id comes with the header
+    let span = tracing::info_span!("stream", stream_id = tracing::field::Empty);
+    tokio::spawn(
+        async move {
+            let _guard = guard;
+            let mut quic_stream = quic_stream;
+
+            // the target is known once it is dialed
+            let mut accepted = config.monitor.stream_accepted(StreamInfo::new(
+                config.remote_quic_server_addr,
+                SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+                Metadata::new(None, None),
+            ));
+            let header = match with_header {
+                true => match stream::read_header(&mut quic_stream).await {
+                    Ok(header) => header,
+                    Err(e) => {
+                        tracing::warn!("Unable to read stream header from the remote: {e}");
+                        accepted.close(Some(&e));
+                        return;
+                    }
+                },
+                false => StreamHeader::default(),
+            };
+            // streams of older remotes don't have an id yet, this end gives them one
+            let metadata = header.metadata.unwrap_or_else(|| Metadata::new(None, None));
+            accepted.update(|info| info.metadata = metadata);
+            tracing::Span::current().record("stream_id", tracing::field::display(metadata.id));
+            tracing::info!(
+                "Stream {metadata} accepted by the remote at {}ms",
+                metadata::unix_millis(metadata.accepted)
+            );
+
+            if let Err(e) =
+                handle_single_tunnel(quic_stream, &config, header, metadata, accepted).await
+            {
+                tracing::warn!(
+                    "Stream {} to {} failed: {e}",
+                    metadata.id,
+                    config.local_tcp_server_addr
+                );
+            }
         }
-    });
+        .instrument(span),
+    );
 }
 
 async fn handle_single_tunnel(
     mut quic_stream: BidirectionalStream,
-    tcp_addr: SocketAddr,
-    buffer_size: usize,
-) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
-    let mut tcp_stream = TcpStream::connect(tcp_addr).await?;
+    config: &LocalConfig,
+    header: StreamHeader,
+    metadata: Metadata,
+    mut accepted: StreamGuard,
+) -> Result<(), Error> {
+    let mut tcp_stream = match stream::dial(
+        config.local_tcp_server_addr,
+        &mut quic_stream,
+        &config.monitor,
+    )
+    .await
+    {
+        Ok(tcp_stream) => tcp_stream,
+        Err(e) => {
+            accepted.close(Some(&e));
+            return Err(e);
+        }
+    };
+    accepted.update(|info| info.tcp_addr = stream::peer_addr(&tcp_stream));
+    if let Some(version) = config.proxy_protocol {
+        if let Err(e) =
+            stream::send_proxy_header(&mut tcp_stream, &mut quic_stream, version, &metadata).await
+        {
+            accepted.close(Some(&e));
+            return Err(e);
+        }
+    }
 
-    tokio::io::copy_bidirectional_with_sizes(
+    stream::copy(
         &mut tcp_stream,
         &mut quic_stream,
-        buffer_size,
-        buffer_size,
+        config.buffer_size,
+        accepted,
+        &config.layers,
+        header.compression,
     )
-    .await?;
-
-    Ok(())
+    .await
 }
 
 async fn handle_command_stream(
     command_stream: BidirectionalStream,
-    close_channel_sender: Sender<()>,
+    close_channel_sender: Sender<ProtoCommand>,
+    stop: StopSignal,
 ) {
     let (mut receiver, mut sender) = command_stream.split();
 
-    tokio::spawn(async move {
-        if tokio::signal::ctrl_c().await.is_ok() {
-            if let Err(e) = sender.send(ProtoCommand::CLOSED.deserialize()).await {
-                log::warn!("Could not send CLOSED to remote reverse tunnel instance: {e}");
-            }
-        }
-    });
-
-    while let Ok(Some(cmd_data)) = receiver.receive().await {
-        if let Some(cmd) = proto::ProtoCommand::serialize(cmd_data) {
-            match cmd {
-                ProtoCommand::CLOSED => {
-                    log::info!("Remote tunnel instance has closed the connection");
-                    close_channel_sender.send(()).await.unwrap();
-                    break;
+    let signal = stop.wait();
+    tokio::pin!(signal);
+    let mut signalled = false;
+
+    loop {
+        tokio::select! {
+            _ = &mut signal, if !signalled => {
+                signalled = true;
+                if let Err(e) = sender.send(ProtoCommand::CLOSED.deserialize()).await {
+                    tracing::warn!("Could not send CLOSED to remote reverse tunnel instance: {e}");
                 }
-                ProtoCommand::ACK => {
-                    log::info!("Closing local instance");
-                    close_channel_sender.send(()).await.unwrap();
-                    break;
+            }
+            cmd_data = receiver.receive() => {
+                let cmd_data = match cmd_data {
+                    Ok(Some(cmd_data)) => cmd_data,
+                    _ => break,
+                };
+
+                match proto::ProtoCommand::serialize(cmd_data) {
+                    Some(ProtoCommand::CLOSED) => {
+                        tracing::info!("Remote tunnel instance has closed the connection");
+                        let _ = close_channel_sender.send(ProtoCommand::CLOSED).await;
+                        break;
+                    }
+                    Some(ProtoCommand::ACK) => {
+                        tracing::info!("Closing local instance");
+                        let _ = close_channel_sender.send(ProtoCommand::CLOSED).await;
+                        break;
+                    }
+                    Some(cmd @ ProtoCommand::GOAWAY(..)) => {
+                        let _ = close_channel_sender.send(cmd).await;
+                        break;
+                    }
+                    _ => {}
                 }
-                _ => {}
             }
         }
     }
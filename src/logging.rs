@@ -0,0 +1,415 @@
+// Where log lines go and what they look like. By default they are colored text on stderr, at
+// info level or at trace level with debug, prefixed with the tunnel, connection and stream spans
+// they were logged in. `json` writes one object per line instead, with the fields of those spans
+// after its own, for log pipelines that would otherwise have to parse the text:
+//
+//   {"time":"2026-10-19T08:12:03.418Z","level":"INFO","target":"sirang::local::forward",
+//    "message":"Stream 146bff4877fe46dd received from 127.0.0.1:50276",
+//    "peer":"203.0.113.5:4433","stream_id":"146bff4877fe46dd","tunnel":"ssh"}
+//
+// Filters take RUST_LOG directives such as `info,s2n_quic=warn,sirang::remote=debug`, applied
+// after the level debug sets and after RUST_LOG itself. A log file is appended to, and with a
+// size it is rotated like logrotate does, to `<file>.1`, `<file>.2` and so on
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use serde::Deserialize;
+
+#[cfg(feature = "cli")]
+use crate::{access::Timestamp, errors::Error};
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!(
+                "unknown log format `{value}`, expected text or json"
+            )),
+        }
+    }
+}
+
+// Old files kept when a size is given without a count
+pub const DEFAULT_LOG_FILES: usize = 5;
+
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct LogOptions {
+    pub debug: bool,
+    pub format: LogFormat,
+    pub filter: Option<String>,
+    pub file: Option<PathBuf>,
+    // rotate the file once it would grow past this many bytes
+    pub max_size: Option<u64>,
+    pub max_files: Option<usize>,
+    // an OpenTelemetry collector to export spans to, with the otlp feature
+    pub otlp_endpoint: Option<String>,
+}
+
+// A log file that moves itself aside once it reaches its size, keeping a number of old files.
+// Writes are never split across files, so a line written at once stays in one file
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: Option<u64>,
+    max_files: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    pub fn open(path: &Path, max_size: Option<u64>, max_files: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            max_size,
+            max_files,
+            file,
+            size,
+        })
+    }
+
+    fn rotated(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{index}"));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.max_files == 0 {
+            self.file.set_len(0)?;
+        } else {
+            // the oldest file is overwritten by the one before it
+            for index in (1..self.max_files).rev() {
+                let from = self.rotated(index);
+                if from.exists() {
+                    fs::rename(from, self.rotated(index + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+            self.file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+        }
+        self.size = 0;
+
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(max_size) = self.max_size {
+            if self.size > 0 && self.size + buf.len() as u64 > max_size {
+                self.rotate()?;
+            }
+        }
+
+        self.file.write_all(buf)?;
+        self.size += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+// Sets up the global subscriber. Logging goes to stderr with the default filter when the file
+// or filter is unusable, so the error can still be reported
+#[cfg(feature = "cli")]
+pub fn init(options: &LogOptions) -> Result<(), Error> {
+    use std::{io::IsTerminal, sync::Mutex};
+
+    use tracing_subscriber::{
+        fmt::{self, format::JsonFields, writer::BoxMakeWriter},
+        layer::SubscriberExt,
+        util::SubscriberInitExt,
+        EnvFilter, Layer,
+    };
+
+    let mut result = Ok(());
+
+    let level = if options.debug { "trace" } else { "info" };
+    let mut filter = EnvFilter::new(level);
+    let directives = std::env::var("RUST_LOG")
+        .ok()
+        .into_iter()
+        .chain(options.filter.clone());
+    for directives in directives {
+        for directive in directives
+            .split(',')
+            .filter(|directive| !directive.is_empty())
+        {
+            match directive.parse() {
+                Ok(directive) => filter = filter.add_directive(directive),
+                Err(e) => {
+                    result = Err(Error::Config(format!(
+                        "invalid log filter `{directive}`: {e}"
+                    )))
+                }
+            }
+        }
+    }
+    if result.is_err() {
+        filter = EnvFilter::new(level);
+    }
+
+    let mut to_file = false;
+    let writer = match &options.file {
+        Some(path) => {
+            let max_files = options.max_files.unwrap_or(DEFAULT_LOG_FILES);
+            match RotatingFile::open(path, options.max_size, max_files) {
+                Ok(file) => {
+                    to_file = true;
+                    BoxMakeWriter::new(Mutex::new(file))
+                }
+                Err(e) => {
+                    result = Err(Error::Config(format!(
+                        "unable to open log file {}: {e}",
+                        path.display()
+                    )));
+                    BoxMakeWriter::new(io::stderr)
+                }
+            }
+        }
+        None => BoxMakeWriter::new(io::stderr),
+    };
+
+    let output = match options.format {
+        LogFormat::Text => fmt::layer()
+            .with_ansi(!to_file && io::stderr().is_terminal())
+            .with_writer(writer)
+            .boxed(),
+        LogFormat::Json => fmt::layer()
+            .event_format(JsonFormat)
+            .fmt_fields(JsonFields::new())
+            .with_writer(writer)
+            .boxed(),
+    };
+
+    #[cfg(feature = "otlp")]
+    let otlp = match &options.otlp_endpoint {
+        Some(endpoint) => match otlp_layer(endpoint) {
+            Ok(layer) => Some(layer),
+            Err(e) => {
+                result = Err(e);
+                None
+            }
+        },
+        None => None,
+    };
+    #[cfg(not(feature = "otlp"))]
+    let otlp: Option<fmt::Layer<_>> = match options.otlp_endpoint {
+        Some(_) => {
+            result = Err(Error::Config(
+                "exporting traces needs sirang built with the otlp feature".to_string(),
+            ));
+            None
+        }
+        None => None,
+    };
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(output)
+        .with(otlp)
+        .init();
+    result
+}
+
+// Sends the spans that are still waiting to be exported, before the process exits
+#[cfg(feature = "cli")]
+pub fn flush() {
+    #[cfg(feature = "otlp")]
+    if let Some(provider) = TRACER_PROVIDER.get() {
+        if let Err(e) = provider.shutdown() {
+            eprintln!("Unable to export the remaining traces: {e}");
+        }
+    }
+}
+
+#[cfg(feature = "otlp")]
+static TRACER_PROVIDER: std::sync::OnceLock<opentelemetry_sdk::trace::SdkTracerProvider> =
+    std::sync::OnceLock::new();
+
+// Exports spans over OTLP/HTTP. An endpoint without a path gets the standard `/v1/traces`
+#[cfg(feature = "otlp")]
+pub(crate) fn otlp_layer<S>(endpoint: &str) -> Result<impl tracing_subscriber::Layer<S>, Error>
+where
+    S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+{
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+    use opentelemetry_sdk::{trace::SdkTracerProvider, Resource};
+
+    let endpoint = match endpoint.trim_end_matches('/') {
+        endpoint if endpoint.ends_with("/v1/traces") => endpoint.to_string(),
+        endpoint => format!("{endpoint}/v1/traces"),
+    };
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(&endpoint)
+        .build()
+        .map_err(|e| Error::Config(format!("unable to export traces to {endpoint}: {e}")))?;
+
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name("sirang").build())
+        .build();
+    let tracer = provider.tracer("sirang");
+    let _ = TRACER_PROVIDER.set(provider);
+
+    Ok(tracing_opentelemetry::layer().with_tracer(tracer))
+}
+
+// One object per event, with the fields of the spans it happened in next to its own
+#[cfg(feature = "cli")]
+struct JsonFormat;
+
+#[cfg(feature = "cli")]
+impl<S, N> tracing_subscriber::fmt::FormatEvent<S, N> for JsonFormat
+where
+    S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+    N: for<'a> tracing_subscriber::fmt::FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &tracing_subscriber::fmt::FmtContext<'_, S, N>,
+        mut writer: tracing_subscriber::fmt::format::Writer<'_>,
+        event: &tracing::Event<'_>,
+    ) -> std::fmt::Result {
+        use std::time::SystemTime;
+
+        use tracing_log::NormalizeEvent;
+        use tracing_subscriber::fmt::FormattedFields;
+
+        // events forwarded from the log crate carry their real target separately
+        let normalized = event.normalized_metadata();
+        let metadata = normalized.as_ref().unwrap_or_else(|| event.metadata());
+
+        let mut fields = serde_json::Map::new();
+        // outer spans first, so the fields of inner ones take precedence
+        if let Some(scope) = ctx.event_scope() {
+            for span in scope.from_root() {
+                if let Some(span_fields) = span.extensions().get::<FormattedFields<N>>() {
+                    if let Ok(serde_json::Value::Object(span_fields)) =
+                        serde_json::from_str(span_fields)
+                    {
+                        fields.extend(span_fields);
+                    }
+                }
+            }
+        }
+        let mut line = JsonLine {
+            time: Timestamp(SystemTime::now()),
+            level: metadata.level().as_str(),
+            target: metadata.target(),
+            message: String::new(),
+            fields,
+        };
+        event.record(&mut line);
+
+        let line = serde_json::to_string(&line).map_err(|_| std::fmt::Error)?;
+        writeln!(writer, "{line}")
+    }
+}
+
+#[cfg(feature = "cli")]
+#[derive(serde::Serialize)]
+struct JsonLine<'a> {
+    time: Timestamp,
+    level: &'static str,
+    target: &'a str,
+    message: String,
+    #[serde(flatten)]
+    fields: serde_json::Map<String, serde_json::Value>,
+}
+
+#[cfg(feature = "cli")]
+impl tracing::field::Visit for JsonLine<'_> {
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+        self.record_str(field, &format!("{value:?}"));
+    }
+
+    fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+        match field.name() {
+            "message" => self.message = value.to_string(),
+            // where a forwarded log record came from, already in the target
+            name if name.starts_with("log.") => {}
+            name => {
+                self.fields.insert(name.to_string(), value.into());
+            }
+        }
+    }
+
+    fn record_i64(&mut self, field: &tracing::field::Field, value: i64) {
+        self.fields.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &tracing::field::Field, value: u64) {
+        self.fields.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &tracing::field::Field, value: bool) {
+        self.fields.insert(field.name().to_string(), value.into());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Write};
+
+    use super::{LogFormat, RotatingFile};
+
+    #[test]
+    fn test_log_format() {
+        assert_eq!("json".parse(), Ok(LogFormat::Json));
+        assert_eq!("text".parse(), Ok(LogFormat::Text));
+        assert!("logfmt".parse::<LogFormat>().is_err());
+    }
+
+    #[test]
+    fn test_rotation() {
+        let dir = std::env::temp_dir().join(format!("sirang-logs-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("sirang.log");
+
+        let mut file = RotatingFile::open(&path, Some(10), 2).unwrap();
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        // a line longer than the size still goes in whole
+        file.write_all(b"a long fifth line\n").unwrap();
+
+        let read = |name: &str| fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("sirang.log"), "a long fifth line\n");
+        assert_eq!(read("sirang.log.1"), "fourth\n");
+        assert_eq!(read("sirang.log.2"), "third\n");
+        assert!(!dir.join("sirang.log.3").exists());
+
+        // reopening carries on with the size already written
+        let mut file = RotatingFile::open(&path, Some(20), 2).unwrap();
+        file.write_all(b"sixth\n").unwrap();
+        assert_eq!(read("sirang.log.1"), "a long fifth line\n");
+        assert_eq!(read("sirang.log"), "sixth\n");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
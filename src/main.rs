@@ -1,4 +1,4 @@
 #[tokio::main]
 async fn main() {
-    sirang::cmd::execute().await;
+    sirang::execute().await;
 }
@@ -0,0 +1,152 @@
+// What the end of the tunnel that accepted a tcp connection knows about it. It travels in the
+// stream header, so the end that connects to the target can log it, pass it to hooks and put
+// it in PROXY headers, and the id ties together what both ends report about the stream
+use std::{
+    collections::hash_map::RandomState,
+    fmt,
+    hash::{BuildHasher, Hasher},
+    net::SocketAddr,
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct StreamId(u64);
+
+impl StreamId {
+    // Random rather than counted, so the ids of different instances and runs don't collide
+    pub fn generate() -> Self {
+        static GENERATED: AtomicU64 = AtomicU64::new(0);
+
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(GENERATED.fetch_add(1, Ordering::Relaxed));
+        hasher.write_u128(unix_millis(SystemTime::now()) as u128);
+        Self(hasher.finish())
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+impl FromStr for StreamId {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if value.len() != 16 {
+            return Err(format!("invalid stream id `{value}`"));
+        }
+        u64::from_str_radix(value, 16)
+            .map(Self)
+            .map_err(|_| format!("invalid stream id `{value}`"))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[non_exhaustive]
+pub struct Metadata {
+    pub id: StreamId,
+    // when the tcp connection was accepted, to the millisecond
+    pub accepted: SystemTime,
+    // the tcp client and the address it connected to, unknown for connect and for streams from
+    // older versions
+    pub client: Option<SocketAddr>,
+    pub listener: Option<SocketAddr>,
+}
+
+impl Metadata {
+    // A stream accepted now
+    pub fn new(client: Option<SocketAddr>, listener: Option<SocketAddr>) -> Self {
+        Self {
+            id: StreamId::generate(),
+            accepted: UNIX_EPOCH + Duration::from_millis(unix_millis(SystemTime::now())),
+            client,
+            listener,
+        }
+    }
+
+    // The stream header options, `id=.. time=.. client=.. listener=..`
+    pub(crate) fn options(&self) -> String {
+        let mut options = format!("id={} time={}", self.id, unix_millis(self.accepted));
+        if let Some(client) = self.client {
+            options.push_str(&format!(" client={client}"));
+        }
+        if let Some(listener) = self.listener {
+            options.push_str(&format!(" listener={listener}"));
+        }
+
+        options
+    }
+
+    // Takes one of the options, false for keys it doesn't know
+    pub(crate) fn set_option(&mut self, key: &str, value: &str) -> Option<bool> {
+        match key {
+            "id" => self.id = value.parse().ok()?,
+            "time" => self.accepted = UNIX_EPOCH + Duration::from_millis(value.parse().ok()?),
+            "client" => self.client = Some(value.parse().ok()?),
+            "listener" => self.listener = Some(value.parse().ok()?),
+            _ => return Some(false),
+        }
+
+        Some(true)
+    }
+}
+
+// The id followed by what is known about the client, such as
+// `5f1c2a9e0b7d4c38 for 203.0.113.7:51234 on 10.0.0.5:5432`
+impl fmt::Display for Metadata {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.id)?;
+        if let Some(client) = self.client {
+            write!(f, " for {client}")?;
+        }
+        if let Some(listener) = self.listener {
+            write!(f, " on {listener}")?;
+        }
+
+        Ok(())
+    }
+}
+
+pub(crate) fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Metadata, StreamId};
+
+    #[test]
+    fn test_stream_id() {
+        let id = StreamId::generate();
+        assert_ne!(id, StreamId::generate());
+        assert_eq!(id.to_string().len(), 16);
+        assert_eq!(id.to_string().parse::<StreamId>().unwrap(), id);
+
+        assert!("abc".parse::<StreamId>().is_err());
+        assert!("000000000000000g".parse::<StreamId>().is_err());
+    }
+
+    #[test]
+    fn test_options() {
+        let metadata = Metadata::new(
+            Some("203.0.113.7:51234".parse().unwrap()),
+            Some("10.0.0.5:5432".parse().unwrap()),
+        );
+
+        let mut parsed = Metadata::new(None, None);
+        for option in metadata.options().split(' ') {
+            let (key, value) = option.split_once('=').unwrap();
+            assert_eq!(parsed.set_option(key, value), Some(true));
+        }
+        assert_eq!(parsed, metadata);
+
+        assert_eq!(parsed.set_option("compress", "zstd"), Some(false));
+        assert_eq!(parsed.set_option("client", "nowhere"), None);
+    }
+}
@@ -0,0 +1,421 @@
+// Prometheus metrics of the running tunnels, served as text on `/metrics` of the address given
+// with `--metricsaddr`, e.g. `curl http://127.0.0.1:9100/metrics`. Every series is labeled with
+// the name of its tunnel, the subcommand for a tunnel run without a config file
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io,
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{
+    errors::Error,
+    monitor::{Monitor, Stats, DIAL_BUCKETS},
+    transport::{QuicStats, QuicTotals},
+};
+
+const MAX_REQUEST_LEN: u64 = 8192;
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+type StatsMetric = (&'static str, &'static str, &'static str, fn(&Stats) -> u64);
+
+const STATS_METRICS: [StatsMetric; 8] = [
+    (
+        "sirang_connections",
+        "gauge",
+        "QUIC connections currently serving streams",
+        |stats| stats.connections,
+    ),
+    (
+        "sirang_streams_active",
+        "gauge",
+        "Streams currently carrying a tcp connection",
+        |stats| stats.active_streams,
+    ),
+    (
+        "sirang_streams_opened_total",
+        "counter",
+        "Streams that started carrying a tcp connection",
+        |stats| stats.total_streams,
+    ),
+    (
+        "sirang_streams_closed_total",
+        "counter",
+        "Streams that stopped carrying a tcp connection",
+        |stats| stats.total_streams - stats.active_streams,
+    ),
+    (
+        "sirang_bytes_sent_total",
+        "counter",
+        "Bytes read from tcp connections and sent through the tunnel",
+        |stats| stats.bytes_sent,
+    ),
+    (
+        "sirang_bytes_received_total",
+        "counter",
+        "Bytes received through the tunnel and written to tcp connections",
+        |stats| stats.bytes_received,
+    ),
+    (
+        "sirang_handshake_failures_total",
+        "counter",
+        "QUIC connections or tunnel handshakes with a peer that didn't complete",
+        |stats| stats.handshake_failures,
+    ),
+    (
+        "sirang_reconnects_total",
+        "counter",
+        "Connections to the remote made again after a GOAWAY",
+        |stats| stats.reconnects,
+    ),
+];
+
+type QuicTotalsMetric = (&'static str, &'static str, fn(&QuicTotals) -> u64);
+
+const QUIC_TOTALS_METRICS: [QuicTotalsMetric; 4] = [
+    (
+        "sirang_quic_packets_sent_total",
+        "QUIC packets sent",
+        |totals| totals.packets_sent,
+    ),
+    (
+        "sirang_quic_packets_lost_total",
+        "QUIC packets declared lost",
+        |totals| totals.packets_lost,
+    ),
+    (
+        "sirang_quic_bytes_lost_total",
+        "Bytes of the QUIC packets declared lost",
+        |totals| totals.bytes_lost,
+    ),
+    (
+        "sirang_quic_congestion_events_total",
+        "Times a congestion window shrank because of loss or ECN",
+        |totals| totals.congestion_events,
+    ),
+];
+
+// gauges of each open connection, labeled with its peer
+type QuicMetric = (&'static str, &'static str, fn(&QuicStats) -> f64);
+
+const QUIC_METRICS: [QuicMetric; 6] = [
+    (
+        "sirang_quic_rtt_seconds",
+        "Smoothed round trip time of a QUIC connection",
+        |stats| stats.smoothed_rtt.as_secs_f64(),
+    ),
+    (
+        "sirang_quic_min_rtt_seconds",
+        "Lowest round trip time seen on a QUIC connection",
+        |stats| stats.min_rtt.as_secs_f64(),
+    ),
+    (
+        "sirang_quic_rtt_variance_seconds",
+        "Variation of the round trip time of a QUIC connection",
+        |stats| stats.rtt_variance.as_secs_f64(),
+    ),
+    (
+        "sirang_quic_congestion_window_bytes",
+        "Congestion window of a QUIC connection",
+        |stats| f64::from(stats.congestion_window),
+    ),
+    (
+        "sirang_quic_bytes_in_flight",
+        "Bytes sent on a QUIC connection and not acknowledged yet",
+        |stats| f64::from(stats.bytes_in_flight),
+    ),
+    (
+        "sirang_quic_mtu_bytes",
+        "Path MTU of a QUIC connection",
+        |stats| f64::from(stats.mtu),
+    ),
+];
+
+// The tunnels whose metrics are served, by name
+#[derive(Clone, Default)]
+pub struct Metrics {
+    tunnels: Arc<RwLock<BTreeMap<String, Monitor>>>,
+}
+
+impl Metrics {
+    pub fn add(&self, name: &str, monitor: &Monitor) {
+        self.tunnels
+            .write()
+            .unwrap()
+            .insert(name.to_string(), monitor.clone());
+    }
+
+    pub fn remove(&self, name: &str) {
+        self.tunnels.write().unwrap().remove(name);
+    }
+
+    // Every metric in the Prometheus text format
+    pub fn render(&self) -> String {
+        let tunnels = self.tunnels.read().unwrap();
+        let mut out = String::new();
+
+        let stats: Vec<(String, Stats)> = tunnels
+            .iter()
+            .map(|(name, monitor)| (label(name), monitor.stats()))
+            .collect();
+        for (metric, kind, help, value) in STATS_METRICS {
+            header(&mut out, metric, kind, help);
+            for (tunnel, stats) in &stats {
+                let _ = writeln!(out, "{metric}{{tunnel=\"{tunnel}\"}} {}", value(stats));
+            }
+        }
+
+        let metric = "sirang_stream_errors_total";
+        header(&mut out, metric, "counter", "Streams that failed, by cause");
+        for (name, monitor) in tunnels.iter() {
+            for (cause, count) in monitor.stream_errors() {
+                let _ = writeln!(
+                    out,
+                    "{metric}{{tunnel=\"{}\",cause=\"{cause}\"}} {count}",
+                    label(name)
+                );
+            }
+        }
+
+        let metric = "sirang_dial_duration_seconds";
+        header(
+            &mut out,
+            metric,
+            "histogram",
+            "Time taken to connect to the tcp targets of streams",
+        );
+        for (name, monitor) in tunnels.iter() {
+            let tunnel = label(name);
+            let histogram = monitor.dial_latency();
+            let mut count = 0;
+            for (bound, bucket) in DIAL_BUCKETS.iter().zip(histogram.counts) {
+                count += bucket;
+                let le = bound.as_secs_f64();
+                let _ = writeln!(
+                    out,
+                    "{metric}_bucket{{tunnel=\"{tunnel}\",le=\"{le}\"}} {count}"
+                );
+            }
+            count += histogram.counts[DIAL_BUCKETS.len()];
+            let _ = writeln!(
+                out,
+                "{metric}_bucket{{tunnel=\"{tunnel}\",le=\"+Inf\"}} {count}"
+            );
+            let _ = writeln!(
+                out,
+                "{metric}_sum{{tunnel=\"{tunnel}\"}} {}",
+                histogram.sum.as_secs_f64()
+            );
+            let _ = writeln!(out, "{metric}_count{{tunnel=\"{tunnel}\"}} {count}");
+        }
+
+        let totals: Vec<(String, QuicTotals)> = tunnels
+            .iter()
+            .map(|(name, monitor)| (label(name), monitor.transport().totals()))
+            .collect();
+        for (metric, help, value) in QUIC_TOTALS_METRICS {
+            header(&mut out, metric, "counter", help);
+            for (tunnel, totals) in &totals {
+                let _ = writeln!(out, "{metric}{{tunnel=\"{tunnel}\"}} {}", value(totals));
+            }
+        }
+
+        let connections: Vec<(String, Vec<QuicStats>)> = tunnels
+            .iter()
+            .map(|(name, monitor)| (label(name), monitor.quic_stats()))
+            .collect();
+        for (metric, help, value) in QUIC_METRICS {
+            header(&mut out, metric, "gauge", help);
+            for (tunnel, connections) in &connections {
+                for stats in connections {
+                    let _ = writeln!(
+                        out,
+                        "{metric}{{tunnel=\"{tunnel}\",peer=\"{}\"}} {}",
+                        stats.peer,
+                        value(stats)
+                    );
+                }
+            }
+        }
+
+        out
+    }
+
+    // Serves the metrics on `address` in the background, returns the address it listens on
+    pub async fn serve(&self, address: SocketAddr) -> Result<SocketAddr, Error> {
+        let listener = TcpListener::bind(address)
+            .await
+            .map_err(|e| Error::Bind(format!("unable to serve metrics on {address}: {e}")))?;
+        let address = listener.local_addr()?;
+        tracing::info!("Serving metrics on http://{address}/metrics");
+
+        let metrics = self.clone();
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        tokio::spawn(handle_client(stream, metrics.clone()));
+                    }
+                    Err(e) => tracing::warn!("Error accepting metrics connection: {e}"),
+                }
+            }
+        });
+
+        Ok(address)
+    }
+}
+
+fn header(out: &mut String, metric: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {metric} {help}\n# TYPE {metric} {kind}");
+}
+
+// A tunnel name as a label value
+fn label(name: &str) -> String {
+    name.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+async fn handle_client(stream: TcpStream, metrics: Metrics) {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader.take(MAX_REQUEST_LEN));
+
+    let (status, body) = match tokio::time::timeout(READ_TIMEOUT, read_request(&mut reader)).await {
+        Ok(Ok((method, path))) => match (method.as_str(), path.as_str()) {
+            ("GET", "/metrics") => ("200 OK", metrics.render()),
+            ("GET", _) => (
+                "404 Not Found",
+                "metrics are served on /metrics\n".to_string(),
+            ),
+            _ => (
+                "405 Method Not Allowed",
+                "only GET is supported\n".to_string(),
+            ),
+        },
+        Ok(Err(e)) => ("400 Bad Request", format!("{e}\n")),
+        Err(_) => return,
+    };
+
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    if let Err(e) = writer.write_all(response.as_bytes()).await {
+        tracing::debug!("Unable to answer metrics request: {e}");
+    }
+}
+
+// The method and path of a request, once its headers have been read. They are read even though
+// they aren't used, as closing with unread data would reset the connection under the response
+async fn read_request<R: AsyncBufReadExt + Unpin>(reader: &mut R) -> io::Result<(String, String)> {
+    let mut line = String::new();
+    reader.read_line(&mut line).await?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid request line",
+        ));
+    };
+    let path = target.split('?').next().unwrap_or_default();
+    let request = (method.to_string(), path.to_string());
+
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).await? == 0 || header.trim().is_empty() {
+            return Ok(request);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use super::Metrics;
+    use crate::{errors::Error, monitor::Monitor};
+
+    #[test]
+    fn test_render() {
+        let monitor = Monitor::default();
+        monitor.stream_failed(&Error::Refused("connection refused".to_string()));
+        monitor.stream_failed(&Error::Refused("connection refused".to_string()));
+        monitor.stream_failed(&Error::TimedOut("no answer".to_string()));
+        monitor.dialed(Duration::from_millis(3));
+        monitor.dialed(Duration::from_millis(200));
+        monitor.dialed(Duration::from_secs(30));
+        monitor.handshake_failed();
+
+        let metrics = Metrics::default();
+        metrics.add("ssh", &monitor);
+        metrics.add("web \"1\"", &Monitor::default());
+        let text = metrics.render();
+
+        for line in [
+            "# TYPE sirang_connections gauge",
+            "sirang_streams_opened_total{tunnel=\"ssh\"} 0",
+            "sirang_handshake_failures_total{tunnel=\"ssh\"} 1",
+            "sirang_handshake_failures_total{tunnel=\"web \\\"1\\\"\"} 0",
+            "sirang_stream_errors_total{tunnel=\"ssh\",cause=\"refused\"} 2",
+            "sirang_stream_errors_total{tunnel=\"ssh\",cause=\"timed_out\"} 1",
+            "# TYPE sirang_dial_duration_seconds histogram",
+            "sirang_dial_duration_seconds_bucket{tunnel=\"ssh\",le=\"0.001\"} 0",
+            "sirang_dial_duration_seconds_bucket{tunnel=\"ssh\",le=\"0.005\"} 1",
+            "sirang_dial_duration_seconds_bucket{tunnel=\"ssh\",le=\"0.25\"} 2",
+            "sirang_dial_duration_seconds_bucket{tunnel=\"ssh\",le=\"10\"} 2",
+            "sirang_dial_duration_seconds_bucket{tunnel=\"ssh\",le=\"+Inf\"} 3",
+            "sirang_dial_duration_seconds_sum{tunnel=\"ssh\"} 30.203",
+            "sirang_dial_duration_seconds_count{tunnel=\"ssh\"} 3",
+            "# TYPE sirang_quic_packets_lost_total counter",
+            "sirang_quic_packets_lost_total{tunnel=\"ssh\"} 0",
+            "# TYPE sirang_quic_rtt_seconds gauge",
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "{line} missing from\n{text}"
+            );
+        }
+
+        metrics.remove("ssh");
+        assert!(!metrics.render().contains("tunnel=\"ssh\""));
+    }
+
+    #[tokio::test]
+    async fn test_serve() {
+        let metrics = Metrics::default();
+        metrics.add("ssh", &Monitor::default());
+        let address = metrics.serve("127.0.0.1:0".parse().unwrap()).await.unwrap();
+
+        let get = |path: &'static str| async move {
+            let mut stream = TcpStream::connect(address).await.unwrap();
+            let request = format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        };
+
+        let response = get("/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.contains("\r\n\r\n# HELP sirang_connections "));
+        assert!(response.contains("sirang_reconnects_total{tunnel=\"ssh\"} 0\n"));
+
+        let response = get("/").await;
+        assert!(
+            response.starts_with("HTTP/1.1 404 Not Found\r\n"),
+            "{response}"
+        );
+    }
+}
@@ -0,0 +1,441 @@
+// What a running tunnel reports about itself: counters read through `Monitor::stats`, events
+// broadcast to every subscriber as they happen, and calls into the `Hooks` an embedding
+// application registered. Each tunnel has its own, shared by the clones of its config
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::broadcast,
+};
+
+use crate::{
+    errors::Error,
+    layer::StreamInfo,
+    metadata::Metadata,
+    transport::{QuicStats, Transport},
+};
+
+// Events not picked up by then are dropped for slow subscribers
+const EVENT_CAPACITY: usize = 256;
+
+// Upper bounds of the dial latency buckets, up to the dial timeout
+pub(crate) const DIAL_BUCKETS: [Duration; 12] = [
+    Duration::from_millis(1),
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(25),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(250),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+    Duration::from_millis(2500),
+    Duration::from_secs(5),
+    Duration::from_secs(10),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum Event {
+    // the tunnel accepts connections on this address
+    Listening(SocketAddr),
+    // a quic connection with the peer at this address was established
+    Connected(SocketAddr),
+    // the tunnel stopped serving streams over the connection with this peer
+    Disconnected(SocketAddr),
+    // a stream started carrying a tcp connection with this address
+    StreamOpened(SocketAddr),
+    StreamClosed(SocketAddr),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Stats {
+    // quic connections currently serving streams
+    pub connections: u64,
+    pub active_streams: u64,
+    pub total_streams: u64,
+
+    // streams that couldn't reach their target or ended with an error
+    pub failed_streams: u64,
+
+    // read from tcp connections and sent through the tunnel
+    pub bytes_sent: u64,
+    // received through the tunnel and written to tcp connections
+    pub bytes_received: u64,
+
+    // quic connections or tunnel handshakes with a peer that didn't complete
+    pub handshake_failures: u64,
+    // connections to the remote made again after a GOAWAY
+    pub reconnects: u64,
+}
+
+// Callbacks for the lifecycle of a tunnel's connections and streams. They run on the tunnel's
+// own tasks, so they should return quickly and hand anything slow off elsewhere. Every method
+// does nothing unless overridden
+pub trait Hooks: Send + Sync + 'static {
+    // the tunnel accepts connections on this address
+    fn on_listening(&self, _addr: SocketAddr) {}
+
+    // a quic connection with the peer at this address was established
+    fn on_connected(&self, _peer: SocketAddr) {}
+
+    // the tunnel stopped serving streams over the connection with this peer
+    fn on_disconnected(&self, _peer: SocketAddr) {}
+
+    // a stream over the connection with a peer started carrying a tcp connection, with a client
+    // or a target depending on the end of the tunnel
+    fn on_stream_open(&self, _stream: &StreamInfo) {}
+
+    // called for every accepted stream, also those that failed before they were opened, such as
+    // ones whose target refused the connection
+    fn on_stream_close(&self, _stream: &StreamEnd<'_>) {}
+
+    // the peer asked for a target it isn't allowed to reach, or was refused one itself
+    fn on_auth_failure(&self, _peer: SocketAddr, _error: &Error) {}
+}
+
+// How a stream went, passed to `Hooks::on_stream_close`
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct StreamEnd<'a> {
+    pub peer: SocketAddr,
+    pub tcp_addr: SocketAddr,
+    pub metadata: Metadata,
+    // read from the tcp connection and sent through the tunnel
+    pub bytes_sent: u64,
+    // received through the tunnel and written to the tcp connection
+    pub bytes_received: u64,
+    pub duration: Duration,
+    // why the stream failed, none if both directions ended normally
+    pub error: Option<&'a Error>,
+}
+
+#[derive(Clone)]
+pub struct Monitor {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    events: broadcast::Sender<Event>,
+    connections: AtomicU64,
+    active_streams: AtomicU64,
+    total_streams: AtomicU64,
+    failed_streams: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    handshake_failures: AtomicU64,
+    reconnects: AtomicU64,
+    // failed streams by the kind of their error
+    stream_errors: Mutex<BTreeMap<&'static str, u64>>,
+    dial_latency: Mutex<Histogram>,
+    transport: Transport,
+    hooks: RwLock<Vec<Arc<dyn Hooks>>>,
+}
+
+// Dials that took up to each of `DIAL_BUCKETS`, counted in the first bucket they fit, and those
+// that took longer in the last count
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Histogram {
+    pub counts: [u64; DIAL_BUCKETS.len() + 1],
+    pub sum: Duration,
+}
+
+impl Default for Monitor {
+    fn default() -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                events: broadcast::channel(EVENT_CAPACITY).0,
+                connections: AtomicU64::new(0),
+                active_streams: AtomicU64::new(0),
+                total_streams: AtomicU64::new(0),
+                failed_streams: AtomicU64::new(0),
+                bytes_sent: AtomicU64::new(0),
+                bytes_received: AtomicU64::new(0),
+                handshake_failures: AtomicU64::new(0),
+                reconnects: AtomicU64::new(0),
+                stream_errors: Mutex::new(BTreeMap::new()),
+                dial_latency: Mutex::new(Histogram::default()),
+                transport: Transport::default(),
+                hooks: RwLock::new(Vec::new()),
+            }),
+        }
+    }
+}
+
+impl Monitor {
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.inner.events.subscribe()
+    }
+
+    pub fn stats(&self) -> Stats {
+        let inner = &self.inner;
+        Stats {
+            connections: inner.connections.load(Ordering::Relaxed),
+            active_streams: inner.active_streams.load(Ordering::Relaxed),
+            total_streams: inner.total_streams.load(Ordering::Relaxed),
+            failed_streams: inner.failed_streams.load(Ordering::Relaxed),
+            bytes_sent: inner.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: inner.bytes_received.load(Ordering::Relaxed),
+            handshake_failures: inner.handshake_failures.load(Ordering::Relaxed),
+            reconnects: inner.reconnects.load(Ordering::Relaxed),
+        }
+    }
+
+    // Transport statistics of the open quic connections of the tunnel
+    pub fn quic_stats(&self) -> Vec<QuicStats> {
+        self.inner.transport.connections()
+    }
+
+    pub(crate) fn transport(&self) -> &Transport {
+        &self.inner.transport
+    }
+
+    pub(crate) fn stream_errors(&self) -> BTreeMap<&'static str, u64> {
+        self.inner.stream_errors.lock().unwrap().clone()
+    }
+
+    pub(crate) fn dial_latency(&self) -> Histogram {
+        self.inner.dial_latency.lock().unwrap().clone()
+    }
+
+    fn emit(&self, event: Event) {
+        // no subscribers is fine
+        let _ = self.inner.events.send(event);
+    }
+
+    pub(crate) fn add_hooks(&self, hooks: Arc<dyn Hooks>) {
+        self.inner.hooks.write().unwrap().push(hooks);
+    }
+
+    fn call_hooks(&self, call: impl Fn(&dyn Hooks)) {
+        for hooks in self.inner.hooks.read().unwrap().iter() {
+            call(hooks.as_ref());
+        }
+    }
+
+    pub(crate) fn listening(&self, address: SocketAddr) {
+        self.emit(Event::Listening(address));
+        self.call_hooks(|hooks| hooks.on_listening(address));
+    }
+
+    // Counts the connection until the guard is dropped
+    pub(crate) fn connected(&self, peer: SocketAddr) -> ConnectionGuard {
+        self.inner.connections.fetch_add(1, Ordering::Relaxed);
+        self.emit(Event::Connected(peer));
+        self.call_hooks(|hooks| hooks.on_connected(peer));
+        ConnectionGuard {
+            monitor: self.clone(),
+            peer,
+        }
+    }
+
+    // Counts a stream from when it was accepted until the guard is closed or dropped. `info`
+    // has what is known so far, the hooks only hear of the stream once it is opened
+    pub(crate) fn stream_accepted(&self, info: StreamInfo) -> StreamGuard {
+        self.inner.active_streams.fetch_add(1, Ordering::Relaxed);
+        self.inner.total_streams.fetch_add(1, Ordering::Relaxed);
+        StreamGuard {
+            monitor: self.clone(),
+            info,
+            accepted: Instant::now(),
+            bytes_sent: 0,
+            bytes_received: 0,
+            opened: false,
+            closed: false,
+        }
+    }
+
+    pub(crate) fn stream_failed(&self, error: &Error) {
+        self.inner.failed_streams.fetch_add(1, Ordering::Relaxed);
+        *self
+            .inner
+            .stream_errors
+            .lock()
+            .unwrap()
+            .entry(error.label())
+            .or_default() += 1;
+    }
+
+    // A tcp target of a stream was connected to after `latency`
+    pub(crate) fn dialed(&self, latency: Duration) {
+        let bucket = DIAL_BUCKETS
+            .iter()
+            .position(|bound| latency <= *bound)
+            .unwrap_or(DIAL_BUCKETS.len());
+        let mut histogram = self.inner.dial_latency.lock().unwrap();
+        histogram.counts[bucket] += 1;
+        histogram.sum += latency;
+    }
+
+    pub(crate) fn handshake_failed(&self) {
+        self.inner
+            .handshake_failures
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn reconnected(&self) {
+        self.inner.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn auth_failed(&self, peer: SocketAddr, error: &Error) {
+        self.call_hooks(|hooks| hooks.on_auth_failure(peer, error));
+    }
+}
+
+pub(crate) struct ConnectionGuard {
+    monitor: Monitor,
+    peer: SocketAddr,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.monitor
+            .inner
+            .connections
+            .fetch_sub(1, Ordering::Relaxed);
+        self.monitor.emit(Event::Disconnected(self.peer));
+        self.monitor
+            .call_hooks(|hooks| hooks.on_disconnected(self.peer));
+    }
+}
+
+pub(crate) struct StreamGuard {
+    monitor: Monitor,
+    info: StreamInfo,
+    accepted: Instant,
+    bytes_sent: u64,
+    bytes_received: u64,
+    opened: bool,
+    closed: bool,
+}
+
+impl StreamGuard {
+    pub(crate) fn info(&self) -> &StreamInfo {
+        &self.info
+    }
+
+    // What was learned about the stream since it was accepted, such as the client behind a proxy
+    // or the address of the target
+    pub(crate) fn update(&mut self, update: impl FnOnce(&mut StreamInfo)) {
+        update(&mut self.info);
+    }
+
+    // The stream carries a tcp connection from now on
+    pub(crate) fn open(&mut self) {
+        self.opened = true;
+        self.monitor.emit(Event::StreamOpened(self.info.tcp_addr));
+        self.monitor
+            .call_hooks(|hooks| hooks.on_stream_open(&self.info));
+    }
+
+    // The other end refused the target this end asked for
+    pub(crate) fn auth_failed(&self, error: &Error) {
+        self.monitor.auth_failed(self.info.peer, error);
+    }
+
+    // Reports how the stream ended, dropping the guard without closing it reports no error.
+    // A failed stream is counted by the kind of its error
+    pub(crate) fn close(mut self, error: Option<&Error>) {
+        self.report(error);
+    }
+
+    fn report(&mut self, error: Option<&Error>) {
+        if self.closed {
+            return;
+        }
+        self.closed = true;
+
+        self.monitor
+            .inner
+            .active_streams
+            .fetch_sub(1, Ordering::Relaxed);
+        if let Some(error) = error {
+            self.monitor.stream_failed(error);
+        }
+        if self.opened {
+            self.monitor.emit(Event::StreamClosed(self.info.tcp_addr));
+        }
+
+        let stream = StreamEnd {
+            peer: self.info.peer,
+            tcp_addr: self.info.tcp_addr,
+            metadata: self.info.metadata,
+            bytes_sent: self.bytes_sent,
+            bytes_received: self.bytes_received,
+            duration: self.accepted.elapsed(),
+            error,
+        };
+        self.monitor
+            .call_hooks(|hooks| hooks.on_stream_close(&stream));
+    }
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        self.report(None);
+    }
+}
+
+// A tcp connection whose traffic is added to the stats of the tunnel and the stream as it passes
+pub(crate) struct Counted<'a, T> {
+    pub inner: &'a mut T,
+    pub stream: &'a mut StreamGuard,
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Counted<'_, T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let res = Pin::new(&mut *self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = res {
+            let read = (buf.filled().len() - before) as u64;
+            self.stream.bytes_sent += read;
+            self.stream
+                .monitor
+                .inner
+                .bytes_sent
+                .fetch_add(read, Ordering::Relaxed);
+        }
+        res
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Counted<'_, T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let res = Pin::new(&mut *self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = res {
+            self.stream.bytes_received += written as u64;
+            self.stream
+                .monitor
+                .inner
+                .bytes_received
+                .fetch_add(written as u64, Ordering::Relaxed);
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_shutdown(cx)
+    }
+}
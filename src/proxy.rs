@@ -0,0 +1,420 @@
+// PROXY protocol headers, as understood by HAProxy, nginx and most connection poolers. An end
+// of the tunnel that connects to a tcp target can send one ahead of the tunneled data, so the
+// target sees the address of the client on the other end rather than that of sirang:
+//
+//   v1  PROXY TCP4 203.0.113.7 10.0.0.5 51234 5432\r\n
+//   v2  the binary form, a 16 byte header followed by the addresses
+//
+// A stream without a known client, such as one from connect, sends PROXY UNKNOWN or the v2
+// LOCAL command, so the target uses the address of the connection itself.
+//
+// The other way around, the listeners of a tunnel read the header from connections of trusted
+// load balancers, so the client behind them is the one logged and passed to the other end
+use std::{
+    fmt, io,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    time::Duration,
+};
+
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    net::TcpStream,
+};
+
+use crate::metadata::StreamId;
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+const V2_LOCAL: u8 = 0x20;
+const V2_PROXY: u8 = 0x21;
+const V2_UNSPEC: u8 = 0x00;
+const V2_TCP4: u8 = 0x11;
+const V2_TCP6: u8 = 0x21;
+const V2_UNIQUE_ID: u8 = 0x05;
+
+// The longest v1 header, and how long a trusted proxy has to send one
+const V1_MAX_LEN: usize = 107;
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyProtocol {
+    V1,
+    V2,
+}
+
+impl FromStr for ProxyProtocol {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "v1" => Ok(ProxyProtocol::V1),
+            "v2" => Ok(ProxyProtocol::V2),
+            _ => Err(format!(
+                "unknown PROXY protocol version `{value}`, expected v1 or v2"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ProxyProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProxyProtocol::V1 => write!(f, "v1"),
+            ProxyProtocol::V2 => write!(f, "v2"),
+        }
+    }
+}
+
+impl ProxyProtocol {
+    // The header for a connection from `client` to `server`, an unknown client leaves the
+    // addresses out
+    pub fn header(&self, client: Option<SocketAddr>, server: SocketAddr) -> Vec<u8> {
+        self.header_with_id(client, server, None)
+    }
+
+    // Also names the stream in v2 headers, as the unique id TLV, v1 has no room for it
+    pub fn header_with_id(
+        &self,
+        client: Option<SocketAddr>,
+        server: SocketAddr,
+        id: Option<StreamId>,
+    ) -> Vec<u8> {
+        let addrs = client.map(|client| same_family(client, server));
+
+        match self {
+            ProxyProtocol::V1 => match addrs {
+                Some((client, server)) => format!(
+                    "PROXY {} {} {} {} {}\r\n",
+                    if client.is_ipv4() { "TCP4" } else { "TCP6" },
+                    client.ip(),
+                    server.ip(),
+                    client.port(),
+                    server.port()
+                )
+                .into_bytes(),
+                None => b"PROXY UNKNOWN\r\n".to_vec(),
+            },
+            ProxyProtocol::V2 => {
+                let mut header = V2_SIGNATURE.to_vec();
+                let mut body = Vec::new();
+
+                let family = match addrs {
+                    Some((client, server)) => {
+                        for addr in [client, server] {
+                            match addr.ip() {
+                                IpAddr::V4(ip) => body.extend_from_slice(&ip.octets()),
+                                IpAddr::V6(ip) => body.extend_from_slice(&ip.octets()),
+                            }
+                        }
+                        body.extend_from_slice(&client.port().to_be_bytes());
+                        body.extend_from_slice(&server.port().to_be_bytes());
+
+                        header.push(V2_PROXY);
+                        if client.is_ipv4() {
+                            V2_TCP4
+                        } else {
+                            V2_TCP6
+                        }
+                    }
+                    None => {
+                        header.push(V2_LOCAL);
+                        V2_UNSPEC
+                    }
+                };
+                if let Some(id) = id {
+                    let id = id.to_string();
+                    body.push(V2_UNIQUE_ID);
+                    body.extend_from_slice(&(id.len() as u16).to_be_bytes());
+                    body.extend_from_slice(id.as_bytes());
+                }
+                header.push(family);
+                header.extend_from_slice(&(body.len() as u16).to_be_bytes());
+                header.extend_from_slice(&body);
+
+                header
+            }
+        }
+    }
+}
+
+// A range of addresses, such as 10.0.0.0/8, a single address is a range of its own
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(try_from = "String")]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        // an IPv4 client of a dual stack listener shows up as a mapped IPv6 address
+        let addr = match addr {
+            IpAddr::V6(ip) => ip.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(addr),
+            addr => addr,
+        };
+
+        match (self.addr, addr) {
+            (IpAddr::V4(range), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(range) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(range), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(range) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid address range `{value}`, expected e.g. 10.0.0.0/8");
+
+        let (addr, prefix) = match value.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (value, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().map_err(|_| invalid())?,
+            None => max_prefix,
+        };
+        if prefix > max_prefix {
+            return Err(invalid());
+        }
+
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+// The client of an accepted connection. A connection from a trusted proxy must open with a PROXY
+// header, which names the client unless it is a health check of the proxy itself
+pub(crate) async fn accept(tcp_stream: &mut TcpStream, trusted: &[Cidr]) -> io::Result<SocketAddr> {
+    let peer = tcp_stream.peer_addr()?;
+    if !trusted.iter().any(|cidr| cidr.contains(peer.ip())) {
+        return Ok(peer);
+    }
+
+    let client = tokio::time::timeout(HEADER_TIMEOUT, read_header(tcp_stream))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no PROXY header in time"))??;
+    Ok(client.unwrap_or(peer))
+}
+
+// Reads a v1 or v2 header and nothing after it, None if it names no client
+async fn read_header<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<SocketAddr>> {
+    // even the shortest v1 header, PROXY UNKNOWN, is as long as the v2 signature
+    let mut start = [0; 12];
+    reader.read_exact(&mut start).await?;
+
+    if start == V2_SIGNATURE {
+        let mut fixed = [0; 4];
+        reader.read_exact(&mut fixed).await?;
+        let mut body = vec![0; u16::from_be_bytes([fixed[2], fixed[3]]) as usize];
+        reader.read_exact(&mut body).await?;
+
+        return parse_v2(fixed[0], fixed[1], &body);
+    }
+
+    if !start.starts_with(b"PROXY ") {
+        return Err(invalid_header("missing PROXY header"));
+    }
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() == V1_MAX_LEN {
+            return Err(invalid_header("PROXY header too long"));
+        }
+        line.push(reader.read_u8().await?);
+    }
+
+    parse_v1(&line[..line.len() - 2])
+}
+
+fn parse_v1(line: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line).map_err(|_| invalid_header("invalid PROXY header"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", source, _, port, _] => {
+            let ip = source
+                .parse()
+                .map_err(|_| invalid_header("invalid PROXY address"))?;
+            let port = port
+                .parse()
+                .map_err(|_| invalid_header("invalid PROXY port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid_header("invalid PROXY header")),
+    }
+}
+
+fn parse_v2(version_command: u8, family: u8, body: &[u8]) -> io::Result<Option<SocketAddr>> {
+    if version_command >> 4 != 2 {
+        return Err(invalid_header("unsupported PROXY version"));
+    }
+    match version_command & 0x0f {
+        0 => return Ok(None),
+        1 => {}
+        _ => return Err(invalid_header("unsupported PROXY command")),
+    }
+
+    // only the address family matters, the transport is the proxy's business
+    let client = match family >> 4 {
+        1 if body.len() >= 12 => {
+            let ip: [u8; 4] = body[..4].try_into().unwrap();
+            SocketAddr::new(IpAddr::from(ip), u16::from_be_bytes([body[8], body[9]]))
+        }
+        2 if body.len() >= 36 => {
+            let ip: [u8; 16] = body[..16].try_into().unwrap();
+            SocketAddr::new(IpAddr::from(ip), u16::from_be_bytes([body[32], body[33]]))
+        }
+        1 | 2 => return Err(invalid_header("truncated PROXY addresses")),
+        _ => return Ok(None),
+    };
+
+    Ok(Some(client))
+}
+
+fn invalid_header(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// Both addresses of a header share a family, an IPv4 one is mapped into IPv6 alongside an
+// IPv6 one
+fn same_family(client: SocketAddr, server: SocketAddr) -> (SocketAddr, SocketAddr) {
+    let to_v6 = |addr: SocketAddr| match addr.ip() {
+        IpAddr::V4(ip) => SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), addr.port()),
+        IpAddr::V6(_) => addr,
+    };
+
+    if client.is_ipv4() == server.is_ipv4() {
+        (client, server)
+    } else {
+        (to_v6(client), to_v6(server))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::{read_header, Cidr, ProxyProtocol};
+    use crate::metadata::StreamId;
+
+    #[test]
+    fn test_v1_header() {
+        let client: SocketAddr = "203.0.113.7:51234".parse().unwrap();
+        let server: SocketAddr = "10.0.0.5:5432".parse().unwrap();
+
+        assert_eq!(
+            ProxyProtocol::V1.header(Some(client), server),
+            b"PROXY TCP4 203.0.113.7 10.0.0.5 51234 5432\r\n"
+        );
+        assert_eq!(
+            ProxyProtocol::V1.header(Some(client), "[::1]:80".parse().unwrap()),
+            b"PROXY TCP6 ::ffff:203.0.113.7 ::1 51234 80\r\n"
+        );
+        assert_eq!(ProxyProtocol::V1.header(None, server), b"PROXY UNKNOWN\r\n");
+    }
+
+    #[test]
+    fn test_v2_header() {
+        let client: SocketAddr = "203.0.113.7:51234".parse().unwrap();
+        let server: SocketAddr = "10.0.0.5:5432".parse().unwrap();
+
+        let header = ProxyProtocol::V2.header(Some(client), server);
+        assert_eq!(&header[..12], b"\r\n\r\n\0\r\nQUIT\n");
+        assert_eq!(
+            &header[12..],
+            [0x21, 0x11, 0, 12, 203, 0, 113, 7, 10, 0, 0, 5, 0xc8, 0x22, 0x15, 0x38]
+        );
+
+        let header = ProxyProtocol::V2.header(Some("[2001:db8::1]:443".parse().unwrap()), server);
+        assert_eq!(&header[12..16], [0x21, 0x21, 0, 36]);
+        assert_eq!(header.len(), 16 + 36);
+
+        let header = ProxyProtocol::V2.header(None, server);
+        assert_eq!(&header[12..], [0x20, 0x00, 0, 0]);
+
+        let id = StreamId::generate();
+        let header = ProxyProtocol::V2.header_with_id(Some(client), server, Some(id));
+        assert_eq!(&header[12..16], [0x21, 0x11, 0, 12 + 3 + 16]);
+        assert_eq!(&header[28..31], [0x05, 0, 16]);
+        assert_eq!(&header[31..], id.to_string().as_bytes());
+        assert_eq!(
+            ProxyProtocol::V1.header_with_id(Some(client), server, Some(id)),
+            ProxyProtocol::V1.header(Some(client), server)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_header() {
+        let server: SocketAddr = "10.0.0.5:5432".parse().unwrap();
+
+        for client in ["203.0.113.7:51234", "[2001:db8::1]:443"] {
+            let client: SocketAddr = client.parse().unwrap();
+            for version in [ProxyProtocol::V1, ProxyProtocol::V2] {
+                let mut data = version.header(Some(client), server);
+                data.extend_from_slice(b"payload");
+
+                // the header is read up to the data that follows it
+                let mut reader = data.as_slice();
+                let read = read_header(&mut reader).await.unwrap();
+                assert_eq!(read, Some(client), "{version}");
+                assert_eq!(reader, b"payload", "{version}");
+
+                let unknown = version.header(None, server);
+                assert_eq!(read_header(&mut unknown.as_slice()).await.unwrap(), None);
+            }
+        }
+
+        for invalid in [
+            &b"GET / HTTP/1.1\r\n\r\n"[..],
+            b"PROXY TCP4 nowhere 10.0.0.5 1 2\r\n",
+            b"PROXY TCP4 203.0.113.7 10.0.0.5 51234 5432 and some more to be longer than any valid header could ever get\r\n",
+        ] {
+            assert!(read_header(&mut &invalid[..]).await.is_err());
+        }
+    }
+
+    #[test]
+    fn test_cidr() {
+        let cidr: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert!(cidr.contains("10.20.30.40".parse().unwrap()));
+        assert!(cidr.contains("::ffff:10.0.0.1".parse().unwrap()));
+        assert!(!cidr.contains("11.0.0.1".parse().unwrap()));
+        assert!(!cidr.contains("::1".parse().unwrap()));
+
+        let single: Cidr = "2001:db8::1".parse().unwrap();
+        assert_eq!(single.to_string(), "2001:db8::1/128");
+        assert!(single.contains("2001:db8::1".parse().unwrap()));
+        assert!(!single.contains("2001:db8::2".parse().unwrap()));
+
+        let all: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains("192.0.2.1".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("example.com/8".parse::<Cidr>().is_err());
+    }
+}
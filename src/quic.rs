@@ -1,30 +1,173 @@
-use s2n_quic::{client::Connect, Client, Connection, Server};
-use std::{error::Error, net::SocketAddr};
+use crate::{common::SHUTDOWN_ERROR_CODE, errors::Error, monitor::Monitor};
+use s2n_quic::{
+    client::Connect, provider::connection_id, provider::endpoint_limits::Limiter,
+    provider::io::tokio::Builder as IoBuilder, provider::tls, Client, Connection, Server,
+};
+use std::{
+    future::Future,
+    net::{SocketAddr, UdpSocket},
+    time::Duration,
+};
+
+const CLOSE_SEND_DELAY: Duration = Duration::from_millis(100);
+const BIND_RETRY_DELAY: Duration = Duration::from_millis(50);
+const BIND_RETRIES: u32 = 40;
+
+// ALPN token of the protocol with a command stream for every tunnel and a header on every
+// stream. 0.1.5 and earlier never set one and offer s2n-quic's default instead, so both are
+// advertised and a connection that settles on the default is with one of those: its forward
+// streams are raw from the first byte and no command stream is opened for them
+const PROTOCOL: &[u8] = b"sirang/1";
+const LEGACY_PROTOCOL: &[u8] = b"h3";
 
 pub async fn new_quic_server(
     server_address: SocketAddr,
     tls_cert: &str,
     tls_key: &str,
-) -> Result<Server, Box<dyn Error + Send + Sync + 'static>> {
+    monitor: &Monitor,
+) -> Result<Server, Error> {
+    let bind_error = |e: &dyn std::fmt::Display| {
+        Error::Bind(format!(
+            "unable to start quic server on {server_address}: {e}"
+        ))
+    };
+
+    let socket = bind_udp(server_address).await.map_err(|e| bind_error(&e))?;
+    socket.set_nonblocking(true)?;
+    let io = IoBuilder::default().with_rx_socket(socket)?.build()?;
+
+    let server = Server::builder()
+        .with_io(io)
+        .map_err(|e| bind_error(&e))?
+        .with_event(monitor.transport().subscriber())
+        .map_err(|e| bind_error(&e))?
+        .with_tls(server_tls(tls_cert, tls_key)?)
+        .map_err(|e| Error::Tls(e.to_string()))?
+        .start()
+        .map_err(|e| bind_error(&e))?;
+
+    Ok(server)
+}
+
+// Starts a server on an already bound socket, such as one shared with a previous process
+pub(crate) async fn new_quic_server_with_socket(
+    socket: UdpSocket,
+    connection_ids: impl connection_id::Provider,
+    limiter: impl Limiter,
+    tls_cert: &str,
+    tls_key: &str,
+    monitor: &Monitor,
+) -> Result<Server, Error> {
+    let bind_error = |e: &dyn std::fmt::Display| {
+        Error::Bind(format!(
+            "unable to start quic server on a shared socket: {e}"
+        ))
+    };
+
+    let io = IoBuilder::default().with_rx_socket(socket)?.build()?;
+
     let server = Server::builder()
-        .with_io(server_address)?
-        .with_tls((tls_cert, tls_key))?
-        .start()?;
+        .with_io(io)
+        .map_err(|e| bind_error(&e))?
+        .with_connection_id(connection_ids)
+        .map_err(|e| bind_error(&e))?
+        .with_endpoint_limits(limiter)
+        .map_err(|e| bind_error(&e))?
+        .with_event(monitor.transport().subscriber())
+        .map_err(|e| bind_error(&e))?
+        .with_tls(server_tls(tls_cert, tls_key)?)
+        .map_err(|e| Error::Tls(e.to_string()))?
+        .start()
+        .map_err(|e| bind_error(&e))?;
 
     Ok(server)
 }
 
+// A tunnel restarted by a reload can get here before the endpoint of its previous instance has
+// released the address, which s2n-quic does from a task of its own once the server is dropped
+async fn bind_udp(address: SocketAddr) -> std::io::Result<UdpSocket> {
+    let mut retries = BIND_RETRIES;
+    loop {
+        match UdpSocket::bind(address) {
+            Err(e) if e.kind() == std::io::ErrorKind::AddrInUse && retries > 0 => {
+                retries -= 1;
+                tokio::time::sleep(BIND_RETRY_DELAY).await;
+            }
+            res => return res,
+        }
+    }
+}
+
+fn server_tls(tls_cert: &str, tls_key: &str) -> Result<tls::default::Server, Error> {
+    tls::default::Server::builder()
+        .with_certificate(tls_cert, tls_key)
+        .and_then(|tls| tls.with_application_protocols([PROTOCOL, LEGACY_PROTOCOL]))
+        .and_then(|tls| tls.build())
+        .map_err(|e| Error::Tls(e.to_string()))
+}
+
+// Whether the other end is 0.1.5 or earlier, which only speaks the original protocol
+pub(crate) fn legacy_peer(connection: &Connection) -> bool {
+    connection
+        .application_protocol()
+        .map_or(true, |protocol| protocol.as_ref() != PROTOCOL)
+}
+
 pub async fn new_quic_connection(
     remote_addr: SocketAddr,
     tls_cert: &str,
-) -> Result<Connection, Box<dyn Error + Send + Sync + 'static>> {
+    monitor: &Monitor,
+) -> Result<Connection, Error> {
+    let bind_error =
+        |e: &dyn std::fmt::Display| Error::Bind(format!("unable to start quic client: {e}"));
+
+    let tls = tls::default::Client::builder()
+        .with_certificate(tls_cert)
+        .and_then(|tls| tls.with_application_protocols([PROTOCOL, LEGACY_PROTOCOL]))
+        .and_then(|tls| tls.build())
+        .map_err(|e| Error::Tls(e.to_string()))?;
+
     let quic_client = Client::builder()
-        .with_tls(tls_cert)?
-        .with_io("0.0.0.0:0")?
-        .start()?;
+        .with_tls(tls)
+        .map_err(|e| Error::Tls(e.to_string()))?
+        .with_io("0.0.0.0:0")
+        .map_err(|e| bind_error(&e))?
+        .with_event(monitor.transport().subscriber())
+        .map_err(|e| bind_error(&e))?
+        .start()
+        .map_err(|e| bind_error(&e))?;
 
     let connection = Connect::new(remote_addr).with_server_name(remote_addr.ip().to_string());
-    let conn = quic_client.connect(connection).await?;
+    let conn = quic_client.connect(connection).await.map_err(|e| match e {
+        // the remote turned the connection away and said why
+        s2n_quic::connection::Error::Application { .. } => Error::from(e),
+        _ => Error::Handshake(format!("unable to connect to {remote_addr}: {e}")),
+    })?;
 
     Ok(conn)
 }
+
+// Closes a connection that failed with the error's code, so the peer learns why. The close is
+// sent in the background, so it is given a moment to go out before the caller gives up and exits
+pub(crate) async fn close_with_error(connection: &Connection, error: &Error) {
+    connection.close(error.into());
+    tokio::time::sleep(CLOSE_SEND_DELAY).await;
+}
+
+// Resolves once the peer closes the connection, streams it opens in the meantime are dropped
+pub(crate) async fn wait_for_close(connection: &mut Connection) {
+    while let Ok(Some(_)) = connection.accept_bidirectional_stream().await {}
+}
+
+// Closes new connections with the shutdown code until `until` resolves, so clients
+// reconnecting to a draining instance fail fast instead of waiting on it
+pub(crate) async fn reject_connections<F: Future>(server: &mut Server, until: F) {
+    tokio::pin!(until);
+
+    loop {
+        tokio::select! {
+            Some(connection) = server.accept() => connection.close(SHUTDOWN_ERROR_CODE.into()),
+            _ = &mut until => break,
+        }
+    }
+}
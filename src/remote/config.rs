@@ -1,6 +1,14 @@
-use std::{net::SocketAddr, str::FromStr};
+use std::{net::SocketAddr, path::PathBuf, str::FromStr, time::Duration};
 
-use crate::common::{TunnelType, DEFAULT_BUFSIZE};
+use crate::{
+    common::{proto::ProtoCommand, TunnelType, DEFAULT_BUFSIZE, DEFAULT_DRAIN_TIMEOUT},
+    compress::{self, Compression},
+    errors::Error,
+    layer::Layers,
+    monitor::Monitor,
+    proxy::{Cidr, ProxyProtocol},
+    shutdown::StopSignal,
+};
 
 #[derive(Clone)]
 pub struct RemoteConfig {
@@ -9,6 +17,13 @@ pub struct RemoteConfig {
     // only used for the forward tunnel
     pub tcp_forward_address: Option<SocketAddr>,
 
+    // only used for the forward tunnel, extra host:port targets clients may ask for
+    pub allowed_targets: Vec<String>,
+
+    // only used for the forward tunnel, compression clients are asked to use for streams to
+    // some of the allowed targets, None for none
+    pub target_compression: Vec<(String, Option<Compression>)>,
+
     // only used for the reverse tunnel
     pub tcp_reverse_address: Option<SocketAddr>,
 
@@ -16,6 +31,33 @@ pub struct RemoteConfig {
     pub tls_cert: String,
     pub tls_key: String,
     pub buffer_size: usize,
+    pub drain_timeout: Duration,
+
+    // when set, clients are told to reconnect after this delay on shutdown instead of closing
+    pub goaway_delay: Option<Duration>,
+    pub redirect_address: Option<SocketAddr>,
+
+    // unix socket on which listening sockets are handed over to a newer process
+    pub handover_path: Option<PathBuf>,
+
+    // resolves when the tunnel should shut down
+    pub stop: StopSignal,
+
+    // stats and events of the running tunnel
+    pub monitor: Monitor,
+
+    // middleware wrapping every stream of the tunnel
+    pub layers: Layers,
+
+    // compresses the streams with this when the other end supports it
+    pub compression: Option<Compression>,
+
+    // sends a PROXY protocol header with the client's address on every connection to the target
+    pub proxy_protocol: Option<ProxyProtocol>,
+
+    // reverse: connections from these addresses start with a PROXY protocol header naming the
+    // real client. forward: local instances at these addresses are believed about their clients
+    pub trusted_proxies: Vec<Cidr>,
 }
 
 impl RemoteConfig {
@@ -25,22 +67,91 @@ impl RemoteConfig {
                 tunnel_type: TunnelType::Forward,
 
                 tcp_forward_address: None,
+                allowed_targets: Vec::new(),
+                target_compression: Vec::new(),
                 tcp_reverse_address: None,
                 quic_address: SocketAddr::from_str("0.0.0.0:4433").unwrap(),
                 tls_cert: String::new(),
                 tls_key: String::new(),
                 buffer_size: DEFAULT_BUFSIZE,
+                drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+                goaway_delay: None,
+                redirect_address: None,
+                handover_path: None,
+                stop: StopSignal::default(),
+                monitor: Monitor::default(),
+                layers: Layers::default(),
+                compression: None,
+                proxy_protocol: None,
+                trusted_proxies: Vec::new(),
             },
 
             TunnelType::Reverse => Self {
                 tunnel_type: TunnelType::Reverse,
                 tcp_forward_address: None,
+                allowed_targets: Vec::new(),
+                target_compression: Vec::new(),
                 tcp_reverse_address: Some(SocketAddr::from_str("0.0.0.0:5000").unwrap()),
                 quic_address: SocketAddr::from_str("0.0.0.0:4433").unwrap(),
                 tls_key: String::new(),
                 tls_cert: String::new(),
                 buffer_size: DEFAULT_BUFSIZE,
+                drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+                goaway_delay: None,
+                redirect_address: None,
+                handover_path: None,
+                stop: StopSignal::default(),
+                monitor: Monitor::default(),
+                layers: Layers::default(),
+                compression: None,
+                proxy_protocol: None,
+                trusted_proxies: Vec::new(),
             },
         }
     }
+
+    // Adds targets clients may ask for, each host:port optionally followed by
+    // `,compress=<zstd|lz4|none>` for the compression streams to it should use
+    pub fn allow_targets<'a>(
+        &mut self,
+        targets: impl IntoIterator<Item = &'a String>,
+    ) -> Result<(), Error> {
+        for value in targets {
+            let Some((target, option)) = value.split_once(',') else {
+                self.allowed_targets.push(value.clone());
+                continue;
+            };
+            let compression = option
+                .strip_prefix("compress=")
+                .ok_or_else(|| format!("unknown option `{option}` for target {target}"))
+                .and_then(compress::parse_choice)
+                .map_err(Error::Config)?;
+
+            self.allowed_targets.push(target.to_string());
+            self.target_compression
+                .push((target.to_string(), compression));
+        }
+
+        Ok(())
+    }
+
+    // Command sent to connected clients when this instance shuts down
+    pub fn shutdown_command(&self) -> ProtoCommand {
+        match (self.goaway_delay, self.redirect_address) {
+            (None, None) => ProtoCommand::CLOSED,
+            (delay, redirect_address) => ProtoCommand::GOAWAY(
+                delay.map(|delay| delay.as_secs()).unwrap_or(0),
+                redirect_address,
+            ),
+        }
+    }
+
+    // Command sent to connected clients once a newer process has taken over the sockets,
+    // they reconnect right away unless a delay or another address was configured
+    pub fn handover_command(&self) -> ProtoCommand {
+        ProtoCommand::GOAWAY(
+            self.goaway_delay.map(|delay| delay.as_secs()).unwrap_or(0),
+            self.redirect_address,
+        )
+    }
 }
@@ -1,92 +1,489 @@
-use super::config::RemoteConfig;
-use crate::quic;
-use std::error::Error;
-use std::net::SocketAddr;
-use tokio::net::TcpStream;
+use super::{config::RemoteConfig, ShutdownReason};
+use crate::{
+    common::{
+        proto::{ProtoCommand, StreamHeader},
+        SHUTDOWN_ERROR_CODE,
+    },
+    compress::Offer,
+    errors::Error,
+    handover::Handover,
+    layer::StreamInfo,
+    metadata::{self, Metadata},
+    quic,
+    shutdown::{Tracker, TrackerGuard},
+    stream,
+};
+use bytes::Bytes;
+use s2n_quic::stream::{Error as StreamError, ReceiveStream, SendStream};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::Instrument;
 
-pub async fn forward_remote(
-    config: RemoteConfig,
-) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
-    let server = setup_quic_server(&config).await?;
+pub async fn forward_remote(config: RemoteConfig) -> Result<(), Error> {
+    let (mut server, handover) = setup_quic_server(&config).await?;
+    let (shutdown_tx, shutdown_rx) = watch::channel(None);
+    let active_connections = Tracker::default();
+    let stop = config.stop.clone();
+
+    let reason = tokio::select! {
+        res = handle_incoming_connections(&mut server, config, shutdown_rx, active_connections.clone()) => {
+            res?;
+            ShutdownReason::Signal
+        }
+        reason = super::wait_for_shutdown(&stop, handover.as_ref()) => reason,
+    };
 
-    handle_incoming_connections(server, config).await
+    // every connection drains its own streams within the drain timeout before closing
+    let _ = shutdown_tx.send(Some(reason));
+    quic::reject_connections(&mut server, active_connections.wait(Duration::MAX)).await;
+
+    Ok(())
 }
 
 async fn setup_quic_server(
     config: &RemoteConfig,
-) -> Result<s2n_quic::Server, Box<dyn Error + Send + Sync + 'static>> {
-    let server =
-        quic::new_quic_server(config.quic_address, &config.tls_cert, &config.tls_key).await?;
+) -> Result<(s2n_quic::Server, Option<Handover>), Error> {
+    let (server, handover) = super::setup_quic_server(config).await?;
 
-    log::info!(
+    tracing::info!(
         "Quic server started at: {} with buffer size: {}",
         config.quic_address,
         config.buffer_size
     );
+    config
+        .monitor
+        .listening(server.local_addr().unwrap_or(config.quic_address));
 
-    Ok(server)
+    Ok((server, handover))
 }
 
 async fn handle_incoming_connections(
-    mut server: s2n_quic::Server,
+    server: &mut s2n_quic::Server,
     config: RemoteConfig,
-) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    shutdown_rx: watch::Receiver<Option<ShutdownReason>>,
+    active_connections: Tracker,
+) -> Result<(), Error> {
     while let Some(connection) = server.accept().await {
-        spawn_connection_handler(connection, config.clone());
+        spawn_connection_handler(
+            connection,
+            config.clone(),
+            shutdown_rx.clone(),
+            active_connections.track(),
+        );
     }
     Ok(())
 }
 
-fn spawn_connection_handler(mut connection: s2n_quic::Connection, config: RemoteConfig) {
-    tokio::spawn(async move {
-        while let Ok(Some(quic_stream)) = connection.accept_bidirectional_stream().await {
-            if let Ok(remote_addr) = connection.remote_addr() {
-                handle_stream(
-                    quic_stream,
-                    remote_addr,
-                    config.tcp_forward_address.unwrap(),
-                    config.buffer_size,
-                )
-                .await;
+fn spawn_connection_handler(
+    connection: s2n_quic::Connection,
+    config: RemoteConfig,
+    shutdown_rx: watch::Receiver<Option<ShutdownReason>>,
+    guard: TrackerGuard,
+) {
+    let span = tracing::info_span!("connection", peer = tracing::field::Empty);
+    tokio::spawn(
+        async move {
+            let _guard = guard;
+            handle_connection(connection, config, shutdown_rx).await;
+        }
+        .instrument(span),
+    );
+}
+
+async fn handle_connection(
+    mut connection: s2n_quic::Connection,
+    config: RemoteConfig,
+    mut shutdown_rx: watch::Receiver<Option<ShutdownReason>>,
+) {
+    let remote_addr = match connection.remote_addr() {
+        Ok(remote_addr) => remote_addr,
+        Err(_) => return,
+    };
+    tracing::Span::current().record("peer", tracing::field::display(remote_addr));
+
+    // the first stream a local instance opens is its command stream. Locals of 0.1.5 and
+    // earlier have none, every stream they open is a client's
+    let legacy = quic::legacy_peer(&connection);
+    let (mut command_receiver, mut command_sender) = if legacy {
+        tracing::info!("Local instance at {remote_addr} speaks the original protocol");
+        (None, None)
+    } else {
+        match connection.accept_bidirectional_stream().await {
+            Ok(Some(command_stream)) => {
+                let (command_receiver, command_sender) = command_stream.split();
+                (Some(command_receiver), Some(command_sender))
+            }
+            _ => {
+                config.monitor.handshake_failed();
+                return;
             }
         }
-    });
+    };
+
+    let active_streams = Tracker::default();
+    let _connection = config.monitor.connected(remote_addr);
+
+    loop {
+        tokio::select! {
+            quic_stream = connection.accept_bidirectional_stream() => match quic_stream {
+                Ok(Some(quic_stream)) => {
+                    // the id comes with the header
+                    let span = tracing::info_span!("stream", stream_id = tracing::field::Empty);
+                    tokio::spawn(
+                        handle_stream(
+                            quic_stream,
+                            remote_addr,
+                            config.clone(),
+                            legacy,
+                            active_streams.track(),
+                        )
+                        .instrument(span),
+                    );
+                }
+                _ => break,
+            },
+            Some(Ok(Some(cmd_data))) = receive_command(command_receiver.as_mut()) => {
+                match ProtoCommand::serialize(cmd_data) {
+                    Some(ProtoCommand::CLOSED) => {
+                        tracing::debug!(
+                            "Local tunnel instance at {remote_addr} is closing the connection"
+                        );
+                    }
+                    // the local waits for the reply before it opens any stream
+                    Some(ProtoCommand::COMPRESS(_)) => {
+                        let reply =
+                            ProtoCommand::COMPRESS(Offer::new(config.target_compression.clone()));
+                        let reply = send_command(command_sender.as_mut(), reply.deserialize());
+                        if let Some(Err(e)) = reply.await {
+                            tracing::warn!(
+                                "Could not send supported compressions to {remote_addr}: {e}"
+                            );
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            _ = shutdown_rx.changed() => {
+                let shutdown_command = match &*shutdown_rx.borrow() {
+                    Some(reason) => reason.command(&config),
+                    None => ProtoCommand::CLOSED,
+                };
+                let command = shutdown_command.deserialize();
+                if let Some(Err(e)) = send_command(command_sender.as_mut(), command).await {
+                    tracing::warn!(
+                        "Could not send {shutdown_command:?} to local forward tunnel instance: {e}"
+                    );
+                }
+                break;
+            }
+        }
+    }
+
+    // older locals can't be told to close, so theirs is closed once its streams are done
+    if legacy {
+        active_streams.drain(config.drain_timeout).await;
+        connection.close(SHUTDOWN_ERROR_CODE.into());
+        return;
+    }
+
+    let reason = shutdown_rx.borrow().clone();
+    super::drain_connection(connection, active_streams, &config, reason).await;
+}
+
+// Resolves to None straight away without a command stream
+async fn receive_command(
+    command_receiver: Option<&mut ReceiveStream>,
+) -> Option<Result<Option<Bytes>, StreamError>> {
+    Some(command_receiver?.receive().await)
+}
+
+async fn send_command(
+    command_sender: Option<&mut SendStream>,
+    command: Bytes,
+) -> Option<Result<(), StreamError>> {
+    Some(command_sender?.send(command).await)
 }
 
 async fn handle_stream(
-    quic_stream: s2n_quic::stream::BidirectionalStream,
+    mut quic_stream: s2n_quic::stream::BidirectionalStream,
     remote_quic_addr: SocketAddr,
-    tcp_forward_addr: SocketAddr,
-    buffer_size: usize,
+    config: RemoteConfig,
+    legacy: bool,
+    _guard: TrackerGuard,
 ) {
-    log::debug!("Stream received from {remote_quic_addr}");
+    tracing::debug!("Stream received from {remote_quic_addr}");
+
+    // the target is known once it is dialed
+    let mut accepted = config.monitor.stream_accepted(StreamInfo::new(
+        remote_quic_addr,
+        SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        Metadata::new(None, None),
+    ));
+    // streams of older locals carry nothing but the client's bytes
+    let header = if legacy {
+        Ok(StreamHeader::default())
+    } else {
+        stream::read_header(&mut quic_stream).await
+    };
+    let header = match header {
+        Ok(header) => header,
+        Err(e) => {
+            tracing::warn!("Error reading stream header from {remote_quic_addr}: {e}");
+            accepted.close(Some(&e));
+            return;
+        }
+    };
+
+    let compression = header.compression;
+    let metadata = trusted_metadata(
+        header.metadata.unwrap_or_else(|| Metadata::new(None, None)),
+        remote_quic_addr,
+        &config,
+    );
+    accepted.update(|info| info.metadata = metadata);
+    tracing::Span::current().record("stream_id", tracing::field::display(metadata.id));
+    tracing::info!(
+        "Stream {metadata} accepted by {remote_quic_addr} at {}ms",
+        metadata::unix_millis(metadata.accepted)
+    );
+    let target = match resolve_target(header, &config) {
+        Ok(target) => target,
+        Err(e) => {
+            tracing::warn!(
+                "Stream {} from {remote_quic_addr} rejected: {e}",
+                metadata.id
+            );
+            stream::reset(&mut quic_stream, &e);
+            config.monitor.auth_failed(remote_quic_addr, &e);
+            accepted.close(Some(&e));
+            return;
+        }
+    };
 
-    let tcp_stream = match TcpStream::connect(tcp_forward_addr).await {
-        Ok(stream) => stream,
+    let mut tcp_stream = match stream::dial(&target, &mut quic_stream, &config.monitor).await {
+        Ok(tcp_stream) => tcp_stream,
         Err(e) => {
-            log::warn!("Error connecting to the remote tcp address: {e}");
+            tracing::warn!("Stream {} failed: {e}", metadata.id);
+            accepted.close(Some(&e));
             return;
         }
     };
+    accepted.update(|info| info.tcp_addr = stream::peer_addr(&tcp_stream));
+    if let Some(version) = config.proxy_protocol {
+        if let Err(e) =
+            stream::send_proxy_header(&mut tcp_stream, &mut quic_stream, version, &metadata).await
+        {
+            tracing::warn!("Stream {} to {target} failed: {e}", metadata.id);
+            accepted.close(Some(&e));
+            return;
+        }
+    }
 
-    spawn_copy_handler(quic_stream, tcp_stream, buffer_size);
+    if let Err(e) = stream::copy(
+        &mut tcp_stream,
+        &mut quic_stream,
+        config.buffer_size,
+        accepted,
+        &config.layers,
+        compression,
+    )
+    .await
+    {
+        tracing::warn!("Stream {} to {target} failed: {e}", metadata.id);
+    }
 }
 
-fn spawn_copy_handler(
-    mut quic_stream: s2n_quic::stream::BidirectionalStream,
-    mut tcp_stream: TcpStream,
-    buffer_size: usize,
-) {
-    tokio::spawn(async move {
-        if let Err(e) = tokio::io::copy_bidirectional_with_sizes(
-            &mut tcp_stream,
-            &mut quic_stream,
-            buffer_size,
-            buffer_size,
+// Local instances don't authenticate, so only those at trusted addresses are believed about the
+// client of a stream. For any other the stream is taken to be from the local instance itself
+fn trusted_metadata(
+    mut metadata: Metadata,
+    remote_quic_addr: SocketAddr,
+    config: &RemoteConfig,
+) -> Metadata {
+    let trusted = config
+        .trusted_proxies
+        .iter()
+        .any(|cidr| cidr.contains(remote_quic_addr.ip()));
+    if trusted {
+        return metadata;
+    }
+
+    if let Some(client) = metadata.client {
+        tracing::info!(
+            "Stream {} from {remote_quic_addr} claims untrusted client {client}",
+            metadata.id
+        );
+    }
+    metadata.client = Some(remote_quic_addr);
+    metadata.listener = None;
+    metadata
+}
+
+// Picks the address to dial for a stream, only targets the remote was configured with are allowed
+fn resolve_target(header: StreamHeader, config: &RemoteConfig) -> Result<String, Error> {
+    let forward_addr = config.tcp_forward_address.unwrap().to_string();
+
+    match header.target {
+        None => Ok(forward_addr),
+        Some(target) if target == forward_addr || config.allowed_targets.contains(&target) => {
+            Ok(target)
+        }
+        Some(target) => Err(Error::Auth(format!(
+            "target {target} is not an allowed target"
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::{
+        net::{SocketAddr, UdpSocket},
+        str::FromStr,
+        time::Duration,
+    };
+
+    use crate::{
+        common::{proto::StreamHeader, TunnelType},
+        errors::code,
+        monitor::Monitor,
+        quic::new_quic_connection,
+        remote::{self, config::RemoteConfig},
+        Error,
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    async fn spawn_echo_server() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = stream.split();
+                    let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                });
+            }
+        });
+
+        addr
+    }
+
+    fn spawn_forward_remote(forward_addr: SocketAddr, allowed_targets: Vec<String>) -> SocketAddr {
+        let quic_address = UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let mut config = RemoteConfig::new(&TunnelType::Forward);
+        config.quic_address = quic_address;
+        config.tcp_forward_address = Some(forward_addr);
+        config.allowed_targets = allowed_targets;
+        config.tls_cert = include_str!("../../test_cert.pem").to_string();
+        config.tls_key = include_str!("../../test_key.pem").to_string();
+
+        tokio::spawn(remote::start_remote(config));
+
+        quic_address
+    }
+
+    async fn echo_through(quic_address: SocketAddr, header: StreamHeader) -> Vec<u8> {
+        let mut conn = new_quic_connection(
+            quic_address,
+            include_str!("../../test_cert.pem"),
+            &Monitor::default(),
         )
         .await
-        {
-            log::warn!("Error while bidirectional copy: {e}");
-        }
-    });
+        .unwrap();
+        let _command_stream = conn.open_bidirectional_stream().await.unwrap();
+        let mut stream = conn.open_bidirectional_stream().await.unwrap();
+
+        header.write_to(&mut stream).await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        stream.shutdown().await.unwrap();
+
+        let mut response = Vec::new();
+        let _ = tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+            .await
+            .unwrap();
+
+        response
+    }
+
+    // The error a stream through the tunnel fails with
+    async fn stream_error(quic_address: SocketAddr, header: StreamHeader) -> Error {
+        let mut conn = new_quic_connection(
+            quic_address,
+            include_str!("../../test_cert.pem"),
+            &Monitor::default(),
+        )
+        .await
+        .unwrap();
+        let _command_stream = conn.open_bidirectional_stream().await.unwrap();
+        let mut stream = conn.open_bidirectional_stream().await.unwrap();
+
+        header.write_to(&mut stream).await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+
+        let mut response = Vec::new();
+        let res = tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+            .await
+            .unwrap();
+
+        Error::from(res.unwrap_err())
+    }
+
+    #[tokio::test]
+    async fn test_forward_default_target() {
+        let echo_addr = spawn_echo_server().await;
+        let quic_address = spawn_forward_remote(echo_addr, Vec::new());
+
+        assert_eq!(
+            echo_through(quic_address, StreamHeader::default()).await,
+            b"ping"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_forward_allowed_target() {
+        let forward_addr = SocketAddr::from_str("127.0.0.1:1").unwrap();
+        let echo_addr = spawn_echo_server().await;
+        let quic_address = spawn_forward_remote(forward_addr, vec![echo_addr.to_string()]);
+
+        let header = StreamHeader {
+            target: Some(echo_addr.to_string()),
+            ..StreamHeader::default()
+        };
+        assert_eq!(echo_through(quic_address, header).await, b"ping");
+    }
+
+    #[tokio::test]
+    async fn test_forward_disallowed_target() {
+        let forward_addr = SocketAddr::from_str("127.0.0.1:1").unwrap();
+        let echo_addr = spawn_echo_server().await;
+        let quic_address = spawn_forward_remote(forward_addr, Vec::new());
+
+        let header = StreamHeader {
+            target: Some(echo_addr.to_string()),
+            ..StreamHeader::default()
+        };
+        let error = stream_error(quic_address, header).await;
+        assert_eq!(error.code(), code::AUTH, "{error}");
+    }
+
+    #[tokio::test]
+    async fn test_forward_refused_target() {
+        // nothing listens on the port once the listener is gone
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let forward_addr = listener.local_addr().unwrap();
+        drop(listener);
+        let quic_address = spawn_forward_remote(forward_addr, Vec::new());
+
+        let error = stream_error(quic_address, StreamHeader::default()).await;
+        assert!(matches!(error, Error::Refused(_)), "{error}");
+    }
 }
@@ -1,16 +1,107 @@
 pub mod config;
 mod forward;
 mod reverse;
-use crate::common::TunnelType;
-use std::error::Error;
+use crate::{
+    common::{proto::ProtoCommand, TunnelType, SHUTDOWN_ERROR_CODE},
+    errors::Error,
+    handover::{self, Handover},
+    quic,
+    shutdown::{self, StopSignal},
+};
+use std::time::Duration;
 
 use config::RemoteConfig;
 
-pub async fn start_remote(
-    config: RemoteConfig,
-) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+const GOAWAY_CLOSE_DELAY: Duration = Duration::from_secs(1);
+
+pub async fn start_remote(config: RemoteConfig) -> Result<(), Error> {
     match config.tunnel_type {
         TunnelType::Forward => forward::forward_remote(config).await,
         TunnelType::Reverse => reverse::reverse_remote(config).await,
     }
 }
+
+// Starts the quic server, on the sockets of a previous process when handing over is configured
+async fn setup_quic_server(
+    config: &RemoteConfig,
+) -> Result<(s2n_quic::Server, Option<Handover>), Error> {
+    let handover_path = match &config.handover_path {
+        Some(handover_path) => handover_path,
+        None => {
+            let server = quic::new_quic_server(
+                config.quic_address,
+                &config.tls_cert,
+                &config.tls_key,
+                &config.monitor,
+            )
+            .await?;
+            return Ok((server, None));
+        }
+    };
+
+    let handover = Handover::start(handover_path, config.quic_address)?;
+    let server = quic::new_quic_server_with_socket(
+        handover.quic_socket()?,
+        handover.connection_ids(),
+        handover.limiter(),
+        &config.tls_cert,
+        &config.tls_key,
+        &config.monitor,
+    )
+    .await?;
+
+    Ok((server, Some(handover)))
+}
+
+// Why this instance stopped serving new streams
+#[derive(Clone, Debug, PartialEq)]
+enum ShutdownReason {
+    Signal,
+    HandedOver,
+}
+
+impl ShutdownReason {
+    // Command connected clients are sent
+    fn command(&self, config: &RemoteConfig) -> ProtoCommand {
+        match self {
+            ShutdownReason::Signal => config.shutdown_command(),
+            ShutdownReason::HandedOver => config.handover_command(),
+        }
+    }
+}
+
+async fn wait_for_shutdown(stop: &StopSignal, handover: Option<&Handover>) -> ShutdownReason {
+    tokio::select! {
+        _ = stop.wait() => {
+            tracing::info!("Shutting down, closing connections...");
+            ShutdownReason::Signal
+        }
+        _ = handover::wait_for_takeover(handover) => {
+            tracing::info!("Listening sockets handed over to a new process, draining connections...");
+            ShutdownReason::HandedOver
+        }
+    }
+}
+
+// Drains a connection this instance is shutting down. Clients told to go away wait for it
+// to close the connection once its streams are done. Otherwise the client is given until the
+// drain deadline to close it
+async fn drain_connection(
+    quic_conn: s2n_quic::Connection,
+    active_streams: shutdown::Tracker,
+    config: &RemoteConfig,
+    reason: Option<ShutdownReason>,
+) {
+    match reason.map(|reason| reason.command(config)) {
+        Some(ProtoCommand::GOAWAY(..)) => {
+            active_streams.drain(config.drain_timeout).await;
+            // the client's side of the last streams may still be finishing when ours are done
+            tokio::time::sleep(GOAWAY_CLOSE_DELAY).await;
+            quic_conn.close(SHUTDOWN_ERROR_CODE.into());
+        }
+        _ => {
+            shutdown::drain_and_wait_for_close(quic_conn, active_streams, config.drain_timeout)
+                .await
+        }
+    }
+}
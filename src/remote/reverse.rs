@@ -1,14 +1,26 @@
-use super::config::RemoteConfig;
-use crate::{common::proto, quic};
+use super::{config::RemoteConfig, ShutdownReason};
+use crate::{
+    common::proto::{self, StreamHeader},
+    compress,
+    errors::Error,
+    handover::{Handover, TcpListenerSlot},
+    layer::StreamInfo,
+    metadata::Metadata,
+    quic,
+    shutdown::{self, StopSignal, Tracker},
+    stream,
+};
 use s2n_quic::stream::BidirectionalStream;
-use std::{error::Error, sync::Arc};
+use std::{net::SocketAddr, sync::Arc};
 use tokio::{
-    net::TcpListener,
+    net::{TcpListener, TcpStream},
     sync::{
         mpsc::{self, channel, Sender},
-        Mutex,
+        watch, Mutex,
     },
+    task::JoinHandle,
 };
+use tracing::Instrument;
 
 #[derive(Debug)]
 enum CloseAction {
@@ -16,46 +28,93 @@ enum CloseAction {
     CloseStream,
 }
 
-pub async fn reverse_remote(
-    config: RemoteConfig,
-) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
-    let mut quic_srv = setup_quic_server(&config).await?;
-    let (global_shutdown_tx, mut global_shutdown_rx) = setup_global_shutdown();
+pub async fn reverse_remote(config: RemoteConfig) -> Result<(), Error> {
+    let (mut quic_srv, handover) = setup_quic_server(&config).await?;
+    let tcp_listeners = handover
+        .as_ref()
+        .map(Handover::tcp_listeners)
+        .unwrap_or_default();
+    let shutdown_rx = spawn_shutdown_watcher(config.stop.clone(), handover);
+    let (global_shutdown_tx, mut global_shutdown_rx) = setup_global_shutdown(shutdown_rx.clone());
 
-    handle_connections(
+    let drained = handle_connections(
         &mut quic_srv,
         config,
+        &tcp_listeners,
+        shutdown_rx,
         global_shutdown_tx,
         &mut global_shutdown_rx,
     )
-    .await
+    .await?;
+
+    // keep turning away new clients until the last one has drained
+    if let Some(drained) = drained {
+        quic::reject_connections(&mut quic_srv, drained).await;
+    }
+
+    Ok(())
 }
 
 async fn setup_quic_server(
     config: &RemoteConfig,
-) -> Result<s2n_quic::Server, Box<dyn Error + Send + Sync + 'static>> {
-    let quic_srv =
-        quic::new_quic_server(config.quic_address, &config.tls_cert, &config.tls_key).await?;
-
-    log::info!("Quic Server started on: {}", config.quic_address);
-    log::info!(
+) -> Result<(s2n_quic::Server, Option<Handover>), Error> {
+    let (quic_srv, handover) = super::setup_quic_server(config).await?;
+
+    tracing::info!("Quic Server started on: {}", config.quic_address);
+    config
+        .monitor
+        .listening(quic_srv.local_addr().unwrap_or(config.quic_address));
+    tracing::info!(
         "Tcp Server listening on: {}",
         config.tcp_reverse_address.unwrap()
     );
 
-    Ok(quic_srv)
+    Ok((quic_srv, handover))
+}
+
+// Publishes why this instance stopped serving, once it does
+fn spawn_shutdown_watcher(
+    stop: StopSignal,
+    handover: Option<Handover>,
+) -> watch::Receiver<Option<ShutdownReason>> {
+    let (shutdown_tx, shutdown_rx) = watch::channel(None);
+
+    tokio::spawn(
+        async move {
+            let reason = super::wait_for_shutdown(&stop, handover.as_ref()).await;
+            let _ = shutdown_tx.send(Some(reason));
+        }
+        .in_current_span(),
+    );
+
+    shutdown_rx
+}
+
+async fn wait_for_shutdown_reason(
+    shutdown_rx: &mut watch::Receiver<Option<ShutdownReason>>,
+) -> Option<ShutdownReason> {
+    shutdown_rx
+        .wait_for(Option::is_some)
+        .await
+        .ok()
+        .and_then(|reason| reason.clone())
 }
 
-fn setup_global_shutdown() -> (Sender<()>, mpsc::Receiver<()>) {
+fn setup_global_shutdown(
+    mut shutdown_rx: watch::Receiver<Option<ShutdownReason>>,
+) -> (Sender<()>, mpsc::Receiver<()>) {
     let (global_shutdown_tx, global_shutdown_rx) = channel::<()>(1);
     let global_shutdown_tx_clone = global_shutdown_tx.clone();
 
-    tokio::spawn(async move {
-        if tokio::signal::ctrl_c().await.is_ok() {
-            log::info!("Received Ctrl-C signal, initiating shutdown...");
-            let _ = global_shutdown_tx_clone.send(()).await;
+    tokio::spawn(
+        async move {
+            if wait_for_shutdown_reason(&mut shutdown_rx).await.is_some() {
+                tracing::info!("Initiating shutdown...");
+                let _ = global_shutdown_tx_clone.send(()).await;
+            }
         }
-    });
+        .in_current_span(),
+    );
 
     (global_shutdown_tx, global_shutdown_rx)
 }
@@ -63,181 +122,327 @@ fn setup_global_shutdown() -> (Sender<()>, mpsc::Receiver<()>) {
 async fn handle_connections(
     quic_srv: &mut s2n_quic::Server,
     config: RemoteConfig,
+    tcp_listeners: &TcpListenerSlot,
+    shutdown_rx: watch::Receiver<Option<ShutdownReason>>,
     global_shutdown_tx: Sender<()>,
     global_shutdown_rx: &mut mpsc::Receiver<()>,
-) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+) -> Result<Option<JoinHandle<()>>, Error> {
     loop {
         let (close_channel_entry_sender, mut close_channel_entry_receiver) =
             channel::<CloseAction>(1);
 
-        let mut quic_conn = tokio::select! {
+        let quic_conn = tokio::select! {
             Some(qc) = quic_srv.accept() => qc,
             _ = close_channel_entry_receiver.recv() => break,
             _ = global_shutdown_rx.recv() => {
-                log::info!("Global shutdown signal received, exiting...");
-                return Ok(());
+                tracing::info!("Global shutdown signal received, exiting...");
+                return Ok(None);
             }
         };
 
         if let Ok(client_address) = quic_conn.remote_addr() {
-            log::debug!("QUIC connection established with: {client_address}");
-            handle_quic_connection(
-                &mut quic_conn,
+            tracing::debug!("QUIC connection established with: {client_address}");
+            let (close_action, drained) = handle_quic_connection(
+                quic_conn,
                 config.clone(),
+                tcp_listeners,
+                shutdown_rx.clone(),
                 close_channel_entry_sender,
                 global_shutdown_tx.clone(),
                 global_shutdown_rx,
             )
+            .instrument(tracing::info_span!("connection", peer = %client_address))
             .await?;
+
+            if let CloseAction::CloseProcess = close_action {
+                return Ok(drained);
+            }
         }
     }
-    Ok(())
+    Ok(None)
 }
 
 async fn handle_quic_connection(
-    quic_conn: &mut s2n_quic::Connection,
-    config: RemoteConfig,
+    mut quic_conn: s2n_quic::Connection,
+    mut config: RemoteConfig,
+    tcp_listeners: &TcpListenerSlot,
+    shutdown_rx: watch::Receiver<Option<ShutdownReason>>,
     close_entry_sender: Sender<CloseAction>,
     global_shutdown_tx: Sender<()>,
     global_shutdown_rx: &mut mpsc::Receiver<()>,
-) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+) -> Result<(CloseAction, Option<JoinHandle<()>>), Error> {
     if let Ok(Some(mut command_stream)) = quic_conn.accept_bidirectional_stream().await {
-        let tcp_listener = setup_tcp_listener(&config).await?;
-        send_connection_handshake(&mut command_stream, &config).await?;
+        let _connection = quic_conn
+            .remote_addr()
+            .ok()
+            .map(|client_address| config.monitor.connected(client_address));
+
+        // the client is told why it can't be served before this instance stops
+        let tcp_listener = match setup_tcp_listener(&config, tcp_listeners) {
+            Ok(tcp_listener) => tcp_listener,
+            Err(e) => {
+                quic::close_with_error(&quic_conn, &e).await;
+                return Err(e);
+            }
+        };
+        // the local lists what it supports first and the algorithm picked from it is used for
+        // every stream of the connection. Locals of 0.1.5 and earlier send nothing and get
+        // streams without a header
+        let with_header = !quic::legacy_peer(&quic_conn);
+        let offer = if with_header {
+            compress::receive_offer(&mut command_stream).await
+        } else {
+            None
+        };
+        config.compression = compress::negotiate(config.compression, &offer.unwrap_or_default());
+        send_connection_handshake(&mut command_stream, &config, with_header).await?;
 
         let (close_tcpwait_sender, mut close_tcpwait_receiver) = mpsc::channel::<CloseAction>(1);
 
         spawn_command_stream_handler(
             command_stream,
+            config.clone(),
+            shutdown_rx.clone(),
             close_tcpwait_sender.clone(),
             close_entry_sender,
             global_shutdown_tx,
         );
 
-        handle_tcp_connections(
+        let active_streams = Tracker::default();
+        let close_action = handle_tcp_connections(
             tcp_listener,
-            quic_conn,
-            config.buffer_size,
+            &mut quic_conn,
+            &config,
+            with_header,
+            &active_streams,
             &mut close_tcpwait_receiver,
             global_shutdown_rx,
         )
-        .await?;
+        .await;
+        tcp_listeners.release();
+        let close_action = close_action?;
+
+        let drained = match close_action {
+            CloseAction::CloseProcess => {
+                let reason = shutdown_rx.borrow().clone();
+                tokio::spawn(
+                    async move {
+                        super::drain_connection(quic_conn, active_streams, &config, reason).await
+                    }
+                    .in_current_span(),
+                )
+            }
+            // the departing client's streams finish in the background while new clients are accepted
+            CloseAction::CloseStream => {
+                shutdown::spawn_drain(quic_conn, active_streams, config.drain_timeout)
+            }
+        };
+
+        return Ok((close_action, Some(drained)));
     }
-    Ok(())
+    // the client went away before opening its command stream
+    config.monitor.handshake_failed();
+    Ok((CloseAction::CloseStream, None))
 }
 
-async fn setup_tcp_listener(
+fn setup_tcp_listener(
     config: &RemoteConfig,
-) -> Result<TcpListener, Box<dyn Error + Send + Sync + 'static>> {
-    TcpListener::bind(config.tcp_reverse_address.unwrap())
-        .await
-        .map_err(|e| {
-            log::warn!("Tcp Listener could not be created: {e}");
-            Box::new(e) as Box<dyn Error + Send + Sync + 'static>
-        })
+    tcp_listeners: &TcpListenerSlot,
+) -> Result<TcpListener, Error> {
+    let tcp_address = config.tcp_reverse_address.unwrap();
+    tcp_listeners.bind(tcp_address).map_err(|e| {
+        tracing::warn!("Tcp Listener could not be created: {e}");
+        Error::Bind(format!("unable to listen on {tcp_address}: {e}"))
+    })
 }
 
 async fn send_connection_handshake(
     command_stream: &mut BidirectionalStream,
     config: &RemoteConfig,
-) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    with_header: bool,
+) -> Result<(), Error> {
     let connected_msg =
-        proto::ProtoCommand::CONNECTED(config.tcp_reverse_address.unwrap()).deserialize();
+        proto::ProtoCommand::CONNECTED(config.tcp_reverse_address.unwrap(), with_header)
+            .deserialize();
     command_stream.send(connected_msg).await.map_err(|e| {
-        log::warn!(
+        config.monitor.handshake_failed();
+        tracing::warn!(
             "Error while sending connect handshake message to local reverse tunnel instance: {e}"
         );
-        Box::new(e) as Box<dyn Error + Send + Sync + 'static>
+        Error::from(e)
     })
 }
 
 async fn handle_tcp_connections(
     tcp_listener: TcpListener,
     quic_conn: &mut s2n_quic::Connection,
-    buffer_size: usize,
+    config: &RemoteConfig,
+    with_header: bool,
+    active_streams: &Tracker,
     close_tcpwait_receiver: &mut mpsc::Receiver<CloseAction>,
     global_shutdown_rx: &mut mpsc::Receiver<()>,
-) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+) -> Result<CloseAction, Error> {
     loop {
         let (tcp_stream, tcp_addr) = tokio::select! {
             Ok(res) = tcp_listener.accept() => res,
             Some(close_action) = close_tcpwait_receiver.recv() => {
                 match close_action {
                     CloseAction::CloseProcess => {
-                        log::info!("Received close process signal, exiting...");
-                        return Ok(());
+                        tracing::info!("Received close process signal, exiting...");
                     },
                     CloseAction::CloseStream => {
-                        log::debug!("Client disconnected, accepting new connections...");
-                        break;
+                        tracing::debug!("Client disconnected, accepting new connections...");
                     }
                 }
+                return Ok(close_action);
             },
             _ = global_shutdown_rx.recv() => {
-                log::info!("Global shutdown signal received in TCP accept loop, exiting...");
-                return Ok(());
+                tracing::info!("Global shutdown signal received in TCP accept loop, exiting...");
+                return Ok(CloseAction::CloseProcess);
             }
         };
 
-        log::info!("Stream received from {tcp_addr}");
-        spawn_stream_handler(quic_conn, tcp_stream, buffer_size).await?;
+        spawn_stream_handler(
+            quic_conn,
+            tcp_stream,
+            tcp_addr,
+            config,
+            with_header,
+            active_streams,
+        )
+        .await?;
     }
-    Ok(())
 }
 
 async fn spawn_stream_handler(
     quic_conn: &mut s2n_quic::Connection,
-    tcp_stream: tokio::net::TcpStream,
-    buffer_size: usize,
-) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    tcp_stream: TcpStream,
+    tcp_addr: SocketAddr,
+    config: &RemoteConfig,
+    with_header: bool,
+    active_streams: &Tracker,
+) -> Result<(), Error> {
+    let metadata = Metadata::new(Some(tcp_addr), tcp_stream.local_addr().ok());
+    let span = tracing::info_span!("stream", stream_id = %metadata.id);
+    span.in_scope(|| tracing::info!("Stream {} received from {tcp_addr}", metadata.id));
+
     let quic_data_stream = quic_conn.open_bidirectional_stream().await.map_err(|e| {
-        log::warn!(
-            "Unable to create bidirectional quic stream with local reverse tunnel instance: {e}"
+        tracing::warn!(
+            "Stream {}: unable to create bidirectional quic stream with local reverse tunnel instance: {e}",
+            metadata.id
         );
-        Box::new(e) as Box<dyn Error + Send + Sync + 'static>
+        Error::from(e)
     })?;
 
-    tokio::spawn(handle_stream_copy(
-        tcp_stream,
-        quic_data_stream,
-        buffer_size,
-    ));
+    let guard = active_streams.track();
+    let remote_addr = quic_conn.remote_addr()?;
+    let config = config.clone();
+    tokio::spawn(
+        async move {
+            handle_stream_copy(
+                tcp_stream,
+                quic_data_stream,
+                remote_addr,
+                &config,
+                metadata,
+                with_header,
+            )
+            .await;
+            drop(guard);
+        }
+        .instrument(span),
+    );
     Ok(())
 }
 
 async fn handle_stream_copy(
     mut tcp_stream: tokio::net::TcpStream,
     mut quic_stream: BidirectionalStream,
-    buffer_size: usize,
+    remote_addr: SocketAddr,
+    config: &RemoteConfig,
+    mut metadata: Metadata,
+    with_header: bool,
 ) {
-    if let Err(e) = tokio::io::copy_bidirectional_with_sizes(
+    let mut accepted = config.monitor.stream_accepted(StreamInfo::new(
+        remote_addr,
+        stream::peer_addr(&tcp_stream),
+        metadata,
+    ));
+    let client =
+        match stream::accept_client(&mut tcp_stream, &mut quic_stream, &config.trusted_proxies)
+            .await
+        {
+            Ok(client) => client,
+            Err(e) => {
+                tracing::warn!("Stream {} failed: {e}", metadata.id);
+                accepted.close(Some(&e));
+                return;
+            }
+        };
+    if metadata.client != Some(client) {
+        tracing::info!("Stream {} relayed for {client}", metadata.id);
+        metadata.client = Some(client);
+    }
+    accepted.update(|info| {
+        info.tcp_addr = client;
+        info.metadata = metadata;
+    });
+
+    if with_header {
+        let header = StreamHeader {
+            compression: config.compression,
+            metadata: Some(metadata),
+            ..StreamHeader::default()
+        };
+        if let Err(e) = header.write_to(&mut quic_stream).await {
+            tracing::warn!(
+                "Stream {}: error sending stream header to local reverse tunnel instance: {e}",
+                metadata.id
+            );
+            accepted.close(Some(&Error::from(e)));
+            return;
+        }
+    }
+
+    if let Err(e) = stream::copy(
         &mut tcp_stream,
         &mut quic_stream,
-        buffer_size,
-        buffer_size,
+        config.buffer_size,
+        accepted,
+        &config.layers,
+        config.compression,
     )
     .await
     {
-        log::warn!("Error during bidirectional copy: {e}");
+        tracing::warn!("Stream {} failed: {e}", metadata.id);
     }
 }
 
 fn spawn_command_stream_handler(
     command_stream: BidirectionalStream,
+    config: RemoteConfig,
+    shutdown_rx: watch::Receiver<Option<ShutdownReason>>,
     close_tcpwait_sender: Sender<CloseAction>,
     close_entry_sender: Sender<CloseAction>,
     global_shutdown_tx: Sender<()>,
 ) {
-    tokio::spawn(handle_command_stream(
-        command_stream,
-        close_tcpwait_sender,
-        close_entry_sender,
-        global_shutdown_tx,
-    ));
+    tokio::spawn(
+        handle_command_stream(
+            command_stream,
+            config,
+            shutdown_rx,
+            close_tcpwait_sender,
+            close_entry_sender,
+            global_shutdown_tx,
+        )
+        .in_current_span(),
+    );
 }
 
 async fn handle_command_stream(
     command_stream: BidirectionalStream,
+    config: RemoteConfig,
+    shutdown_rx: watch::Receiver<Option<ShutdownReason>>,
     close_tcpwait_sender: Sender<CloseAction>,
     close_entry_sender: Sender<CloseAction>,
     global_shutdown_tx: Sender<()>,
@@ -245,8 +450,10 @@ async fn handle_command_stream(
     let (receiver, sender) = command_stream.split();
     let sender_arc = Arc::new(Mutex::new(sender));
 
-    spawn_ctrl_c_handler(
+    spawn_shutdown_handler(
         sender_arc.clone(),
+        config,
+        shutdown_rx,
         close_tcpwait_sender.clone(),
         close_entry_sender,
         global_shutdown_tx,
@@ -255,23 +462,28 @@ async fn handle_command_stream(
     handle_command_receiver(receiver, sender_arc, close_tcpwait_sender).await;
 }
 
-fn spawn_ctrl_c_handler(
+fn spawn_shutdown_handler(
     sender_arc: Arc<Mutex<s2n_quic::stream::SendStream>>,
+    config: RemoteConfig,
+    mut shutdown_rx: watch::Receiver<Option<ShutdownReason>>,
     close_tcpwait_sender: Sender<CloseAction>,
     close_entry_sender: Sender<CloseAction>,
     global_shutdown_tx: Sender<()>,
 ) {
     tokio::spawn(async move {
-        if tokio::signal::ctrl_c().await.is_ok() {
-            let mut guard = sender_arc.lock().await;
-            let _ = guard.send(proto::ProtoCommand::CLOSED.deserialize()).await;
-            let _ = guard.flush().await;
-            drop(guard);
+        let shutdown_command = match wait_for_shutdown_reason(&mut shutdown_rx).await {
+            Some(reason) => reason.command(&config),
+            None => return,
+        };
 
-            let _ = close_tcpwait_sender.send(CloseAction::CloseProcess).await;
-            let _ = close_entry_sender.send(CloseAction::CloseProcess).await;
-            let _ = global_shutdown_tx.send(()).await;
-        }
+        let mut guard = sender_arc.lock().await;
+        let _ = guard.send(shutdown_command.deserialize()).await;
+        let _ = guard.flush().await;
+        drop(guard);
+
+        let _ = close_tcpwait_sender.send(CloseAction::CloseProcess).await;
+        let _ = close_entry_sender.send(CloseAction::CloseProcess).await;
+        let _ = global_shutdown_tx.send(()).await;
     });
 }
 
@@ -281,22 +493,22 @@ async fn handle_command_receiver(
     close_tcpwait_sender: Sender<CloseAction>,
 ) {
     while let Ok(Some(cmd_data)) = receiver.receive().await {
-        log::debug!("Received command from client");
+        tracing::debug!("Received command from client");
 
         let cmd = match proto::ProtoCommand::serialize(cmd_data) {
             Some(cmd) => cmd,
             None => {
-                log::warn!("Received invalid command data");
+                tracing::warn!("Received invalid command data");
                 continue;
             }
         };
 
         if let proto::ProtoCommand::CLOSED = cmd {
-            log::debug!("Local tunnel instance has closed the connection");
+            tracing::debug!("Local tunnel instance has closed the connection");
             send_ack_and_close(sender_arc, close_tcpwait_sender).await;
             break;
         } else {
-            log::debug!("Received unhandled command");
+            tracing::debug!("Received unhandled command");
         }
     }
 }
@@ -307,11 +519,11 @@ async fn send_ack_and_close(
 ) {
     let mut guard = sender_arc.lock().await;
     if let Err(e) = guard.send(proto::ProtoCommand::ACK.deserialize()).await {
-        log::warn!("Failed to send ACK: {e}");
+        tracing::warn!("Failed to send ACK: {e}");
     }
     drop(guard);
 
     if let Err(e) = close_tcpwait_sender.send(CloseAction::CloseStream).await {
-        log::warn!("Failed to send CloseStream action: {e}");
+        tracing::warn!("Failed to send CloseStream action: {e}");
     }
 }
@@ -0,0 +1,267 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    sync::{watch, Notify},
+    task::JoinHandle,
+    time::Instant,
+};
+use tracing::Instrument;
+
+use crate::{common::SHUTDOWN_ERROR_CODE, quic};
+
+// Resolves once the process is asked to stop, through SIGINT (Ctrl-C) or SIGTERM
+pub async fn wait_for_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut sigterm = match signal(SignalKind::terminate()) {
+            Ok(sigterm) => sigterm,
+            Err(e) => {
+                tracing::warn!("Unable to listen for SIGTERM: {e}");
+                if tokio::signal::ctrl_c().await.is_err() {
+                    std::future::pending::<()>().await;
+                }
+                return;
+            }
+        };
+
+        tokio::select! {
+            res = tokio::signal::ctrl_c() => {
+                if res.is_err() {
+                    sigterm.recv().await;
+                }
+            }
+            _ = sigterm.recv() => {}
+        }
+    }
+
+    #[cfg(not(unix))]
+    if tokio::signal::ctrl_c().await.is_err() {
+        std::future::pending::<()>().await;
+    }
+}
+
+// What a tunnel waits on before shutting down. Tunnels run from a config file also get a
+// StopHandle, so a reload can stop them one at a time while the rest keep running. Tunnels
+// embedded in another application only stop through their handle, the process signals are
+// left to the application
+#[derive(Clone)]
+pub struct StopSignal {
+    stop_rx: Option<watch::Receiver<bool>>,
+    process_signals: bool,
+}
+
+impl Default for StopSignal {
+    fn default() -> Self {
+        Self {
+            stop_rx: None,
+            process_signals: true,
+        }
+    }
+}
+
+// Stops the tunnel holding the matching StopSignal, dropping it does too
+pub struct StopHandle(watch::Sender<bool>);
+
+pub fn stop_signal() -> (StopHandle, StopSignal) {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let signal = StopSignal {
+        stop_rx: Some(stop_rx),
+        process_signals: true,
+    };
+    (StopHandle(stop_tx), signal)
+}
+
+impl StopSignal {
+    // Stops only through the handle, not on SIGINT or SIGTERM
+    pub fn without_process_signals(self) -> Self {
+        Self {
+            process_signals: false,
+            ..self
+        }
+    }
+
+    // Resolves on a shutdown signal, or once the tunnel is stopped through its handle
+    pub async fn wait(&self) {
+        let stopped = async {
+            match &self.stop_rx {
+                Some(stop_rx) => {
+                    let _ = stop_rx.clone().wait_for(|stopped| *stopped).await;
+                }
+                None => std::future::pending().await,
+            }
+        };
+
+        if !self.process_signals {
+            return stopped.await;
+        }
+        tokio::select! {
+            _ = wait_for_signal() => {}
+            _ = stopped => {}
+        }
+    }
+}
+
+impl StopHandle {
+    pub fn stop(&self) {
+        let _ = self.0.send(true);
+    }
+}
+
+// Counts running tasks, such as stream copies, so shutdown can wait for them to finish
+#[derive(Clone, Default)]
+pub struct Tracker {
+    inner: Arc<TrackerInner>,
+}
+
+#[derive(Default)]
+struct TrackerInner {
+    active: AtomicUsize,
+    notify: Notify,
+}
+
+// Held by a tracked task for as long as it runs
+pub struct TrackerGuard {
+    inner: Arc<TrackerInner>,
+}
+
+impl Tracker {
+    pub fn track(&self) -> TrackerGuard {
+        self.inner.active.fetch_add(1, Ordering::SeqCst);
+        TrackerGuard {
+            inner: self.inner.clone(),
+        }
+    }
+
+    pub fn active(&self) -> usize {
+        self.inner.active.load(Ordering::SeqCst)
+    }
+
+    // Waits for every tracked task to finish, returns false if the timeout ran out first
+    pub async fn wait(&self, timeout: Duration) -> bool {
+        tokio::time::timeout(timeout, async {
+            loop {
+                let notified = self.inner.notify.notified();
+                if self.active() == 0 {
+                    break;
+                }
+                notified.await;
+            }
+        })
+        .await
+        .is_ok()
+    }
+
+    // Waits for tracked tasks up to the drain timeout and logs how many were cut off
+    pub async fn drain(&self, timeout: Duration) {
+        if self.active() == 0 {
+            return;
+        }
+
+        tracing::info!(
+            "Waiting up to {}s for {} active streams to finish",
+            timeout.as_secs(),
+            self.active()
+        );
+
+        if !self.wait(timeout).await {
+            tracing::warn!(
+                "Drain timeout reached, closing {} active streams",
+                self.active()
+            );
+        }
+    }
+}
+
+// Keeps a quic connection open in the background while its streams finish, then closes it
+pub fn spawn_drain(
+    quic_conn: s2n_quic::Connection,
+    active_streams: Tracker,
+    drain_timeout: Duration,
+) -> JoinHandle<()> {
+    tokio::spawn(
+        async move {
+            active_streams.drain(drain_timeout).await;
+            quic_conn.close(SHUTDOWN_ERROR_CODE.into());
+        }
+        .in_current_span(),
+    )
+}
+
+// Drains a connection this side is shutting down. The peer is then given until the drain
+// deadline to close it, so its side of the streams can finish before the connection goes away
+pub async fn drain_and_wait_for_close(
+    mut quic_conn: s2n_quic::Connection,
+    active_streams: Tracker,
+    drain_timeout: Duration,
+) {
+    let drain_deadline = Instant::now() + drain_timeout;
+    active_streams.drain(drain_timeout).await;
+
+    let _ = tokio::time::timeout_at(drain_deadline, quic::wait_for_close(&mut quic_conn)).await;
+    quic_conn.close(SHUTDOWN_ERROR_CODE.into());
+}
+
+impl Drop for TrackerGuard {
+    fn drop(&mut self) {
+        if self.inner.active.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.inner.notify.notify_waiters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::time::Duration;
+
+    use super::{stop_signal, Tracker};
+
+    #[tokio::test]
+    async fn test_tracker_wait() {
+        let tracker = Tracker::default();
+        assert!(tracker.wait(Duration::from_millis(10)).await);
+
+        let guard = tracker.track();
+        assert_eq!(tracker.active(), 1);
+        assert!(!tracker.wait(Duration::from_millis(10)).await);
+
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            drop(guard);
+        });
+
+        assert!(tracker.wait(Duration::from_secs(5)).await);
+        assert_eq!(tracker.active(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_stop_signal() {
+        let (stop, signal) = stop_signal();
+        let waiting = tokio::spawn({
+            let signal = signal.clone();
+            async move { signal.wait().await }
+        });
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!waiting.is_finished());
+
+        stop.stop();
+        tokio::time::timeout(Duration::from_secs(5), waiting)
+            .await
+            .unwrap()
+            .unwrap();
+
+        // a tunnel waiting after it was stopped doesn't block
+        tokio::time::timeout(Duration::from_secs(5), signal.wait())
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,603 @@
+// Moves data between a tcp connection and the quic stream carrying it. A failure on one side
+// is passed on to the other, so both ends of a tunnel report the same cause: a tcp target
+// that refuses, doesn't answer or resets the connection resets the quic stream with the
+// matching error code, and a reset quic stream resets the tcp connection on the other end
+use std::{
+    fmt, future, io,
+    net::{Ipv4Addr, SocketAddr},
+    pin::Pin,
+    task::Poll,
+    time::{Duration, Instant},
+};
+
+use s2n_quic::stream::BidirectionalStream;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::{TcpStream, ToSocketAddrs},
+};
+
+use crate::{
+    common::proto::StreamHeader,
+    compress::{self, Compression},
+    errors::Error,
+    layer::Layers,
+    metadata::Metadata,
+    monitor::{Counted, Monitor, StreamGuard},
+    proxy::{self, Cidr, ProxyProtocol},
+};
+
+const DIAL_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Connects to the tcp target of a stream, the stream is reset with the reason if that fails
+pub async fn dial<A: ToSocketAddrs + fmt::Display>(
+    target: A,
+    quic_stream: &mut BidirectionalStream,
+    monitor: &Monitor,
+) -> Result<TcpStream, Error> {
+    let started = Instant::now();
+    let res = tokio::time::timeout(DIAL_TIMEOUT, TcpStream::connect(&target))
+        .await
+        .unwrap_or_else(|_| {
+            Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("no answer within {}s", DIAL_TIMEOUT.as_secs()),
+            ))
+        });
+
+    // only connections made count towards the latency, failures are counted by their cause
+    res.inspect(|_| monitor.dialed(started.elapsed()))
+        .map_err(|e| {
+            let context = format!("unable to connect to {target}");
+            let error = match Error::tcp(e, &context) {
+                Error::Io(e) => Error::Dial(format!("{context}: {e}")),
+                error => error,
+            };
+            reset(quic_stream, &error);
+            error
+        })
+}
+
+// Tells the target about the client of the stream ahead of its data, the stream is reset if the
+// target can't be written to
+pub async fn send_proxy_header(
+    tcp_stream: &mut TcpStream,
+    quic_stream: &mut BidirectionalStream,
+    version: ProxyProtocol,
+    metadata: &Metadata,
+) -> Result<(), Error> {
+    let header = |target| version.header_with_id(metadata.client, target, Some(metadata.id));
+    let res = match tcp_stream.peer_addr() {
+        Ok(target) => tcp_stream.write_all(&header(target)).await,
+        Err(e) => Err(e),
+    };
+
+    res.map_err(|e| {
+        let error = Error::tcp(e, "unable to send PROXY header");
+        reset(quic_stream, &error);
+        error
+    })
+}
+
+// The client of an accepted tcp connection, taken from its PROXY header when it comes from one of
+// the trusted proxies. The stream is reset if such a connection doesn't send a valid one
+pub async fn accept_client(
+    tcp_stream: &mut TcpStream,
+    quic_stream: &mut BidirectionalStream,
+    trusted_proxies: &[Cidr],
+) -> Result<SocketAddr, Error> {
+    proxy::accept(tcp_stream, trusted_proxies)
+        .await
+        .map_err(|e| {
+            let error = match e.kind() {
+                io::ErrorKind::InvalidData
+                | io::ErrorKind::TimedOut
+                | io::ErrorKind::UnexpectedEof => Error::Protocol(format!(
+                    "no valid PROXY header from {}: {e}",
+                    peer_addr(tcp_stream)
+                )),
+                _ => Error::tcp(e, "tcp connection failed"),
+            };
+            reset(quic_stream, &error);
+            error
+        })
+}
+
+// The header the opening end of a stream sends ahead of its data, the stream is reset if it
+// doesn't send a valid one in time
+pub async fn read_header(quic_stream: &mut BidirectionalStream) -> Result<StreamHeader, Error> {
+    match StreamHeader::read_from(quic_stream).await {
+        Ok(header) => Ok(header),
+        Err(e) => {
+            let error = match e.kind() {
+                io::ErrorKind::InvalidData
+                | io::ErrorKind::TimedOut
+                | io::ErrorKind::UnexpectedEof => {
+                    Error::Protocol(format!("no valid stream header: {e}"))
+                }
+                _ => Error::from(e),
+            };
+            reset(quic_stream, &error);
+            Err(error)
+        }
+    }
+}
+
+// The other end of a tcp connection, unspecified once it is gone
+pub fn peer_addr(tcp_stream: &TcpStream) -> SocketAddr {
+    tcp_stream
+        .peer_addr()
+        .unwrap_or(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)))
+}
+
+// Aborts both directions of a stream with the error's code
+pub fn reset(quic_stream: &mut BidirectionalStream, error: &Error) {
+    let _ = quic_stream.stop_sending(error.into());
+    let _ = quic_stream.reset(error.into());
+}
+
+// Copies between the two until both directions are closed. Each direction ends on its own: a
+// tcp FIN finishes the quic stream and a quic FIN shuts down the tcp write half, while the other
+// direction keeps going, which protocols like `nc -q` or rsync rely on. Once both have ended the
+// tcp connection closes normally, any unsent data is still delivered in the background. If
+// either side fails, the other is reset instead of ended, so a cut stream never looks complete.
+// `stream` was accepted with the other end of the tunnel and the client or target of the stream,
+// it is opened here and closed with how the copy ended. The tunnel's layers wrap both sides, the
+// monitor counts the bytes on the tcp connection itself and the quic stream is compressed below
+// the layers, as negotiated for the stream
+pub async fn copy(
+    tcp_stream: &mut TcpStream,
+    quic_stream: &mut BidirectionalStream,
+    buffer_size: usize,
+    stream: StreamGuard,
+    layers: &Layers,
+    compression: Option<Compression>,
+) -> Result<(), Error> {
+    let res = copy_local(
+        &mut *tcp_stream,
+        Local::Tcp,
+        quic_stream,
+        buffer_size,
+        stream,
+        layers,
+        compression,
+    )
+    .await;
+    if res.is_err() {
+        // closing with no linger sends a reset rather than a clean end of stream
+        let _ = socket2::SockRef::from(&*tcp_stream).set_linger(Some(Duration::ZERO));
+    }
+    res
+}
+
+// Copies between the quic stream and a local stream that isn't a tcp connection, such as stdio,
+// as `copy` does except that it returns once the tunnel side has ended. Such a stream may never
+// reach its end on its own: stdin of an ssh ProxyCommand stays open after the server has closed
+// the connection. If the local side ended first the copy still waits for the tunnel to
+pub async fn copy_io<T: AsyncRead + AsyncWrite + Send + Unpin>(
+    local_stream: &mut T,
+    quic_stream: &mut BidirectionalStream,
+    buffer_size: usize,
+    stream: StreamGuard,
+    layers: &Layers,
+    compression: Option<Compression>,
+) -> Result<(), Error> {
+    copy_local(
+        local_stream,
+        Local::Io,
+        quic_stream,
+        buffer_size,
+        stream,
+        layers,
+        compression,
+    )
+    .await
+}
+
+// What the quic stream is copied to
+#[derive(Clone, Copy, PartialEq)]
+enum Local {
+    Tcp,
+    Io,
+}
+
+async fn copy_local<T: AsyncRead + AsyncWrite + Send + Unpin>(
+    local_stream: &mut T,
+    local: Local,
+    quic_stream: &mut BidirectionalStream,
+    buffer_size: usize,
+    mut stream: StreamGuard,
+    layers: &Layers,
+    compression: Option<Compression>,
+) -> Result<(), Error> {
+    stream.open();
+    let info = *stream.info();
+
+    let counted = Counted {
+        inner: local_stream,
+        stream: &mut stream,
+    };
+    let res = {
+        let (mut local_reader, mut local_writer) =
+            tokio::io::split(layers.wrap_local(Box::new(counted), &info));
+        let (mut tunnel_reader, mut tunnel_writer) = tokio::io::split(layers.wrap_tunnel(
+            compress::wrap(Box::new(&mut *quic_stream), compression),
+            &info,
+        ));
+        match local {
+            // the first direction to fail drops the other one
+            Local::Tcp => tokio::try_join!(
+                copy_half(&mut local_reader, &mut tunnel_writer, buffer_size),
+                copy_half(&mut tunnel_reader, &mut local_writer, buffer_size),
+            )
+            .map(|_| ()),
+            Local::Io => {
+                let mut sent = false;
+                let res = tokio::select! {
+                    res = copy_half(&mut tunnel_reader, &mut local_writer, buffer_size) => res,
+                    Err(e) = async {
+                        copy_half(&mut local_reader, &mut tunnel_writer, buffer_size)
+                            .await
+                            .inspect(|()| sent = true)
+                    } => Err(e),
+                };
+                // the quic stream is still ended cleanly when the local side never was
+                match res {
+                    Ok(()) if !sent => tunnel_writer.shutdown().await,
+                    res => res,
+                }
+            }
+        }
+    };
+    let Err(e) = res else {
+        stream.close(None);
+        return Ok(());
+    };
+
+    let context = match local {
+        Local::Tcp => "tcp connection failed",
+        Local::Io => "local stream failed",
+    };
+    let error = match Error::from(e) {
+        // only quic errors are decoded, anything else came from the local side or a layer
+        Error::Io(e) if e.kind() == io::ErrorKind::InvalidData => {
+            let error = Error::Protocol(e.to_string());
+            reset(quic_stream, &error);
+            error
+        }
+        Error::Io(e) => {
+            let error = Error::tcp(e, context);
+            reset(quic_stream, &error);
+            error
+        }
+        error => error,
+    };
+
+    // the remote refused the target this end asked for
+    if let Error::Auth(_) = error {
+        stream.auth_failed(&error);
+    }
+    stream.close(Some(&error));
+    Err(error)
+}
+
+// Copies one direction until its reader ends, then shuts the writer down so the end of stream
+// reaches the other side. The opposite direction isn't touched and runs until its own end.
+// Whatever was written is flushed once the reader has nothing more for now, rather than after
+// every read, since flushing a quic stream waits for the peer to acknowledge the data
+async fn copy_half<R, W>(reader: &mut R, writer: &mut W, buffer_size: usize) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0; buffer_size];
+    let mut flushed = true;
+
+    loop {
+        let mut read_buf = ReadBuf::new(&mut buf);
+        let poll =
+            future::poll_fn(|cx| Poll::Ready(Pin::new(&mut *reader).poll_read(cx, &mut read_buf)))
+                .await;
+        match poll {
+            Poll::Ready(res) => res?,
+            Poll::Pending => {
+                if !flushed {
+                    writer.flush().await?;
+                }
+                future::poll_fn(|cx| Pin::new(&mut *reader).poll_read(cx, &mut read_buf)).await?;
+            }
+        }
+
+        let data = read_buf.filled();
+        if data.is_empty() {
+            break;
+        }
+        writer.write_all(data).await?;
+        flushed = false;
+    }
+
+    writer.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::{
+        net::SocketAddr,
+        pin::Pin,
+        str::FromStr,
+        sync::Arc,
+        task::{Context, Poll},
+        time::Duration,
+    };
+
+    use crate::{
+        errors::code,
+        layer::{BoxedIo, Layers, StreamInfo, StreamLayer},
+        monitor::Monitor,
+        quic::{new_quic_connection, new_quic_server},
+        stream, Error, Metadata,
+    };
+    use s2n_quic::stream::BidirectionalStream;
+    use tokio::{
+        io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+        net::{TcpListener, TcpStream},
+        task::JoinHandle,
+    };
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    // One end of a tunnel: `stream::copy` runs between a tcp connection and a quic stream,
+    // and the test drives the tcp client and the quic peer on the other side of each
+    struct Tunnel {
+        tcp_client: TcpStream,
+        quic_peer: BidirectionalStream,
+        copy: JoinHandle<Result<(), Error>>,
+        // closing the connections early would fail streams still waiting for acks
+        _connections: (s2n_quic::Server, s2n_quic::Connection, s2n_quic::Connection),
+    }
+
+    async fn open_tunnel() -> Tunnel {
+        open_layered_tunnel(Layers::default()).await
+    }
+
+    async fn open_layered_tunnel(layers: Layers) -> Tunnel {
+        let mut server = new_quic_server(
+            SocketAddr::from_str("127.0.0.1:0").unwrap(),
+            include_str!("../test_cert.pem"),
+            include_str!("../test_key.pem"),
+            &Monitor::default(),
+        )
+        .await
+        .unwrap();
+        let mut client_conn = new_quic_connection(
+            server.local_addr().unwrap(),
+            include_str!("../test_cert.pem"),
+            &Monitor::default(),
+        )
+        .await
+        .unwrap();
+
+        // the stream only reaches the server once something is sent on it
+        let mut quic_peer = client_conn.open_bidirectional_stream().await.unwrap();
+        quic_peer.write_all(b"open").await.unwrap();
+        quic_peer.flush().await.unwrap();
+
+        let mut server_conn = server.accept().await.unwrap();
+        let mut quic_stream = server_conn
+            .accept_bidirectional_stream()
+            .await
+            .unwrap()
+            .unwrap();
+        let mut opened = [0; 4];
+        quic_stream.read_exact(&mut opened).await.unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let tcp_client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut tcp_stream, _) = listener.accept().await.unwrap();
+
+        let info = StreamInfo::new(
+            server_conn.remote_addr().unwrap(),
+            tcp_stream.peer_addr().unwrap(),
+            Metadata::new(None, None),
+        );
+        let copy = tokio::spawn(async move {
+            stream::copy(
+                &mut tcp_stream,
+                &mut quic_stream,
+                1024,
+                Monitor::default().stream_accepted(info),
+                &layers,
+                None,
+            )
+            .await
+        });
+
+        Tunnel {
+            tcp_client,
+            quic_peer,
+            copy,
+            _connections: (server, server_conn, client_conn),
+        }
+    }
+
+    // Flips the case of ascii letters passing through the tunnel half, both ways
+    struct FlipCase;
+
+    struct Flipped<'a>(BoxedIo<'a>);
+
+    fn flip(data: &mut [u8]) {
+        for byte in data.iter_mut().filter(|byte| byte.is_ascii_alphabetic()) {
+            *byte ^= 0x20;
+        }
+    }
+
+    impl StreamLayer for FlipCase {
+        fn wrap_tunnel<'a>(&self, stream: BoxedIo<'a>, _info: &StreamInfo) -> BoxedIo<'a> {
+            Box::new(Flipped(stream))
+        }
+    }
+
+    impl AsyncRead for Flipped<'_> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            let before = buf.filled().len();
+            let res = Pin::new(&mut self.0).poll_read(cx, buf);
+            flip(&mut buf.filled_mut()[before..]);
+            res
+        }
+    }
+
+    impl AsyncWrite for Flipped<'_> {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            let mut flipped = buf.to_vec();
+            flip(&mut flipped);
+            Pin::new(&mut self.0).poll_write(cx, &flipped)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.0).poll_flush(cx)
+        }
+
+        fn poll_shutdown(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.0).poll_shutdown(cx)
+        }
+    }
+
+    async fn read_to_end<R: AsyncReadExt + Unpin>(reader: &mut R) -> std::io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        tokio::time::timeout(TIMEOUT, reader.read_to_end(&mut data))
+            .await
+            .unwrap()?;
+        Ok(data)
+    }
+
+    async fn copy_result(copy: JoinHandle<Result<(), Error>>) -> Result<(), Error> {
+        tokio::time::timeout(TIMEOUT, copy).await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_tcp_half_close_first() {
+        let mut tunnel = open_tunnel().await;
+
+        tunnel.tcp_client.write_all(b"request").await.unwrap();
+        tunnel.tcp_client.shutdown().await.unwrap();
+        assert_eq!(
+            read_to_end(&mut tunnel.quic_peer).await.unwrap(),
+            b"request"
+        );
+
+        // the other direction is still open after the fin
+        tunnel.quic_peer.write_all(b"response").await.unwrap();
+        tunnel.quic_peer.close().await.unwrap();
+        assert_eq!(
+            read_to_end(&mut tunnel.tcp_client).await.unwrap(),
+            b"response"
+        );
+
+        assert!(copy_result(tunnel.copy).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_quic_half_close_first() {
+        let mut tunnel = open_tunnel().await;
+
+        tunnel.quic_peer.write_all(b"greeting").await.unwrap();
+        tunnel.quic_peer.close().await.unwrap();
+        assert_eq!(
+            read_to_end(&mut tunnel.tcp_client).await.unwrap(),
+            b"greeting"
+        );
+
+        tunnel.tcp_client.write_all(b"reply").await.unwrap();
+        tunnel.tcp_client.shutdown().await.unwrap();
+        assert_eq!(read_to_end(&mut tunnel.quic_peer).await.unwrap(), b"reply");
+
+        assert!(copy_result(tunnel.copy).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_simultaneous_close() {
+        let mut tunnel = open_tunnel().await;
+
+        tunnel.tcp_client.write_all(b"from tcp").await.unwrap();
+        tunnel.quic_peer.write_all(b"from quic").await.unwrap();
+        tunnel.tcp_client.shutdown().await.unwrap();
+        tunnel.quic_peer.close().await.unwrap();
+
+        assert_eq!(
+            read_to_end(&mut tunnel.quic_peer).await.unwrap(),
+            b"from tcp"
+        );
+        assert_eq!(
+            read_to_end(&mut tunnel.tcp_client).await.unwrap(),
+            b"from quic"
+        );
+        assert!(copy_result(tunnel.copy).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_tcp_reset() {
+        let mut tunnel = open_tunnel().await;
+
+        socket2::SockRef::from(&tunnel.tcp_client)
+            .set_linger(Some(Duration::ZERO))
+            .unwrap();
+        drop(tunnel.tcp_client);
+
+        let e = read_to_end(&mut tunnel.quic_peer).await.unwrap_err();
+        assert_eq!(Error::from(e).code(), code::RESET);
+        assert!(matches!(
+            copy_result(tunnel.copy).await,
+            Err(Error::Reset(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_stream_layer() {
+        let mut layers = Layers::default();
+        layers.push(Arc::new(FlipCase));
+        let mut tunnel = open_layered_tunnel(layers).await;
+
+        tunnel.tcp_client.write_all(b"Request").await.unwrap();
+        tunnel.tcp_client.shutdown().await.unwrap();
+        assert_eq!(
+            read_to_end(&mut tunnel.quic_peer).await.unwrap(),
+            b"rEQUEST"
+        );
+
+        tunnel.quic_peer.write_all(b"rESPONSE").await.unwrap();
+        tunnel.quic_peer.close().await.unwrap();
+        assert_eq!(
+            read_to_end(&mut tunnel.tcp_client).await.unwrap(),
+            b"Response"
+        );
+
+        assert!(copy_result(tunnel.copy).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_quic_reset() {
+        let mut tunnel = open_tunnel().await;
+
+        tunnel.quic_peer.reset(code::REFUSED.into()).unwrap();
+
+        let e = read_to_end(&mut tunnel.tcp_client).await.unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::ConnectionReset);
+        assert!(matches!(
+            copy_result(tunnel.copy).await,
+            Err(Error::Refused(_))
+        ));
+    }
+}
@@ -0,0 +1,671 @@
+// Runs the tunnels of a config file and applies changes to it without a restart. On SIGHUP or
+// an admin `reload` the file is read again and diffed against the running tunnels by name:
+// added tunnels start, removed ones drain and stop, and changed ones drain and start again
+// once the old instance has released its addresses. Unchanged tunnels are left alone
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use tokio::{sync::mpsc, task::JoinSet};
+use tracing::Instrument;
+
+use crate::{
+    admin,
+    config::{self, Overrides, Tunnel},
+    errors::Error,
+    metrics::Metrics,
+    shutdown::{self, StopHandle},
+    transport::QuicStats,
+};
+
+type TunnelResult = (String, u64, Result<(), Error>);
+
+struct Running {
+    id: u64,
+    tunnel: Tunnel,
+    stop: StopHandle,
+    stopping: bool,
+}
+
+pub struct Supervisor {
+    path: PathBuf,
+    overrides: Overrides,
+    running: BTreeMap<String, Running>,
+
+    // tunnels waiting for their previous instance to stop
+    pending: BTreeMap<String, Tunnel>,
+    tasks: JoinSet<TunnelResult>,
+    next_id: u64,
+
+    // tunnels that failed and haven't been started again, with why
+    failed: Vec<(String, Error)>,
+    metrics: Metrics,
+}
+
+impl Supervisor {
+    pub fn new(path: &Path, overrides: Overrides) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            overrides,
+            running: BTreeMap::new(),
+            pending: BTreeMap::new(),
+            tasks: JoinSet::new(),
+            next_id: 0,
+            failed: Vec::new(),
+            metrics: Metrics::default(),
+        }
+    }
+
+    // Runs every tunnel until all of them have stopped, a failing tunnel doesn't stop the others
+    pub async fn run(
+        mut self,
+        tunnels: Vec<Tunnel>,
+        admin_path: Option<&Path>,
+        metrics_address: Option<SocketAddr>,
+    ) -> Result<(), Error> {
+        let mut admin_requests = match admin_path {
+            Some(path) => Some(admin::listen(path)?),
+            None => None,
+        };
+        if let Some(address) = metrics_address {
+            self.metrics.serve(address).await?;
+        }
+
+        for tunnel in tunnels {
+            self.start(tunnel);
+        }
+
+        let mut hangup = Hangup::new()?;
+        let signal = shutdown::wait_for_signal();
+        tokio::pin!(signal);
+        let mut shutting_down = false;
+
+        while !self.tasks.is_empty() {
+            tokio::select! {
+                Some(res) = self.tasks.join_next() => match res {
+                    Ok((name, id, res)) => self.stopped(name, id, res, shutting_down),
+                    Err(e) => tracing::error!("Tunnel task failed: {e}"),
+                },
+                _ = &mut signal, if !shutting_down => {
+                    // every tunnel is stopping on its own, only keep waiting for them
+                    shutting_down = true;
+                    self.pending.clear();
+                }
+                _ = hangup.recv(), if !shutting_down => {
+                    tracing::info!("Received SIGHUP, reloading {}", self.path.display());
+                    if let Err(e) = self.reload() {
+                        tracing::error!("Unable to reload config, keeping the running tunnels: {e}");
+                    }
+                }
+                Some(request) = recv_request(&mut admin_requests), if !shutting_down => {
+                    self.handle_request(request);
+                }
+            }
+        }
+
+        // the process exits with the kind of the first failure
+        let names: Vec<&str> = self.failed.iter().map(|(name, _)| name.as_str()).collect();
+        let context = format!("tunnels failed: {}", names.join(", "));
+        match self.failed.into_iter().next() {
+            Some((_, e)) => Err(e.context(&context)),
+            None => Ok(()),
+        }
+    }
+
+    fn start(&mut self, tunnel: Tunnel) {
+        tracing::info!("Starting tunnel {}", tunnel.name);
+        if let Err(e) = tunnel.register_outputs() {
+            tracing::error!("Tunnel {} failed: {e}", tunnel.name);
+            self.failed.retain(|(failed, _)| *failed != tunnel.name);
+            self.failed.push((tunnel.name, e));
+            return;
+        }
+
+        let (stop, stop_signal) = shutdown::stop_signal();
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let name = tunnel.name.clone();
+        let mut config = tunnel.config.clone();
+        config.set_stop(stop_signal);
+        // everything the tunnel logs carries its name
+        let span = tracing::info_span!("tunnel", tunnel = %name);
+        self.tasks
+            .spawn(async move { (name, id, config.run().instrument(span).await) });
+
+        self.failed.retain(|(failed, _)| *failed != tunnel.name);
+        self.metrics.add(&tunnel.name, tunnel.config.monitor());
+        self.running.insert(
+            tunnel.name.clone(),
+            Running {
+                id,
+                tunnel,
+                stop,
+                stopping: false,
+            },
+        );
+    }
+
+    fn stopped(&mut self, name: String, id: u64, res: Result<(), Error>, shutting_down: bool) {
+        match res {
+            Ok(()) => tracing::info!("Tunnel {name} stopped"),
+            Err(e) => {
+                tracing::error!("Tunnel {name} failed: {e}");
+                self.failed.push((name.clone(), e));
+            }
+        }
+
+        if self
+            .running
+            .get(&name)
+            .is_some_and(|running| running.id == id)
+        {
+            self.running.remove(&name);
+            self.metrics.remove(&name);
+        }
+        if let Some(tunnel) = self.pending.remove(&name) {
+            if !shutting_down {
+                self.start(tunnel);
+            }
+        }
+    }
+
+    fn stop(&mut self, name: &str) {
+        if let Some(running) = self.running.get_mut(name) {
+            if !running.stopping {
+                tracing::info!("Stopping tunnel {name}");
+                running.stopping = true;
+                running.stop.stop();
+            }
+        }
+    }
+
+    // Applies the current contents of the config file, returns a summary of the changes
+    fn reload(&mut self) -> Result<String, Error> {
+        let config = config::load(&self.path, &self.overrides)?;
+        let mut tunnels: BTreeMap<String, Tunnel> = config
+            .tunnels
+            .into_iter()
+            .map(|tunnel| (tunnel.name.clone(), tunnel))
+            .collect();
+
+        let (mut added, mut removed, mut changed, mut unchanged) = (0, 0, 0, 0);
+
+        let names: Vec<String> = self.running.keys().cloned().collect();
+        for name in names.iter().filter(|name| !tunnels.contains_key(*name)) {
+            // a tunnel already stopping for good was removed by an earlier reload
+            if !self.running[name].stopping || self.pending.remove(name).is_some() {
+                removed += 1;
+            }
+            self.stop(name);
+        }
+
+        while let Some((name, tunnel)) = tunnels.pop_first() {
+            let Some(running) = self.running.get(&name) else {
+                added += 1;
+                self.start(tunnel);
+                continue;
+            };
+
+            if !running.stopping && running.tunnel.same_as(&tunnel) {
+                unchanged += 1;
+                continue;
+            }
+
+            // a stopping tunnel is compared with what it is going to be restarted as
+            match (running.stopping, self.pending.get(&name)) {
+                (true, Some(pending)) if pending.same_as(&tunnel) => unchanged += 1,
+                (true, None) => added += 1,
+                _ => changed += 1,
+            }
+
+            self.stop(&name);
+            self.pending.insert(name, tunnel);
+        }
+
+        let summary =
+            format!("{added} added, {removed} removed, {changed} changed, {unchanged} unchanged");
+        tracing::info!("Reloaded config: {summary}");
+
+        Ok(summary)
+    }
+
+    // The transport statistics of every open quic connection as one JSON line, by tunnel
+    fn quic_stats(&self) -> String {
+        let connections: BTreeMap<&str, Vec<QuicStats>> = self
+            .running
+            .iter()
+            .map(|(name, running)| (name.as_str(), running.tunnel.config.monitor().quic_stats()))
+            .collect();
+        serde_json::to_string(&connections).unwrap_or_default()
+    }
+
+    fn handle_request(&mut self, request: admin::Request) {
+        let response = match request.command.as_str() {
+            "reload" => match self.reload() {
+                Ok(summary) => format!("ok: {summary}"),
+                Err(e) => {
+                    tracing::error!("Unable to reload config, keeping the running tunnels: {e}");
+                    format!("error: {e}")
+                }
+            },
+            "quic" => self.quic_stats(),
+            command => format!("error: unknown command `{command}`, expected reload or quic"),
+        };
+
+        request.reply(response);
+    }
+}
+
+async fn recv_request(
+    requests: &mut Option<mpsc::Receiver<admin::Request>>,
+) -> Option<admin::Request> {
+    match requests {
+        Some(requests) => requests.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+// SIGHUP, where there is such a signal
+struct Hangup {
+    #[cfg(unix)]
+    signal: tokio::signal::unix::Signal,
+}
+
+impl Hangup {
+    fn new() -> std::io::Result<Self> {
+        Ok(Self {
+            #[cfg(unix)]
+            signal: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?,
+        })
+    }
+
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        if self.signal.recv().await.is_some() {
+            return;
+        }
+
+        std::future::pending::<()>().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{SocketAddr, TcpListener, UdpSocket},
+        path::{Path, PathBuf},
+        time::Duration,
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use super::Supervisor;
+    use crate::config::{self, Overrides};
+
+    fn free_tcp_addr() -> SocketAddr {
+        TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    }
+
+    fn free_udp_addr() -> SocketAddr {
+        UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    }
+
+    async fn spawn_echo_server() -> SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = stream.split();
+                    let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                });
+            }
+        });
+
+        addr
+    }
+
+    fn pem_paths() -> (String, String) {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let path = |name: &str| dir.join(name).display().to_string();
+        (path("test_cert.pem"), path("test_key.pem"))
+    }
+
+    fn forward_remote(name: &str, quic_addr: SocketAddr, forward_addr: SocketAddr) -> String {
+        let (cert, key) = pem_paths();
+        format!(
+            r#"
+[tunnels.{name}]
+type = "forward"
+side = "remote"
+cert = "{cert}"
+key = "{key}"
+quicaddr = "{quic_addr}"
+forwardaddr = "{forward_addr}"
+draintimeout = 5
+"#
+        )
+    }
+
+    fn forward_local(name: &str, quic_addr: SocketAddr, local_addr: SocketAddr) -> String {
+        let (cert, _) = pem_paths();
+        format!(
+            r#"
+[tunnels.{name}]
+type = "forward"
+side = "local"
+cert = "{cert}"
+remoteaddr = "{quic_addr}"
+localaddr = "{local_addr}"
+draintimeout = 5
+"#
+        )
+    }
+
+    // A forward remote in front of `forward_addr` and a local connected to it
+    fn forward_pair(
+        name: &str,
+        quic_addr: SocketAddr,
+        local_addr: SocketAddr,
+        forward_addr: SocketAddr,
+    ) -> String {
+        forward_remote(&format!("{name}-remote"), quic_addr, forward_addr)
+            + &forward_local(&format!("{name}-local"), quic_addr, local_addr)
+    }
+
+    fn start(path: &Path, source: &str) -> Supervisor {
+        std::fs::write(path, source).unwrap();
+        let config = config::load(path, &Overrides::default()).unwrap();
+        let mut supervisor = Supervisor::new(path, Overrides::default());
+        for tunnel in config.tunnels {
+            supervisor.start(tunnel);
+        }
+        supervisor
+    }
+
+    // Connects once the local tunnel listens and has reached its remote
+    async fn connect(local_addr: SocketAddr) -> TcpStream {
+        for _ in 0..50 {
+            if let Ok(mut stream) = TcpStream::connect(local_addr).await {
+                if echo(&mut stream, b"hello").await.is_ok() {
+                    return stream;
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("no tunnel reachable on {local_addr}");
+    }
+
+    async fn echo(stream: &mut TcpStream, message: &[u8]) -> std::io::Result<()> {
+        stream.write_all(message).await?;
+        let mut response = vec![0; message.len()];
+        tokio::time::timeout(Duration::from_secs(5), stream.read_exact(&mut response)).await??;
+        assert_eq!(response, message);
+        Ok(())
+    }
+
+    async fn stop_all(supervisor: &mut Supervisor) {
+        let names: Vec<String> = supervisor.running.keys().cloned().collect();
+        for name in names {
+            supervisor.stop(&name);
+        }
+        while let Some(res) = supervisor.tasks.join_next().await {
+            let (name, _, res) = res.unwrap();
+            assert!(res.is_ok(), "{name}: {res:?}");
+        }
+    }
+
+    fn temp_config(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("sirang-{name}-{}.toml", std::process::id()))
+    }
+
+    // Hands the supervisor the tunnels that stop, as its run loop does
+    async fn wait_stopped(supervisor: &mut Supervisor, count: usize) {
+        for _ in 0..count {
+            let joined =
+                tokio::time::timeout(Duration::from_secs(10), supervisor.tasks.join_next())
+                    .await
+                    .unwrap();
+            let (name, id, res) = joined.unwrap().unwrap();
+            supervisor.stopped(name, id, res, false);
+        }
+    }
+
+    // Answers every connection with its own name, to tell targets apart
+    async fn spawn_named_server(name: &'static [u8]) -> SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let _ = stream.write_all(name).await;
+            }
+        });
+
+        addr
+    }
+
+    // The name the target of the remote at `quic_addr` answers with
+    async fn target_name(quic_addr: SocketAddr) -> Vec<u8> {
+        let local = crate::TunnelBuilder::forward_local()
+            .cert(crate::PemSource::File(pem_paths().0.into()))
+            .remote_addr(quic_addr)
+            .local_addr("127.0.0.1:0".parse().unwrap())
+            .start()
+            .await
+            .unwrap();
+
+        let mut stream = TcpStream::connect(local.local_addr()).await.unwrap();
+        let mut name = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut name))
+            .await
+            .unwrap()
+            .unwrap();
+
+        drop(stream);
+        local.shutdown().await.unwrap();
+        name
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let echo_addr = spawn_echo_server().await;
+        let (old_target, new_target) = (
+            spawn_named_server(b"old").await,
+            spawn_named_server(b"new").await,
+        );
+
+        let (kept_local, removed_local) = (free_tcp_addr(), free_tcp_addr());
+        let kept = forward_pair("kept", free_udp_addr(), kept_local, echo_addr);
+        let removed = forward_pair("removed", free_udp_addr(), removed_local, echo_addr);
+        let changed_quic = free_udp_addr();
+        let changed_to = |target| forward_remote("changed", changed_quic, target);
+        let (added_quic, added_local) = (free_udp_addr(), free_tcp_addr());
+
+        let path = temp_config("reload");
+        let mut supervisor = start(&path, &(kept.clone() + &removed + &changed_to(old_target)));
+        let ids = |supervisor: &Supervisor, name: &str| supervisor.running[name].id;
+        let kept_ids = (
+            ids(&supervisor, "kept-remote"),
+            ids(&supervisor, "kept-local"),
+        );
+        let changed_id = ids(&supervisor, "changed");
+
+        let mut kept_stream = connect(kept_local).await;
+        let mut removed_stream = connect(removed_local).await;
+        assert_eq!(target_name(changed_quic).await, b"old");
+
+        std::fs::write(
+            &path,
+            kept + &changed_to(new_target)
+                + &forward_pair("added", added_quic, added_local, echo_addr),
+        )
+        .unwrap();
+        assert_eq!(
+            supervisor.reload().unwrap(),
+            "2 added, 2 removed, 1 changed, 2 unchanged"
+        );
+
+        // removed tunnels drain, their open stream keeps going until it is closed
+        echo(&mut removed_stream, b"draining").await.unwrap();
+        drop(removed_stream);
+        // both removed tunnels and the old instance of the changed one
+        wait_stopped(&mut supervisor, 3).await;
+
+        let mut running: Vec<&str> = supervisor.running.keys().map(String::as_str).collect();
+        running.sort();
+        assert_eq!(
+            running,
+            [
+                "added-local",
+                "added-remote",
+                "changed",
+                "kept-local",
+                "kept-remote"
+            ]
+        );
+
+        // the changed tunnel runs again with its new target
+        assert_ne!(ids(&supervisor, "changed"), changed_id);
+        assert_eq!(target_name(changed_quic).await, b"new");
+
+        // added tunnels started
+        drop(connect(added_local).await);
+
+        // and the unchanged ones never stopped, nor did the stream through them
+        assert_eq!(
+            (
+                ids(&supervisor, "kept-remote"),
+                ids(&supervisor, "kept-local")
+            ),
+            kept_ids
+        );
+        echo(&mut kept_stream, b"still there").await.unwrap();
+        assert_eq!(
+            supervisor.running["kept-remote"]
+                .tunnel
+                .config
+                .monitor()
+                .stats()
+                .total_streams,
+            1
+        );
+
+        drop(kept_stream);
+        stop_all(&mut supervisor).await;
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "otlp")]
+    #[test]
+    fn test_otlp_export() {
+        use std::{
+            io::{BufRead, BufReader, Read, Write},
+            sync::mpsc,
+            thread,
+        };
+
+        use tracing_subscriber::layer::SubscriberExt;
+
+        // stands in for a collector, passing on every export request it answers
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let (requests_tx, requests) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut reader = BufReader::new(stream.unwrap());
+                let requests_tx = requests_tx.clone();
+                thread::spawn(move || loop {
+                    let mut request_line = String::new();
+                    if reader.read_line(&mut request_line).unwrap_or(0) == 0 {
+                        return;
+                    }
+
+                    let mut length = 0;
+                    loop {
+                        let mut line = String::new();
+                        reader.read_line(&mut line).unwrap();
+                        if line == "\r\n" {
+                            break;
+                        }
+                        if let Some(value) = line.to_lowercase().strip_prefix("content-length:") {
+                            length = value.trim().parse().unwrap();
+                        }
+                    }
+                    let mut body = vec![0; length];
+                    reader.read_exact(&mut body).unwrap();
+                    reader
+                        .get_mut()
+                        .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                        .unwrap();
+
+                    let _ = requests_tx.send((request_line, body));
+                });
+            }
+        });
+
+        // the tunnels run on this thread, so they log to this subscriber
+        let layer = crate::logging::otlp_layer(&endpoint).unwrap();
+        let _subscriber =
+            tracing::subscriber::set_default(tracing_subscriber::registry().with(layer));
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        let path = temp_config("otlp");
+        runtime.block_on(async {
+            let echo_addr = spawn_echo_server().await;
+            let local_addr = free_tcp_addr();
+            let source = forward_pair("traced", free_udp_addr(), local_addr, echo_addr);
+            let mut supervisor = start(&path, &source);
+
+            let stream = connect(local_addr).await;
+            drop(stream);
+            stop_all(&mut supervisor).await;
+        });
+        crate::logging::flush();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut body = Vec::new();
+        while let Ok((request_line, request)) = requests.recv_timeout(Duration::from_secs(1)) {
+            assert!(
+                request_line.starts_with("POST /v1/traces "),
+                "{request_line}"
+            );
+            body.extend(request);
+        }
+
+        // both tunnel spans arrive with their names
+        let contains = |needle: &[u8]| body.windows(needle.len()).any(|window| window == needle);
+        assert!(contains(b"traced-local"));
+        assert!(contains(b"traced-remote"));
+
+        // and the stream spans on both ends with the id of the one stream, which follows the
+        // attribute key as an AnyValue holding a string
+        let ids: Vec<&[u8]> = body
+            .windows(b"stream_id".len())
+            .enumerate()
+            .filter(|(_, window)| *window == b"stream_id")
+            .map(|(at, _)| at + b"stream_id".len())
+            .filter(|&at| body[at] == 0x12 && body[at + 2] == 0x0a && body[at + 3] == 16)
+            .map(|at| &body[at + 4..at + 20])
+            .collect();
+        assert_eq!(ids.len(), 2, "{ids:?}");
+        assert_eq!(ids[0], ids[1]);
+        assert!(ids[0].iter().all(u8::is_ascii_hexdigit));
+    }
+}
@@ -0,0 +1,222 @@
+// Where a certificate or key is read from. Besides a file, `-` reads it from stdin and a value
+// that is itself PEM is used as is, so secrets can be passed inline through the environment
+use std::{
+    convert::Infallible,
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::OnceLock,
+};
+
+use serde::Deserialize;
+
+use crate::errors::Error;
+
+const PEM_MARKER: &str = "-----BEGIN";
+
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+#[serde(from = "String")]
+pub enum PemSource {
+    File(PathBuf),
+    Stdin,
+    Inline(String),
+}
+
+impl From<String> for PemSource {
+    fn from(value: String) -> Self {
+        if value == "-" {
+            PemSource::Stdin
+        } else if value.trim_start().starts_with(PEM_MARKER) {
+            PemSource::Inline(value)
+        } else {
+            PemSource::File(PathBuf::from(value))
+        }
+    }
+}
+
+impl FromStr for PemSource {
+    type Err = Infallible;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Ok(PemSource::from(value.to_string()))
+    }
+}
+
+// Never shows inline contents, which may be a private key
+impl fmt::Display for PemSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PemSource::File(path) => write!(f, "{}", path.display()),
+            PemSource::Stdin => write!(f, "stdin"),
+            PemSource::Inline(_) => write!(f, "inline pem"),
+        }
+    }
+}
+
+impl PemSource {
+    // Resolves a relative file path against a directory, such as the config file's
+    pub fn relative_to(self, base_dir: &Path) -> Self {
+        match self {
+            PemSource::File(path) => PemSource::File(base_dir.join(path)),
+            source => source,
+        }
+    }
+
+    pub fn read_cert(&self) -> Result<String, Error> {
+        self.read("certificate", "CERTIFICATE")
+    }
+
+    // Key files any user can write to are refused, other group or world access is warned about
+    pub fn read_key(&self) -> Result<String, Error> {
+        if let PemSource::File(path) = self {
+            check_key_permissions(path)?;
+        }
+
+        self.read("key", "PRIVATE KEY")
+    }
+
+    // Only keeps the blocks with the label, so one PEM holding both can be given for each
+    fn read(&self, what: &str, label: &str) -> Result<String, Error> {
+        let pem = match self {
+            PemSource::File(path) => std::fs::read_to_string(path).map_err(|e| {
+                Error::Tls(format!("unable to read {what} {}: {e}", path.display()))
+            })?,
+            PemSource::Stdin => read_stdin()
+                .map_err(|e| Error::Tls(format!("unable to read {what} from stdin: {e}")))?,
+            PemSource::Inline(pem) => pem.clone(),
+        };
+
+        if !pem.contains(PEM_MARKER) {
+            return Err(Error::Tls(format!("{what} from {self} is not PEM encoded")));
+        }
+
+        let blocks = select_blocks(&pem, label);
+        if blocks.is_empty() {
+            return Err(Error::Tls(format!(
+                "{what} from {self} has no {label} block"
+            )));
+        }
+
+        Ok(blocks)
+    }
+}
+
+fn select_blocks(pem: &str, label: &str) -> String {
+    let mut selected = String::new();
+    let mut keep = false;
+
+    for line in pem.lines() {
+        if let Some(begin) = line.trim().strip_prefix(PEM_MARKER) {
+            keep = begin.contains(label);
+        }
+        if keep {
+            selected.push_str(line);
+            selected.push('\n');
+        }
+        if line.trim().starts_with("-----END") {
+            keep = false;
+        }
+    }
+
+    selected
+}
+
+// Stdin can only be read once, so a certificate and key piped in together are both read
+// from the same contents
+fn read_stdin() -> Result<String, String> {
+    static STDIN: OnceLock<Result<String, String>> = OnceLock::new();
+
+    STDIN
+        .get_or_init(|| std::io::read_to_string(std::io::stdin()).map_err(|e| e.to_string()))
+        .clone()
+}
+
+#[cfg(unix)]
+fn check_key_permissions(path: &Path) -> Result<(), Error> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = match std::fs::metadata(path) {
+        Ok(metadata) => metadata.permissions().mode() & 0o777,
+        // reading it reports the error
+        Err(_) => return Ok(()),
+    };
+
+    if mode & 0o002 != 0 {
+        return Err(Error::Tls(format!(
+            "key {} is writable by any user (mode {mode:o}), restrict it with chmod 600",
+            path.display()
+        )));
+    }
+    if mode & 0o066 != 0 {
+        tracing::warn!(
+            "Tls key {} is accessible by other users (mode {mode:o}), consider chmod 600",
+            path.display()
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_key_permissions(_path: &Path) -> Result<(), Error> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::PemSource;
+
+    #[test]
+    fn test_pem_sources() {
+        assert_eq!(PemSource::from("-".to_string()), PemSource::Stdin);
+        assert_eq!(
+            PemSource::from("certs/cert.pem".to_string()).relative_to(Path::new("/etc/sirang")),
+            PemSource::File(PathBuf::from("/etc/sirang/certs/cert.pem"))
+        );
+
+        let pem = std::fs::read_to_string("test_cert.pem").unwrap();
+        let inline = PemSource::from(pem.clone());
+        assert_eq!(inline.to_string(), "inline pem");
+        assert_eq!(inline.read_cert().unwrap(), pem);
+
+        let key = std::fs::read_to_string("test_key.pem").unwrap();
+        let combined = PemSource::from(format!("{pem}{key}"));
+        assert_eq!(combined.read_cert().unwrap(), pem);
+        assert_eq!(combined.read_key().unwrap(), key);
+        let err = inline.read_key().unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("inline pem has no PRIVATE KEY block"),
+            "{err}"
+        );
+
+        let err = PemSource::from("Cargo.toml".to_string())
+            .read_cert()
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("Cargo.toml is not PEM encoded"),
+            "{err}"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_key_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("sirang-key-{}.pem", std::process::id()));
+        std::fs::copy("test_key.pem", &path).unwrap();
+        let key = PemSource::File(path.clone());
+
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).unwrap();
+        assert!(key.read_key().is_ok());
+
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o666)).unwrap();
+        let err = key.read_key().unwrap_err();
+        assert!(err.to_string().contains("writable by any user"), "{err}");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
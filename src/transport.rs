@@ -0,0 +1,318 @@
+// Quic transport statistics of each connection, collected from s2n-quic events: round trip
+// times, the congestion window, lost packets, congestion events and the path MTU. A lossy or
+// slow network shows here while a slow target shows in the dial latency and stream durations.
+// Open connections are logged at debug level every 30s, served with the metrics and answered
+// to the admin `quic` command
+use std::{
+    collections::BTreeMap,
+    net::{Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use s2n_quic::provider::event::{
+    events::{Congestion, ConnectionStarted, MtuUpdated, PacketLost, PacketSent, RecoveryMetrics},
+    supervisor, ConnectionInfo, ConnectionMeta,
+};
+use serde::{Serialize, Serializer};
+
+const LOG_INTERVAL: Duration = Duration::from_secs(30);
+
+// What is known about one quic connection so far
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[non_exhaustive]
+pub struct QuicStats {
+    pub peer: SocketAddr,
+    #[serde(rename = "rtt_ms", serialize_with = "millis")]
+    pub smoothed_rtt: Duration,
+    #[serde(rename = "min_rtt_ms", serialize_with = "millis")]
+    pub min_rtt: Duration,
+    #[serde(rename = "latest_rtt_ms", serialize_with = "millis")]
+    pub latest_rtt: Duration,
+    #[serde(rename = "rtt_variance_ms", serialize_with = "millis")]
+    pub rtt_variance: Duration,
+    // in bytes
+    pub congestion_window: u32,
+    pub bytes_in_flight: u32,
+    // probe timeouts in a row, more than a couple means the peer stopped answering
+    pub pto_count: u32,
+    pub packets_sent: u64,
+    pub packets_lost: u64,
+    pub bytes_lost: u64,
+    // times the congestion window shrank because of loss or ECN
+    pub congestion_events: u64,
+    pub mtu: u16,
+}
+
+impl Default for QuicStats {
+    fn default() -> Self {
+        Self {
+            peer: SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            smoothed_rtt: Duration::ZERO,
+            min_rtt: Duration::ZERO,
+            latest_rtt: Duration::ZERO,
+            rtt_variance: Duration::ZERO,
+            congestion_window: 0,
+            bytes_in_flight: 0,
+            pto_count: 0,
+            packets_sent: 0,
+            packets_lost: 0,
+            bytes_lost: 0,
+            congestion_events: 0,
+            mtu: 0,
+        }
+    }
+}
+
+// To the microsecond
+fn millis<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_micros() as f64 / 1000.0)
+}
+
+// Counters summed over every connection of a tunnel, closed ones included
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct QuicTotals {
+    pub packets_sent: u64,
+    pub packets_lost: u64,
+    pub bytes_lost: u64,
+    pub congestion_events: u64,
+}
+
+impl QuicTotals {
+    fn add(&mut self, stats: &QuicStats) {
+        self.packets_sent += stats.packets_sent;
+        self.packets_lost += stats.packets_lost;
+        self.bytes_lost += stats.bytes_lost;
+        self.congestion_events += stats.congestion_events;
+    }
+}
+
+// The quic connections of a tunnel
+#[derive(Clone, Default)]
+pub(crate) struct Transport {
+    connections: Arc<Mutex<Connections>>,
+}
+
+#[derive(Default)]
+struct Connections {
+    next_id: u64,
+    open: BTreeMap<u64, Arc<Mutex<QuicStats>>>,
+    // counters of the connections already closed
+    closed: QuicTotals,
+}
+
+impl Transport {
+    pub(crate) fn connections(&self) -> Vec<QuicStats> {
+        let connections = self.connections.lock().unwrap();
+        connections
+            .open
+            .values()
+            .map(|stats| *stats.lock().unwrap())
+            .collect()
+    }
+
+    pub(crate) fn totals(&self) -> QuicTotals {
+        let connections = self.connections.lock().unwrap();
+        let mut totals = connections.closed;
+        for stats in connections.open.values() {
+            totals.add(&stats.lock().unwrap());
+        }
+        totals
+    }
+
+    // An event subscriber for the endpoint of the tunnel
+    pub(crate) fn subscriber(&self) -> Subscriber {
+        Subscriber {
+            transport: self.clone(),
+        }
+    }
+
+    fn track(&self) -> Tracked {
+        let stats = Arc::new(Mutex::new(QuicStats::default()));
+        let mut connections = self.connections.lock().unwrap();
+        let id = connections.next_id;
+        connections.next_id += 1;
+        connections.open.insert(id, stats.clone());
+
+        Tracked {
+            transport: self.clone(),
+            id,
+            stats,
+            logged: Instant::now(),
+        }
+    }
+}
+
+pub(crate) struct Subscriber {
+    transport: Transport,
+}
+
+// The stats of a connection, counted as closed once s2n-quic drops it
+pub(crate) struct Tracked {
+    transport: Transport,
+    id: u64,
+    stats: Arc<Mutex<QuicStats>>,
+    logged: Instant,
+}
+
+impl Tracked {
+    fn update(&self, update: impl FnOnce(&mut QuicStats)) {
+        update(&mut self.stats.lock().unwrap());
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        let stats = *self.stats.lock().unwrap();
+        let mut connections = self.transport.connections.lock().unwrap();
+        connections.open.remove(&self.id);
+        connections.closed.add(&stats);
+    }
+}
+
+impl s2n_quic::provider::event::Subscriber for Subscriber {
+    type ConnectionContext = Tracked;
+
+    fn create_connection_context(
+        &mut self,
+        _meta: &ConnectionMeta,
+        _info: &ConnectionInfo,
+    ) -> Self::ConnectionContext {
+        self.transport.track()
+    }
+
+    fn supervisor_timeout(
+        &mut self,
+        _conn_context: &mut Self::ConnectionContext,
+        _meta: &ConnectionMeta,
+        _context: &supervisor::Context,
+    ) -> Option<Duration> {
+        Some(LOG_INTERVAL)
+    }
+
+    fn on_supervisor_timeout(
+        &mut self,
+        conn_context: &mut Self::ConnectionContext,
+        _meta: &ConnectionMeta,
+        _context: &supervisor::Context,
+    ) -> supervisor::Outcome {
+        // other subscribers may ask to be called sooner
+        if conn_context.logged.elapsed() >= LOG_INTERVAL {
+            conn_context.logged = Instant::now();
+            let stats = *conn_context.stats.lock().unwrap();
+            tracing::debug!(
+                "Quic connection with {}: rtt {:?} (min {:?}, variance {:?}), congestion window {} bytes with {} in flight, {} of {} packets lost, {} congestion events, mtu {}",
+                stats.peer,
+                stats.smoothed_rtt,
+                stats.min_rtt,
+                stats.rtt_variance,
+                stats.congestion_window,
+                stats.bytes_in_flight,
+                stats.packets_lost,
+                stats.packets_sent,
+                stats.congestion_events,
+                stats.mtu
+            );
+        }
+
+        supervisor::Outcome::default()
+    }
+
+    fn on_connection_started(
+        &mut self,
+        context: &mut Self::ConnectionContext,
+        _meta: &ConnectionMeta,
+        event: &ConnectionStarted,
+    ) {
+        context.update(|stats| stats.peer = SocketAddr::from(&event.path.remote_addr));
+    }
+
+    fn on_packet_sent(
+        &mut self,
+        context: &mut Self::ConnectionContext,
+        _meta: &ConnectionMeta,
+        _event: &PacketSent,
+    ) {
+        context.update(|stats| stats.packets_sent += 1);
+    }
+
+    fn on_packet_lost(
+        &mut self,
+        context: &mut Self::ConnectionContext,
+        _meta: &ConnectionMeta,
+        event: &PacketLost,
+    ) {
+        context.update(|stats| {
+            stats.packets_lost += 1;
+            stats.bytes_lost += u64::from(event.bytes_lost);
+        });
+    }
+
+    fn on_recovery_metrics(
+        &mut self,
+        context: &mut Self::ConnectionContext,
+        _meta: &ConnectionMeta,
+        event: &RecoveryMetrics,
+    ) {
+        context.update(|stats| {
+            // the peer may have moved to another address
+            if event.path.is_active {
+                stats.peer = SocketAddr::from(&event.path.remote_addr);
+            }
+            stats.smoothed_rtt = event.smoothed_rtt;
+            stats.min_rtt = event.min_rtt;
+            stats.latest_rtt = event.latest_rtt;
+            stats.rtt_variance = event.rtt_variance;
+            stats.congestion_window = event.congestion_window;
+            stats.bytes_in_flight = event.bytes_in_flight;
+            stats.pto_count = event.pto_count;
+        });
+    }
+
+    fn on_congestion(
+        &mut self,
+        context: &mut Self::ConnectionContext,
+        _meta: &ConnectionMeta,
+        _event: &Congestion,
+    ) {
+        context.update(|stats| stats.congestion_events += 1);
+    }
+
+    fn on_mtu_updated(
+        &mut self,
+        context: &mut Self::ConnectionContext,
+        _meta: &ConnectionMeta,
+        event: &MtuUpdated,
+    ) {
+        context.update(|stats| stats.mtu = event.mtu);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Transport;
+
+    #[test]
+    fn test_totals() {
+        let transport = Transport::default();
+        let first = transport.track();
+        let second = transport.track();
+        first.update(|stats| {
+            stats.packets_sent = 100;
+            stats.packets_lost = 3;
+        });
+        second.update(|stats| stats.packets_sent = 50);
+        assert_eq!(transport.connections().len(), 2);
+
+        // a closed connection still counts towards the totals
+        drop(first);
+        assert_eq!(transport.connections().len(), 1);
+        let totals = transport.totals();
+        assert_eq!(totals.packets_sent, 150);
+        assert_eq!(totals.packets_lost, 3);
+
+        let json = serde_json::to_string(&transport.connections()[0]).unwrap();
+        assert!(json.contains(r#""rtt_ms":0.0,"#), "{json}");
+        assert!(json.contains(r#""packets_sent":50,"#), "{json}");
+    }
+}
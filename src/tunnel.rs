@@ -0,0 +1,288 @@
+// Runs a tunnel from within another application. The builder takes the same settings as a
+// config file entry and is validated the same way, and the handle it returns reports on the
+// running tunnel and stops it:
+//
+//   let tunnel = TunnelBuilder::forward_local()
+//       .cert(PemSource::File("cert.pem".into()))
+//       .remote_addr("203.0.113.5:4433".parse()?)
+//       .local_addr("127.0.0.1:0".parse()?)
+//       .start()
+//       .await?;
+//
+//   println!("tunnel listening on {}", tunnel.local_addr());
+//   tunnel.shutdown().await?;
+//
+// Unlike the command line, embedded tunnels ignore SIGINT and SIGTERM and only stop through
+// their handle, or once it is dropped
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::{sync::broadcast, task::JoinHandle};
+use tracing::Instrument;
+
+use crate::{
+    access::AccessLogFormat,
+    compress::Compression,
+    config::{self, Kind, Side, TunnelConfig, TunnelDef},
+    errors::Error,
+    layer::{Layers, StreamLayer},
+    monitor::{Event, Hooks, Monitor, Stats},
+    proxy::{Cidr, ProxyProtocol},
+    shutdown::{self, StopHandle},
+    tls::PemSource,
+    transport::QuicStats,
+};
+
+#[derive(Clone)]
+pub struct TunnelBuilder {
+    def: TunnelDef,
+    hooks: Vec<Arc<dyn Hooks>>,
+    layers: Layers,
+}
+
+impl TunnelBuilder {
+    fn new(kind: Kind, side: Side) -> Self {
+        Self {
+            def: TunnelDef {
+                kind: Some(kind),
+                side: Some(side),
+                ..TunnelDef::default()
+            },
+            hooks: Vec::new(),
+            layers: Layers::default(),
+        }
+    }
+
+    // Accepts tcp connections and forwards them to a remote forward tunnel
+    pub fn forward_local() -> Self {
+        Self::new(Kind::Forward, Side::Local)
+    }
+
+    // Takes streams from local forward tunnels and connects them to its forward address
+    pub fn forward_remote() -> Self {
+        Self::new(Kind::Forward, Side::Remote)
+    }
+
+    // Connects to a remote reverse tunnel and serves its tcp clients from a local address
+    pub fn reverse_local() -> Self {
+        Self::new(Kind::Reverse, Side::Local)
+    }
+
+    // Accepts tcp connections for the local reverse tunnel connected to it
+    pub fn reverse_remote() -> Self {
+        Self::new(Kind::Reverse, Side::Remote)
+    }
+
+    pub fn cert(mut self, cert: PemSource) -> Self {
+        self.def.cert = Some(cert);
+        self
+    }
+
+    pub fn key(mut self, key: PemSource) -> Self {
+        self.def.key = Some(key);
+        self
+    }
+
+    pub fn local_addr(mut self, local_addr: SocketAddr) -> Self {
+        self.def.localaddr = Some(local_addr);
+        self
+    }
+
+    pub fn remote_addr(mut self, remote_addr: SocketAddr) -> Self {
+        self.def.remoteaddr = Some(remote_addr);
+        self
+    }
+
+    pub fn quic_addr(mut self, quic_addr: SocketAddr) -> Self {
+        self.def.quicaddr = Some(quic_addr);
+        self
+    }
+
+    pub fn forward_addr(mut self, forward_addr: SocketAddr) -> Self {
+        self.def.forwardaddr = Some(forward_addr);
+        self
+    }
+
+    pub fn tcp_addr(mut self, tcp_addr: SocketAddr) -> Self {
+        self.def.tcpaddr = Some(tcp_addr);
+        self
+    }
+
+    // Another host:port clients of a forward remote may ask for
+    pub fn allow_target(mut self, target: impl Into<String>) -> Self {
+        self.def
+            .allowtarget
+            .get_or_insert_with(Vec::new)
+            .push(target.into());
+        self
+    }
+
+    pub fn buffer_size(mut self, buffer_size: usize) -> Self {
+        self.def.buffersize = Some(buffer_size);
+        self
+    }
+
+    // Rounded down to whole seconds
+    pub fn drain_timeout(mut self, drain_timeout: Duration) -> Self {
+        self.def.draintimeout = Some(drain_timeout.as_secs());
+        self
+    }
+
+    pub fn goaway(mut self, delay: Duration) -> Self {
+        self.def.goaway = Some(delay.as_secs());
+        self
+    }
+
+    pub fn redirect_addr(mut self, redirect_addr: SocketAddr) -> Self {
+        self.def.redirectaddr = Some(redirect_addr);
+        self
+    }
+
+    pub fn handover(mut self, path: impl Into<PathBuf>) -> Self {
+        self.def.handover = Some(path.into());
+        self
+    }
+
+    // Only for a forward local or reverse remote, the end that opens the streams or picks for them
+    pub fn compress(mut self, compression: Compression) -> Self {
+        self.def.compress = Some(compression);
+        self
+    }
+
+    // Only for a forward remote or reverse local, the end that connects to the target
+    pub fn proxy_protocol(mut self, version: ProxyProtocol) -> Self {
+        self.def.proxyprotocol = Some(version);
+        self
+    }
+
+    // Load balancers trusted to name their clients in PROXY headers, for a forward local or
+    // reverse remote. For a forward remote, the local instances trusted to name their clients
+    pub fn accept_proxy(mut self, trusted: Cidr) -> Self {
+        self.def
+            .acceptproxy
+            .get_or_insert_with(Vec::new)
+            .push(trusted);
+        self
+    }
+
+    // Appends a record of every stream to the file, or stdout for `-`
+    pub fn access_log(mut self, path: impl Into<PathBuf>) -> Self {
+        self.def.accesslog = Some(path.into());
+        self
+    }
+
+    pub fn access_log_format(mut self, format: AccessLogFormat) -> Self {
+        self.def.accesslogformat = Some(format);
+        self
+    }
+
+    // Called as connections and streams of the tunnel come and go, in the order they were added
+    pub fn hooks(mut self, hooks: impl Hooks) -> Self {
+        self.hooks.push(Arc::new(hooks));
+        self
+    }
+
+    // Wraps every stream of the tunnel, over the layers added before it
+    pub fn layer(mut self, layer: impl StreamLayer) -> Self {
+        self.layers.push(Arc::new(layer));
+        self
+    }
+
+    // Checks the settings and reads the certificate and key. Settings are named after their
+    // config file keys in errors, e.g. `remoteaddr` for remote_addr
+    pub fn build(&self) -> Result<TunnelConfig, Error> {
+        let (mut config, outputs) = config::build_tunnel(self.def.clone(), Path::new(""))?;
+        outputs.register(config.monitor())?;
+        config.set_layers(self.layers.clone());
+        for hooks in &self.hooks {
+            config.monitor().add_hooks(hooks.clone());
+        }
+        Ok(config)
+    }
+
+    // Starts the tunnel in the background and waits until it accepts connections
+    pub async fn start(&self) -> Result<TunnelHandle, Error> {
+        let mut config = self.build()?;
+
+        let (stop, stop_signal) = shutdown::stop_signal();
+        config.set_stop(stop_signal.without_process_signals());
+        let monitor = config.monitor().clone();
+
+        let mut events = monitor.subscribe();
+        let mut task = tokio::spawn(config.run().in_current_span());
+
+        let local_addr = loop {
+            tokio::select! {
+                event = events.recv() => {
+                    if let Ok(Event::Listening(local_addr)) = event {
+                        break local_addr;
+                    }
+                }
+                res = &mut task => {
+                    let e = match joined(res) {
+                        Ok(()) => Error::Closed("tunnel stopped before accepting connections".to_string()),
+                        Err(e) => e,
+                    };
+                    return Err(e);
+                }
+            }
+        };
+
+        Ok(TunnelHandle {
+            local_addr,
+            monitor,
+            stop,
+            task,
+        })
+    }
+}
+
+// A running tunnel, dropping the handle stops it
+pub struct TunnelHandle {
+    local_addr: SocketAddr,
+    monitor: Monitor,
+    stop: StopHandle,
+    task: JoinHandle<Result<(), Error>>,
+}
+
+impl TunnelHandle {
+    // Where the tunnel accepts connections: the tcp listener of a local forward tunnel, the quic
+    // server of a remote one, and the tcp address of its remote for a local reverse tunnel
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.monitor.stats()
+    }
+
+    // Round trip times, congestion window, losses and MTU of the tunnel's open quic connections
+    pub fn quic_stats(&self) -> Vec<QuicStats> {
+        self.monitor.quic_stats()
+    }
+
+    // Events from now on, a subscriber falling far behind misses the oldest ones
+    pub fn events(&self) -> broadcast::Receiver<Event> {
+        self.monitor.subscribe()
+    }
+
+    // Stops accepting, lets active streams drain as on SIGTERM and waits for the tunnel to stop
+    pub async fn shutdown(self) -> Result<(), Error> {
+        self.stop.stop();
+        joined(self.task.await)
+    }
+
+    // Waits for the tunnel to stop on its own, such as when its remote shuts down for good
+    pub async fn wait(self) -> Result<(), Error> {
+        let _stop = self.stop;
+        joined(self.task.await)
+    }
+}
+
+fn joined(res: Result<Result<(), Error>, tokio::task::JoinError>) -> Result<(), Error> {
+    res.unwrap_or_else(|e| Err(Error::Io(std::io::Error::other(e))))
+}
@@ -0,0 +1,149 @@
+#[cfg(all(test, feature = "cli", target_os = "linux"))]
+mod handover_tests {
+
+    use std::{
+        net::{SocketAddr, UdpSocket},
+        os::unix::fs::MetadataExt,
+        path::{Path, PathBuf},
+        process::{Child, Command, Stdio},
+        time::Duration,
+    };
+
+    use sirang::{PemSource, TunnelBuilder};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    async fn spawn_echo_server() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = stream.split();
+                    let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                });
+            }
+        });
+
+        addr
+    }
+
+    fn cert() -> PemSource {
+        PemSource::Inline(include_str!(".././test_cert.pem").to_string())
+    }
+
+    // A remote process killed when the test ends, however it ends
+    struct Remote(Child);
+
+    impl Drop for Remote {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    fn spawn_remote(quic_addr: SocketAddr, forward_addr: SocketAddr, handover: &Path) -> Remote {
+        let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let child = Command::new(env!("CARGO_BIN_EXE_sirang"))
+            .args(["forward", "--draintimeout", "10", "remote"])
+            .arg("--cert")
+            .arg(manifest_dir.join("test_cert.pem"))
+            .arg("--key")
+            .arg(manifest_dir.join("test_key.pem"))
+            .arg("--quicaddr")
+            .arg(quic_addr.to_string())
+            .arg("--forwardaddr")
+            .arg(forward_addr.to_string())
+            .arg("--handover")
+            .arg(handover)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+
+        Remote(child)
+    }
+
+    // Waits for a process to bind the handover socket, other than the one with `previous` inode
+    async fn wait_for_handover_socket(path: &Path, previous: Option<u64>) -> u64 {
+        tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                match std::fs::metadata(path) {
+                    Ok(metadata) if Some(metadata.ino()) != previous => return metadata.ino(),
+                    _ => tokio::time::sleep(Duration::from_millis(50)).await,
+                }
+            }
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn echo(stream: &mut TcpStream, message: &[u8]) {
+        stream.write_all(message).await.unwrap();
+        let mut response = vec![0; message.len()];
+        tokio::time::timeout(Duration::from_secs(5), stream.read_exact(&mut response))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(response, message);
+    }
+
+    #[tokio::test]
+    async fn test_handover_keeps_open_stream() {
+        let echo_addr = spawn_echo_server().await;
+        let quic_addr = UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let handover: PathBuf =
+            std::env::temp_dir().join(format!("sirang-handover-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&handover);
+
+        let mut old = spawn_remote(quic_addr, echo_addr, &handover);
+        let old_socket = wait_for_handover_socket(&handover, None).await;
+
+        let local = TunnelBuilder::forward_local()
+            .cert(cert())
+            .remote_addr(quic_addr)
+            .local_addr("127.0.0.1:0".parse().unwrap())
+            .drain_timeout(Duration::from_secs(1))
+            .start()
+            .await
+            .unwrap();
+
+        let mut open_stream = TcpStream::connect(local.local_addr()).await.unwrap();
+        echo(&mut open_stream, b"before").await;
+
+        let _new = spawn_remote(quic_addr, echo_addr, &handover);
+        wait_for_handover_socket(&handover, Some(old_socket)).await;
+
+        // packets of the old connection have to keep reaching the old process, any of them
+        // landing on the new one would be answered with a stateless reset
+        for i in 0..20 {
+            echo(&mut open_stream, format!("during {i}").as_bytes()).await;
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        let mut new_stream = TcpStream::connect(local.local_addr()).await.unwrap();
+        echo(&mut new_stream, b"new").await;
+        drop(new_stream);
+
+        // the old process exits once its last stream is done
+        drop(open_stream);
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while old.0.try_wait().unwrap().is_none() {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        let mut after_stream = TcpStream::connect(local.local_addr()).await.unwrap();
+        echo(&mut after_stream, b"after").await;
+
+        let _ = std::fs::remove_file(&handover);
+    }
+}
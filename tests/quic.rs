@@ -3,8 +3,9 @@ mod quic_tests {
 
     use std::{net::SocketAddr, str::FromStr};
 
+    use bytes::Bytes;
     use sirang::{
-        common::proto::ProtoCommand,
+        monitor::Monitor,
         quic::{new_quic_connection, new_quic_server},
     };
 
@@ -14,6 +15,7 @@ mod quic_tests {
             SocketAddr::from_str("127.0.0.1:0").unwrap(),
             include_str!(".././test_cert.pem"),
             include_str!(".././test_key.pem"),
+            &Monitor::default(),
         )
         .await
         .unwrap();
@@ -21,24 +23,26 @@ mod quic_tests {
         let new_conn_result = new_quic_connection(
             server.local_addr().unwrap(),
             include_str!(".././test_cert.pem"),
+            &Monitor::default(),
         )
         .await;
-        assert_eq!(new_conn_result.is_ok(), true);
+        assert!(new_conn_result.is_ok());
     }
 
     #[tokio::test]
     async fn test_create_new_quic_server() {
         let socket_addr_result = SocketAddr::from_str("127.0.0.1:0");
-        assert_eq!(socket_addr_result.is_ok(), true);
+        assert!(socket_addr_result.is_ok());
         let socket_addr = socket_addr_result.unwrap();
 
         let new_server_result = new_quic_server(
             socket_addr,
             include_str!(".././test_cert.pem"),
             include_str!(".././test_key.pem"),
+            &Monitor::default(),
         )
         .await;
-        assert_eq!(new_server_result.is_ok(), true);
+        assert!(new_server_result.is_ok());
     }
 
     #[tokio::test]
@@ -47,57 +51,57 @@ mod quic_tests {
             SocketAddr::from_str("127.0.0.1:0").unwrap(),
             include_str!(".././test_cert.pem"),
             include_str!(".././test_key.pem"),
+            &Monitor::default(),
         )
         .await
         .unwrap();
 
-        let mut client_conn = new_quic_connection(
-            server.local_addr().unwrap(),
-            include_str!(".././test_cert.pem"),
-        )
-        .await
-        .unwrap();
-        assert_eq!(client_conn.keep_alive(true).is_ok(), true);
+        let server_addr = server.local_addr().unwrap();
+        let monitor = Monitor::default();
+        let mut client_conn =
+            new_quic_connection(server_addr, include_str!(".././test_cert.pem"), &monitor)
+                .await
+                .unwrap();
+        assert!(client_conn.keep_alive(true).is_ok());
 
         tokio::spawn(async move {
             let conn_result = server.accept().await;
-            assert_eq!(conn_result.is_some(), true);
+            assert!(conn_result.is_some());
 
             let mut conn = conn_result.unwrap();
 
             let bdstream_result = conn.accept_bidirectional_stream().await;
-            assert_eq!(bdstream_result.is_ok(), true);
+            assert!(bdstream_result.is_ok());
 
             let bdstream_option = bdstream_result.unwrap();
-            assert_eq!(bdstream_option.is_some(), true);
+            assert!(bdstream_option.is_some());
 
             let mut bdstream = bdstream_option.unwrap();
 
             let recv_data_result = bdstream.receive().await;
-            assert_eq!(recv_data_result.is_ok(), true);
+            assert!(recv_data_result.is_ok());
 
             let recv_data_option = recv_data_result.unwrap();
-            assert_eq!(recv_data_option.is_some(), true);
+            assert!(recv_data_option.is_some());
 
-            let client_cmd_res = ProtoCommand::serialize(recv_data_option.unwrap());
-            assert_eq!(client_cmd_res.is_some(), true);
+            assert_eq!(recv_data_option.unwrap(), Bytes::from_static(b"ACK"));
 
-            assert_eq!(
-                bdstream.send(ProtoCommand::ACK.deserialize()).await.is_ok(),
-                true
-            );
+            assert!(bdstream.send(Bytes::from_static(b"ACK")).await.is_ok());
 
             conn.close(6u32.into());
         });
 
         let bdstream_result = client_conn.open_bidirectional_stream().await;
-        assert_eq!(bdstream_result.is_ok(), true);
+        assert!(bdstream_result.is_ok());
         let mut bdstream = bdstream_result.unwrap();
 
-        assert_eq!(
-            bdstream.send(ProtoCommand::ACK.deserialize()).await.is_ok(),
-            true
-        );
+        assert!(bdstream.send(Bytes::from_static(b"ACK")).await.is_ok());
+
+        // the client's endpoint collects the transport statistics of its connection
+        let stats = monitor.quic_stats();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].peer, server_addr);
+        assert!(stats[0].packets_sent > 0);
 
         client_conn.close(6u32.into());
     }
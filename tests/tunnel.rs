@@ -0,0 +1,839 @@
+#[cfg(test)]
+mod tunnel_tests {
+
+    use std::{
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use sirang::{
+        layer::StreamInfo, Compression, Error, Event, Hooks, PemSource, ProxyProtocol, StreamEnd,
+        StreamId, TunnelBuilder,
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    async fn spawn_echo_server() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = stream.split();
+                    let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                });
+            }
+        });
+
+        addr
+    }
+
+    fn cert() -> PemSource {
+        PemSource::Inline(include_str!(".././test_cert.pem").to_string())
+    }
+
+    fn key() -> PemSource {
+        PemSource::Inline(include_str!(".././test_key.pem").to_string())
+    }
+
+    #[tokio::test]
+    async fn test_forward_tunnel() {
+        let echo_addr = spawn_echo_server().await;
+
+        let remote = TunnelBuilder::forward_remote()
+            .cert(cert())
+            .key(key())
+            .quic_addr("127.0.0.1:0".parse().unwrap())
+            .forward_addr(echo_addr)
+            .drain_timeout(Duration::from_secs(1))
+            .start()
+            .await
+            .unwrap();
+        let mut remote_events = remote.events();
+
+        let local = TunnelBuilder::forward_local()
+            .cert(cert())
+            .remote_addr(remote.local_addr())
+            .local_addr("127.0.0.1:0".parse().unwrap())
+            .drain_timeout(Duration::from_secs(1))
+            .start()
+            .await
+            .unwrap();
+        assert_ne!(local.local_addr().port(), 0);
+
+        let mut tcp_stream = TcpStream::connect(local.local_addr()).await.unwrap();
+        tcp_stream.write_all(b"ping").await.unwrap();
+        tcp_stream.shutdown().await.unwrap();
+        let mut response = Vec::new();
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            tcp_stream.read_to_end(&mut response),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(response, b"ping");
+
+        // the stream is counted until both ends of it are done
+        let closed = async {
+            loop {
+                if let Ok(Event::StreamClosed(addr)) = remote_events.recv().await {
+                    break addr;
+                }
+            }
+        };
+        let closed = tokio::time::timeout(Duration::from_secs(5), closed)
+            .await
+            .unwrap();
+        assert_eq!(closed, echo_addr);
+
+        let stats = remote.stats();
+        assert_eq!(stats.connections, 1);
+        assert_eq!(stats.total_streams, 1);
+        assert_eq!(stats.active_streams, 0);
+        assert_eq!(stats.bytes_sent, 4);
+        assert_eq!(stats.bytes_received, 4);
+
+        assert!(local.shutdown().await.is_ok());
+        assert!(remote.shutdown().await.is_ok());
+    }
+
+    // Records the hooks called, in order
+    #[derive(Clone, Default)]
+    struct Recorder {
+        calls: Arc<Mutex<Vec<String>>>,
+        ids: Arc<Mutex<Vec<StreamId>>>,
+    }
+
+    impl Recorder {
+        fn record(&self, call: String) {
+            self.calls.lock().unwrap().push(call);
+        }
+
+        fn calls(&self) -> Vec<String> {
+            self.calls.lock().unwrap().clone()
+        }
+
+        fn ids(&self) -> Vec<StreamId> {
+            self.ids.lock().unwrap().clone()
+        }
+    }
+
+    impl Hooks for Recorder {
+        fn on_connected(&self, _peer: SocketAddr) {
+            self.record("connected".to_string());
+        }
+
+        fn on_disconnected(&self, _peer: SocketAddr) {
+            self.record("disconnected".to_string());
+        }
+
+        fn on_stream_open(&self, stream: &StreamInfo) {
+            // the client as the local that accepted it saw it
+            let metadata = &stream.metadata;
+            self.ids.lock().unwrap().push(metadata.id);
+            self.record(format!(
+                "open {} for {} on {}",
+                stream.tcp_addr,
+                metadata.client.unwrap(),
+                metadata.listener.unwrap()
+            ));
+        }
+
+        fn on_stream_close(&self, stream: &StreamEnd<'_>) {
+            self.record(format!(
+                "close {} {} {} {}",
+                stream.tcp_addr,
+                stream.bytes_sent,
+                stream.bytes_received,
+                stream.error.is_some()
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hooks() {
+        let echo_addr = spawn_echo_server().await;
+        let recorder = Recorder::default();
+        let local_recorder = Recorder::default();
+
+        let remote = TunnelBuilder::forward_remote()
+            .cert(cert())
+            .key(key())
+            .quic_addr("127.0.0.1:0".parse().unwrap())
+            .forward_addr(echo_addr)
+            .accept_proxy("127.0.0.0/8".parse().unwrap())
+            .drain_timeout(Duration::from_secs(1))
+            .hooks(recorder.clone())
+            .start()
+            .await
+            .unwrap();
+        let mut remote_events = remote.events();
+
+        let local = TunnelBuilder::forward_local()
+            .cert(cert())
+            .remote_addr(remote.local_addr())
+            .local_addr("127.0.0.1:0".parse().unwrap())
+            .drain_timeout(Duration::from_secs(1))
+            .hooks(local_recorder.clone())
+            .start()
+            .await
+            .unwrap();
+
+        let mut tcp_stream = TcpStream::connect(local.local_addr()).await.unwrap();
+        let (client, listener) = (tcp_stream.local_addr().unwrap(), local.local_addr());
+        tcp_stream.write_all(b"hello").await.unwrap();
+        tcp_stream.shutdown().await.unwrap();
+        let mut response = Vec::new();
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            tcp_stream.read_to_end(&mut response),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+
+        assert!(local.shutdown().await.is_ok());
+        let disconnected = async {
+            loop {
+                if let Ok(Event::Disconnected(_)) = remote_events.recv().await {
+                    break;
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), disconnected)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            recorder.calls(),
+            vec![
+                "connected".to_string(),
+                format!("open {echo_addr} for {client} on {listener}"),
+                format!("close {echo_addr} 5 5 false"),
+                "disconnected".to_string(),
+            ]
+        );
+        // both ends know the stream by the id the local gave it
+        assert_eq!(local_recorder.ids().len(), 1);
+        assert_eq!(local_recorder.ids(), recorder.ids());
+        assert!(remote.shutdown().await.is_ok());
+    }
+
+    async fn echo_through(addr: SocketAddr, data: &[u8]) -> Vec<u8> {
+        let mut tcp_stream = TcpStream::connect(addr).await.unwrap();
+        let (mut reader, mut writer) = tcp_stream.split();
+        let write = async {
+            writer.write_all(data).await.unwrap();
+            writer.shutdown().await.unwrap();
+        };
+        let mut response = Vec::new();
+        let read = reader.read_to_end(&mut response);
+
+        let (_, res) =
+            tokio::time::timeout(Duration::from_secs(5), async { tokio::join!(write, read) })
+                .await
+                .unwrap();
+        res.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_compression() {
+        let echo_addr = spawn_echo_server().await;
+        let data = b"compressible line of text\n".repeat(8192);
+
+        // the end opening the streams of a forward tunnel compresses them
+        let remote = TunnelBuilder::forward_remote()
+            .cert(cert())
+            .key(key())
+            .quic_addr("127.0.0.1:0".parse().unwrap())
+            .forward_addr(echo_addr)
+            .start()
+            .await
+            .unwrap();
+        let local = TunnelBuilder::forward_local()
+            .cert(cert())
+            .remote_addr(remote.local_addr())
+            .local_addr("127.0.0.1:0".parse().unwrap())
+            .compress(Compression::Zstd)
+            .start()
+            .await
+            .unwrap();
+
+        assert!(echo_through(local.local_addr(), &data).await == data);
+        assert_eq!(remote.stats().bytes_sent, data.len() as u64);
+        assert!(local.shutdown().await.is_ok());
+        assert!(remote.shutdown().await.is_ok());
+
+        // the remote of a reverse tunnel picks it for the whole connection
+        let tcp_addr = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let remote = TunnelBuilder::reverse_remote()
+            .cert(cert())
+            .key(key())
+            .quic_addr("127.0.0.1:0".parse().unwrap())
+            .tcp_addr(tcp_addr)
+            .compress(Compression::Lz4)
+            .start()
+            .await
+            .unwrap();
+        let local = TunnelBuilder::reverse_local()
+            .cert(cert())
+            .remote_addr(remote.local_addr())
+            .local_addr(echo_addr)
+            .start()
+            .await
+            .unwrap();
+        assert_eq!(local.local_addr(), tcp_addr);
+
+        assert!(echo_through(tcp_addr, &data).await == data);
+        assert!(local.shutdown().await.is_ok());
+        assert!(remote.shutdown().await.is_ok());
+
+        let err = TunnelBuilder::reverse_local()
+            .cert(cert())
+            .remote_addr(tcp_addr)
+            .local_addr(echo_addr)
+            .compress(Compression::Zstd)
+            .build()
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("`compress` is not used"), "{err}");
+    }
+
+    // Answers every connection with what it received once the client is done sending
+    async fn spawn_replay_server() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut received = Vec::new();
+                    stream.read_to_end(&mut received).await.unwrap();
+                    stream.write_all(&received).await.unwrap();
+                });
+            }
+        });
+
+        addr
+    }
+
+    // What the target got from a client that sent nothing, along with the client's address
+    async fn read_header(addr: SocketAddr) -> (SocketAddr, Vec<u8>) {
+        let mut tcp_stream = TcpStream::connect(addr).await.unwrap();
+        tcp_stream.shutdown().await.unwrap();
+        let mut header = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), tcp_stream.read_to_end(&mut header))
+            .await
+            .unwrap()
+            .unwrap();
+
+        (tcp_stream.local_addr().unwrap(), header)
+    }
+
+    #[tokio::test]
+    async fn test_proxy_protocol() {
+        // the client's address reaches the target of a forward tunnel with a trusted local
+        let target = spawn_replay_server().await;
+        let remote = TunnelBuilder::forward_remote()
+            .cert(cert())
+            .key(key())
+            .quic_addr("127.0.0.1:0".parse().unwrap())
+            .forward_addr(target)
+            .proxy_protocol(ProxyProtocol::V1)
+            .accept_proxy("127.0.0.0/8".parse().unwrap())
+            .start()
+            .await
+            .unwrap();
+        let local = TunnelBuilder::forward_local()
+            .cert(cert())
+            .remote_addr(remote.local_addr())
+            .local_addr("127.0.0.1:0".parse().unwrap())
+            .start()
+            .await
+            .unwrap();
+
+        let (client, header) = read_header(local.local_addr()).await;
+        assert_eq!(
+            String::from_utf8(header).unwrap(),
+            format!(
+                "PROXY TCP4 127.0.0.1 127.0.0.1 {} {}\r\n",
+                client.port(),
+                target.port()
+            )
+        );
+        assert!(local.shutdown().await.is_ok());
+        assert!(remote.shutdown().await.is_ok());
+
+        // any other local is the client itself, whatever it claims
+        let remote = TunnelBuilder::forward_remote()
+            .cert(cert())
+            .key(key())
+            .quic_addr("127.0.0.1:0".parse().unwrap())
+            .forward_addr(target)
+            .proxy_protocol(ProxyProtocol::V1)
+            .accept_proxy("192.0.2.0/24".parse().unwrap())
+            .start()
+            .await
+            .unwrap();
+        let local = TunnelBuilder::forward_local()
+            .cert(cert())
+            .remote_addr(remote.local_addr())
+            .local_addr("127.0.0.1:0".parse().unwrap())
+            .start()
+            .await
+            .unwrap();
+
+        let (client, header) = read_header(local.local_addr()).await;
+        let header = String::from_utf8(header).unwrap();
+        assert!(
+            header.starts_with("PROXY TCP4 127.0.0.1 127.0.0.1 "),
+            "{header}"
+        );
+        assert!(
+            !header.contains(&format!(" {} ", client.port())),
+            "{header}"
+        );
+        assert!(local.shutdown().await.is_ok());
+        assert!(remote.shutdown().await.is_ok());
+
+        // and that of the remote's client the target of a reverse tunnel
+        let target = spawn_replay_server().await;
+        let tcp_addr = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let remote = TunnelBuilder::reverse_remote()
+            .cert(cert())
+            .key(key())
+            .quic_addr("127.0.0.1:0".parse().unwrap())
+            .tcp_addr(tcp_addr)
+            .start()
+            .await
+            .unwrap();
+        let local = TunnelBuilder::reverse_local()
+            .cert(cert())
+            .remote_addr(remote.local_addr())
+            .local_addr(target)
+            .proxy_protocol(ProxyProtocol::V2)
+            .start()
+            .await
+            .unwrap();
+
+        // v2 also names the stream, by the id the remote gave it
+        let (client, header) = read_header(tcp_addr).await;
+        let expected = ProxyProtocol::V2.header(Some(client), target);
+        assert_eq!(&header[..14], &expected[..14]);
+        assert_eq!(&header[16..28], &expected[16..]);
+        assert_eq!(&header[28..31], [0x05, 0, 16]);
+        assert!(std::str::from_utf8(&header[31..])
+            .unwrap()
+            .parse::<StreamId>()
+            .is_ok());
+        assert!(local.shutdown().await.is_ok());
+        assert!(remote.shutdown().await.is_ok());
+    }
+
+    // What the target got from a client that sent `data` before closing its side
+    async fn send_through(addr: SocketAddr, data: &[u8]) -> Vec<u8> {
+        let mut tcp_stream = TcpStream::connect(addr).await.unwrap();
+        tcp_stream.write_all(data).await.unwrap();
+        tcp_stream.shutdown().await.unwrap();
+        let mut received = Vec::new();
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            tcp_stream.read_to_end(&mut received),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+
+        received
+    }
+
+    #[tokio::test]
+    async fn test_accept_proxy() {
+        // a load balancer in front of the local passes on its client's address to the target
+        let target = spawn_replay_server().await;
+        let remote = TunnelBuilder::forward_remote()
+            .cert(cert())
+            .key(key())
+            .quic_addr("127.0.0.1:0".parse().unwrap())
+            .forward_addr(target)
+            .proxy_protocol(ProxyProtocol::V1)
+            .accept_proxy("127.0.0.0/8".parse().unwrap())
+            .start()
+            .await
+            .unwrap();
+        let local = TunnelBuilder::forward_local()
+            .cert(cert())
+            .remote_addr(remote.local_addr())
+            .local_addr("127.0.0.1:0".parse().unwrap())
+            .accept_proxy("127.0.0.0/8".parse().unwrap())
+            .start()
+            .await
+            .unwrap();
+
+        let client: SocketAddr = "198.51.100.7:40000".parse().unwrap();
+        let mut sent = ProxyProtocol::V2.header(Some(client), local.local_addr());
+        sent.extend_from_slice(b"hello");
+        let received = send_through(local.local_addr(), &sent).await;
+        assert_eq!(
+            String::from_utf8(received).unwrap(),
+            format!(
+                "PROXY TCP4 198.51.100.7 127.0.0.1 40000 {}\r\nhello",
+                target.port()
+            )
+        );
+
+        // a trusted address that doesn't send a header never reaches the target
+        let received = send_through(local.local_addr(), b"hello").await;
+        assert!(received.is_empty());
+
+        assert!(local.shutdown().await.is_ok());
+        assert!(remote.shutdown().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_access_log() {
+        let dir = std::env::temp_dir().join(format!("sirang-access-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (local_log, remote_log) = (dir.join("local.log"), dir.join("remote.log"));
+
+        let echo_addr = spawn_echo_server().await;
+        let remote = TunnelBuilder::forward_remote()
+            .cert(cert())
+            .key(key())
+            .quic_addr("127.0.0.1:0".parse().unwrap())
+            .forward_addr(echo_addr)
+            .accept_proxy("127.0.0.0/8".parse().unwrap())
+            .access_log(&remote_log)
+            .access_log_format(
+                "{id} {client} {target} {bytes_sent} {close}"
+                    .parse()
+                    .unwrap(),
+            )
+            .start()
+            .await
+            .unwrap();
+        let local = TunnelBuilder::forward_local()
+            .cert(cert())
+            .remote_addr(remote.local_addr())
+            .local_addr("127.0.0.1:0".parse().unwrap())
+            .access_log(&local_log)
+            .start()
+            .await
+            .unwrap();
+
+        let mut tcp_stream = TcpStream::connect(local.local_addr()).await.unwrap();
+        let client = tcp_stream.local_addr().unwrap();
+        tcp_stream.write_all(b"hello").await.unwrap();
+        tcp_stream.shutdown().await.unwrap();
+        let mut response = Vec::new();
+        tcp_stream.read_to_end(&mut response).await.unwrap();
+        drop(tcp_stream);
+        assert!(local.shutdown().await.is_ok());
+        assert!(remote.shutdown().await.is_ok());
+
+        // the local knows the client, the remote the target as well, and both the same id
+        let record: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&local_log).unwrap()).unwrap();
+        assert_eq!(record["client"], client.to_string());
+        assert_eq!(record["target"], serde_json::Value::Null);
+        assert_eq!(record["bytes_sent"], 5);
+        assert_eq!(record["bytes_received"], 5);
+        assert_eq!(record["close"], "fin");
+        let id = record["id"].as_str().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&remote_log).unwrap(),
+            format!("{id} {client} {echo_addr} 5 fin\n")
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_access_log_refused() {
+        let dir = std::env::temp_dir().join(format!("sirang-refused-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (local_log, remote_log) = (dir.join("local.log"), dir.join("remote.log"));
+
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed_addr = closed.local_addr().unwrap();
+        drop(closed);
+        let remote = TunnelBuilder::forward_remote()
+            .cert(cert())
+            .key(key())
+            .quic_addr("127.0.0.1:0".parse().unwrap())
+            .forward_addr(closed_addr)
+            .access_log(&remote_log)
+            .start()
+            .await
+            .unwrap();
+        let local = TunnelBuilder::forward_local()
+            .cert(cert())
+            .remote_addr(remote.local_addr())
+            .local_addr("127.0.0.1:0".parse().unwrap())
+            .access_log(&local_log)
+            .start()
+            .await
+            .unwrap();
+
+        let mut tcp_stream = TcpStream::connect(local.local_addr()).await.unwrap();
+        let mut response = Vec::new();
+        let _ = tcp_stream.read_to_end(&mut response).await;
+        drop(tcp_stream);
+        assert!(local.shutdown().await.is_ok());
+        assert!(remote.shutdown().await.is_ok());
+
+        // a stream that never reached its target is still logged on both ends, with the cause
+        for log in [&local_log, &remote_log] {
+            let record: serde_json::Value =
+                serde_json::from_str(&std::fs::read_to_string(log).unwrap()).unwrap();
+            assert_eq!(record["target"], serde_json::Value::Null);
+            assert_eq!(record["bytes_sent"], 0);
+            assert_eq!(record["close"], "error");
+            assert_eq!(record["error_code"], sirang::code::REFUSED);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_invalid_tunnel() {
+        let err = TunnelBuilder::forward_remote()
+            .cert(cert())
+            .forward_addr("127.0.0.1:22".parse().unwrap())
+            .build()
+            .err()
+            .unwrap();
+        assert!(matches!(err, Error::Config(_)), "{err}");
+        assert!(err.to_string().contains("missing `key`"), "{err}");
+
+        // failing to start is reported by start rather than through the handle
+        let remote = TunnelBuilder::forward_remote()
+            .cert(cert())
+            .key(key())
+            .quic_addr("127.0.0.1:0".parse().unwrap())
+            .forward_addr("127.0.0.1:22".parse().unwrap())
+            .start()
+            .await
+            .unwrap();
+        let taken = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let err = TunnelBuilder::forward_local()
+            .cert(cert())
+            .remote_addr(remote.local_addr())
+            .local_addr(taken.local_addr().unwrap())
+            .start()
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, Error::Bind(_)), "{err}");
+    }
+
+    // A forward remote of 0.1.5, which leaves ALPN to s2n-quic and pipes every stream it
+    // accepts to its target as is
+    fn spawn_old_forward_remote(target: SocketAddr) -> SocketAddr {
+        let mut server = s2n_quic::Server::builder()
+            .with_tls((
+                include_str!(".././test_cert.pem"),
+                include_str!(".././test_key.pem"),
+            ))
+            .unwrap()
+            .with_io("127.0.0.1:0")
+            .unwrap()
+            .start()
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            while let Some(mut connection) = server.accept().await {
+                tokio::spawn(async move {
+                    while let Ok(Some(mut quic_stream)) =
+                        connection.accept_bidirectional_stream().await
+                    {
+                        tokio::spawn(async move {
+                            let mut tcp_stream = TcpStream::connect(target).await.unwrap();
+                            let _ =
+                                tokio::io::copy_bidirectional(&mut tcp_stream, &mut quic_stream)
+                                    .await;
+                        });
+                    }
+                });
+            }
+        });
+
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_forward_local_with_old_remote() {
+        let echo_addr = spawn_echo_server().await;
+        let remote_addr = spawn_old_forward_remote(echo_addr);
+
+        let local = TunnelBuilder::forward_local()
+            .cert(cert())
+            .remote_addr(remote_addr)
+            .local_addr("127.0.0.1:0".parse().unwrap())
+            .compress(Compression::Zstd)
+            .start()
+            .await
+            .unwrap();
+
+        // the target gets the client's bytes and nothing else, on every stream
+        for _ in 0..2 {
+            let mut tcp_stream = TcpStream::connect(local.local_addr()).await.unwrap();
+            tcp_stream.write_all(b"hello").await.unwrap();
+            tcp_stream.shutdown().await.unwrap();
+            let mut response = Vec::new();
+            tokio::time::timeout(
+                Duration::from_secs(5),
+                tcp_stream.read_to_end(&mut response),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+            assert_eq!(response, b"hello");
+        }
+
+        assert!(local.shutdown().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_forward_remote_with_old_local() {
+        let echo_addr = spawn_echo_server().await;
+        let remote = TunnelBuilder::forward_remote()
+            .cert(cert())
+            .key(key())
+            .quic_addr("127.0.0.1:0".parse().unwrap())
+            .forward_addr(echo_addr)
+            .start()
+            .await
+            .unwrap();
+
+        // a local of 0.1.5 opens a stream per client, without a command stream or headers
+        let client = s2n_quic::Client::builder()
+            .with_tls(include_str!(".././test_cert.pem"))
+            .unwrap()
+            .with_io("0.0.0.0:0")
+            .unwrap()
+            .start()
+            .unwrap();
+        let connect =
+            s2n_quic::client::Connect::new(remote.local_addr()).with_server_name("127.0.0.1");
+        let mut connection = client.connect(connect).await.unwrap();
+
+        // the first stream is a client's as well
+        for _ in 0..2 {
+            let mut quic_stream = connection.open_bidirectional_stream().await.unwrap();
+            quic_stream.write_all(b"hello").await.unwrap();
+            quic_stream.shutdown().await.unwrap();
+            let mut response = Vec::new();
+            tokio::time::timeout(
+                Duration::from_secs(5),
+                quic_stream.read_to_end(&mut response),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+            assert_eq!(response, b"hello");
+        }
+
+        assert!(remote.shutdown().await.is_ok());
+    }
+
+    async fn echo(stream: &mut TcpStream, message: &[u8]) {
+        stream.write_all(message).await.unwrap();
+        let mut response = vec![0; message.len()];
+        tokio::time::timeout(Duration::from_secs(5), stream.read_exact(&mut response))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(response, message);
+    }
+
+    #[tokio::test]
+    async fn test_goaway_redirect() {
+        let echo_addr = spawn_echo_server().await;
+
+        let second = TunnelBuilder::forward_remote()
+            .cert(cert())
+            .key(key())
+            .quic_addr("127.0.0.1:0".parse().unwrap())
+            .forward_addr(echo_addr)
+            .start()
+            .await
+            .unwrap();
+
+        // on shutdown the first remote sends its locals to the second one right away
+        let first = TunnelBuilder::forward_remote()
+            .cert(cert())
+            .key(key())
+            .quic_addr("127.0.0.1:0".parse().unwrap())
+            .forward_addr(echo_addr)
+            .goaway(Duration::ZERO)
+            .redirect_addr(second.local_addr())
+            .drain_timeout(Duration::from_secs(5))
+            .start()
+            .await
+            .unwrap();
+
+        let local = TunnelBuilder::forward_local()
+            .cert(cert())
+            .remote_addr(first.local_addr())
+            .local_addr("127.0.0.1:0".parse().unwrap())
+            .start()
+            .await
+            .unwrap();
+        let mut local_events = local.events();
+
+        let mut open_stream = TcpStream::connect(local.local_addr()).await.unwrap();
+        echo(&mut open_stream, b"before").await;
+        assert_eq!(first.stats().active_streams, 1);
+
+        // the shutdown only completes once the open stream is done
+        let first_addr = first.local_addr();
+        let first_shutdown = tokio::spawn(first.shutdown());
+
+        let reconnected = async {
+            loop {
+                match local_events.recv().await {
+                    Ok(Event::Connected(peer)) if peer != first_addr => break peer,
+                    _ => {}
+                }
+            }
+        };
+        let reconnected = tokio::time::timeout(Duration::from_secs(5), reconnected)
+            .await
+            .unwrap();
+        assert_eq!(reconnected, second.local_addr());
+        assert_eq!(local.stats().reconnects, 1);
+
+        // the stream opened before the GOAWAY keeps flowing over the first remote
+        echo(&mut open_stream, b"during").await;
+        assert!(!first_shutdown.is_finished());
+
+        // while new ones go to the second remote
+        let mut new_stream = TcpStream::connect(local.local_addr()).await.unwrap();
+        echo(&mut new_stream, b"new").await;
+        assert_eq!(second.stats().total_streams, 1);
+
+        drop(open_stream);
+        let shutdown = tokio::time::timeout(Duration::from_secs(5), first_shutdown)
+            .await
+            .unwrap();
+        assert!(shutdown.unwrap().is_ok());
+
+        drop(new_stream);
+        assert!(local.shutdown().await.is_ok());
+        assert!(second.shutdown().await.is_ok());
+    }
+}